@@ -1,25 +1,49 @@
 use crate::{
-    compact_printer::LettersWithColors,
-    config::ExternalContext,
+    compact_printer::{FormattedHomologies, LettersWithColors},
+    compression::OutputFile,
+    config::{AlignBackend, ExternalContext},
+    error::{ErrorKind, Stage, StageError},
     external,
     matching::solve_matching_problem,
-    score_calc::ScoringCtxt,
+    profile::SearchProfile,
+    score_calc::{load_profile, ScoringCtxt},
     structures::{AdderPayload, CrucibleCtxt},
 };
 use ahash::AHashMap;
-use anyhow::bail;
-use itertools::Itertools;
+use anyhow::anyhow;
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
-use seq_io::fasta::OwnedRecord;
-use std::{cell::RefCell, fs::File, io::BufWriter, path::PathBuf, sync::Arc};
+use seq_io::{fasta::OwnedRecord, BaseRecord};
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use thread_local::ThreadLocal;
 use tracing::info;
 
+/// how many Phred qualities FASTQ files can hold, from '!' to '~'
+const NUM_PHRED: usize = 94;
+
+/// the probability that a base call of each Phred quality `q` is correct, `1 - 10^(-q/10)`
+fn phred_weights() -> [f64; NUM_PHRED] {
+    let mut weights = [0.0; NUM_PHRED];
+    for (q, w) in weights.iter_mut().enumerate() {
+        *w = 1.0 - 10f64.powf(-(q as f64) / 10.0);
+    }
+    weights
+}
+
 pub struct AdderContext {
     base_dir: PathBuf,
     hmm_ctxt: CrucibleCtxt,
     queries: Vec<OwnedRecord>,
+    /// the Phred qualities of the queries, only kept with `quality_weights`
+    qualities: Option<Vec<Vec<u8>>>,
+    /// the probability that a base call is correct, by Phred quality
+    phred_weights: [f64; NUM_PHRED],
     transposed_scores: Vec<Vec<(u32, f64)>>,
+    /// the profiles of the HMMs, if loaded beforehand (see `ScoringCtxt::with_profiles`)
+    profiles: Option<Arc<Vec<SearchProfile>>>,
 }
 
 impl AdderContext {
@@ -29,12 +53,14 @@ impl AdderContext {
         payload: AdderPayload,
     ) -> anyhow::Result<Self> {
         let transposed = payload.transpose(&scorer.hmm_ctxt);
-        let (queries, hmm_ctxt) = (scorer.queries, scorer.hmm_ctxt);
         Ok(Self {
             base_dir: base_dir.to_owned(),
-            hmm_ctxt,
-            queries,
+            hmm_ctxt: scorer.hmm_ctxt,
+            queries: scorer.queries,
+            qualities: scorer.qualities,
+            phred_weights: phred_weights(),
             transposed_scores: transposed,
+            profiles: scorer.profiles,
         })
     }
 }
@@ -52,6 +78,14 @@ impl BatchedWeightMatrix {
         }
     }
 
+    /// add `weight` to the homology between a residue of a query and a backbone column
+    pub fn add_homology(&mut self, seq_id: u32, residue_ix: u32, global_column: u32, weight: f64) {
+        self.weights[seq_id as usize]
+            .entry((residue_ix, global_column))
+            .and_modify(|w| *w += weight)
+            .or_insert(weight);
+    }
+
     pub fn merge_in(&mut self, rhs: BatchedWeightMatrix) {
         for (w, r) in self.weights.iter_mut().zip(rhs.weights) {
             for (k, v) in r {
@@ -68,35 +102,89 @@ impl AdderContext {
             .join(format!("{}.hmm", hmm_id))
     }
 
+    /// the weight of a residue of a query: the probability that its base call is correct if the
+    /// queries have qualities, 1 otherwise
+    fn residue_weight(&self, seq_id: u32, residue_ix: u32) -> f64 {
+        self.qualities.as_ref().map_or(1.0, |qualities| {
+            self.phred_weights[qualities[seq_id as usize][residue_ix as usize] as usize]
+        })
+    }
+
     pub fn hmmalign_for_one_hmm(
         &self,
         hmm_id: u32,
         subweights: &mut BatchedWeightMatrix,
+        config: &ExternalContext,
     ) -> anyhow::Result<()> {
         let metadata = &self.hmm_ctxt.metadata[hmm_id as usize];
         let hits = &self.transposed_scores[hmm_id as usize];
         if hits.is_empty() {
             return Ok(());
         }
-        let queries_for_hmm = hits
-            .iter()
-            .map(|&(seq_id, _)| &self.queries[seq_id as usize]);
+        if config.aligner == AlignBackend::Viterbi {
+            return Ok(config.retry.run(Stage::Align, hmm_id, None, || {
+                self.align_natively(hmm_id, subweights)
+                    .map_err(ErrorKind::Other)
+            })?);
+        }
         let hmm_path = self.hmm_path(hmm_id);
-        let raw_afa = queries_for_hmm
-            .chunks(500)
-            .into_iter()
-            .flat_map(|c| external::hmmalign(&hmm_path, c).expect("hmmalign failed"))
-            .collect_vec();
+        let mut raw_afa = vec![];
+        for (chunk_id, chunk) in hits.chunks(500).enumerate() {
+            let queries_for_hmm = chunk
+                .iter()
+                .map(|&(seq_id, _)| (seq_id, &self.queries[seq_id as usize]));
+            raw_afa.extend(config.retry.run(Stage::Align, hmm_id, Some(chunk_id), || {
+                external::hmmalign(
+                    &hmm_path,
+                    queries_for_hmm.clone(),
+                    self.hmm_ctxt.alphabet,
+                    config,
+                )
+            })?);
+        }
+        // problems of the output are those of hmmalign, not of the queries
+        let invalid = |e: anyhow::Error| StageError {
+            stage: Stage::Align,
+            hmm_id,
+            chunk_id: None,
+            attempts: 1,
+            kind: ErrorKind::InvalidOutput(e),
+        };
+        let num_columns = metadata.column_poitions.len() as u32;
         let raw_afa_view: &[u8] = &raw_afa;
         let mut reader = seq_io::fasta::Reader::new(raw_afa_view);
         let mut record_id = 0usize;
         while let Some(unverified_record) = reader.next() {
-            let record = unverified_record?;
-            let seq_id = hits[record_id].0;
-            let seq_weight = hits[record_id].1;
+            let record = unverified_record.map_err(|e| invalid(e.into()))?;
+            let &(seq_id, seq_weight) = hits.get(record_id).ok_or_else(|| {
+                invalid(anyhow!(
+                    "hmmalign output has more sequences than the {} queries",
+                    hits.len()
+                ))
+            })?;
+            let aligned_id = external::parse_id(record.head()).map_err(invalid)?;
+            if aligned_id != seq_id {
+                return Err(invalid(anyhow!(
+                    "hmmalign output has sequence {} where {} was expected",
+                    aligned_id,
+                    seq_id
+                ))
+                .into());
+            }
+            let query_len = self.queries[seq_id as usize].seq.len() as u32;
             let mut residue_ix = 0u32; // which character of the query are we at?
             let mut column_ix = 0u32; // which column of the consensus are we at?
             for &c in record.seq_lines().flatten() {
+                let is_residue = c.is_ascii_alphabetic();
+                if (c == b'-' || c.is_ascii_uppercase()) && column_ix >= num_columns
+                    || is_residue && residue_ix >= query_len
+                {
+                    return Err(invalid(anyhow!(
+                        "sequence {} is aligned to more columns or residues than it has",
+                        seq_id
+                    ))
+                    .into());
+                }
                 match c {
                     b'.' => {
                         continue;
@@ -105,13 +193,16 @@ impl AdderContext {
                         column_ix += 1;
                     }
                     _ if c.is_ascii_uppercase() => {
-                        let weight_delta =
-                            seq_weight * metadata.chars_cnt[column_ix as usize] as f64;
+                        let weight_delta = seq_weight
+                            * self.residue_weight(seq_id, residue_ix)
+                            * metadata.chars_cnt[column_ix as usize] as f64;
                         let global_column = metadata.column_poitions[column_ix as usize];
-                        subweights.weights[seq_id as usize]
-                            .entry((residue_ix, global_column as u32))
-                            .and_modify(|w| *w += weight_delta)
-                            .or_insert(weight_delta);
+                        subweights.add_homology(
+                            seq_id,
+                            residue_ix,
+                            global_column as u32,
+                            weight_delta,
+                        );
                         residue_ix += 1;
                         column_ix += 1;
                     }
@@ -119,28 +210,69 @@ impl AdderContext {
                         residue_ix += 1;
                     }
                     _ => {
-                        bail!("Unexpected character in alignment: {}", c as char);
+                        return Err(invalid(anyhow!(
+                            "unexpected character in alignment: {}",
+                            c as char
+                        ))
+                        .into());
                     }
                 }
             }
-            assert_eq!(column_ix, metadata.column_poitions.len() as u32);
+            if column_ix != num_columns {
+                return Err(invalid(anyhow!(
+                    "sequence {} is aligned to {} columns instead of {}",
+                    seq_id,
+                    column_ix,
+                    num_columns
+                ))
+                .into());
+            }
             record_id += 1;
         }
         Ok(())
     }
+
+    /// same as `hmmalign_for_one_hmm`, but aligning in-process instead of running hmmalign
+    fn align_natively(
+        &self,
+        hmm_id: u32,
+        subweights: &mut BatchedWeightMatrix,
+    ) -> anyhow::Result<()> {
+        let metadata = &self.hmm_ctxt.metadata[hmm_id as usize];
+        let loaded;
+        let profile = match &self.profiles {
+            Some(profiles) => &profiles[hmm_id as usize],
+            None => {
+                loaded = load_profile(&self.base_dir, &self.hmm_ctxt, hmm_id)?;
+                &loaded
+            }
+        };
+        for &(seq_id, seq_weight) in &self.transposed_scores[hmm_id as usize] {
+            for (residue_ix, column_ix) in profile.align(&self.queries[seq_id as usize].seq) {
+                let weight_delta = seq_weight
+                    * self.residue_weight(seq_id, residue_ix)
+                    * metadata.chars_cnt[column_ix as usize] as f64;
+                let global_column = metadata.column_poitions[column_ix as usize];
+                subweights.add_homology(seq_id, residue_ix, global_column as u32, weight_delta);
+            }
+        }
+        Ok(())
+    }
 }
 
-pub fn compute_top_homologies(ctxt: &AdderContext) -> anyhow::Result<BatchedWeightMatrix> {
+pub fn compute_top_homologies(
+    ctxt: &AdderContext,
+    config: &ExternalContext,
+) -> anyhow::Result<BatchedWeightMatrix> {
     let tls = Arc::new(ThreadLocal::new());
     (0..ctxt.hmm_ctxt.num_hmms())
         .into_par_iter()
-        .for_each(|hmm_id| {
+        .try_for_each(|hmm_id| {
             let local = tls.clone();
             let subweights = local.get_or(|| RefCell::new(BatchedWeightMatrix::from_ctxt(ctxt)));
             let mut borrowed = subweights.borrow_mut();
-            ctxt.hmmalign_for_one_hmm(hmm_id as u32, &mut borrowed)
-                .expect("Failed to run hmmalign.");
-        });
+            ctxt.hmmalign_for_one_hmm(hmm_id as u32, &mut borrowed, config)
+        })?;
     let mut subweights = BatchedWeightMatrix::from_ctxt(ctxt);
     Arc::try_unwrap(tls).unwrap().into_iter().for_each(|s| {
         subweights.merge_in(s.into_inner());
@@ -148,13 +280,13 @@ pub fn compute_top_homologies(ctxt: &AdderContext) -> anyhow::Result<BatchedWeig
     Ok(subweights)
 }
 
-pub fn align_queries_using_scores(
-    ctxt: AdderContext,
-    outfile: &PathBuf,
-    base_alignment_path: &PathBuf,
+/// runs hmmalign and the ensemble aggregation, producing the backbone column (homology class)
+/// of each letter of each query, -1 for the letters homologous to none
+pub fn solve_homologies(
+    ctxt: &AdderContext,
     config: &ExternalContext,
-) -> anyhow::Result<()> {
-    let subweights = compute_top_homologies(&ctxt)?;
+) -> anyhow::Result<Vec<Vec<i32>>> {
+    let subweights = compute_top_homologies(ctxt, config)?;
     let m = ctxt.hmm_ctxt.metadata[0].column_poitions.len();
     let pool = config.create_full_pool();
     info!(
@@ -172,19 +304,56 @@ pub fn align_queries_using_scores(
             })
             .collect()
     });
+    Ok(dp_solutions)
+}
+
+/// runs hmmalign and the ensemble aggregation, producing the layout of the output alignment
+pub fn format_homologies(
+    ctxt: &AdderContext,
+    config: &ExternalContext,
+) -> anyhow::Result<FormattedHomologies> {
+    let dp_solutions = solve_homologies(ctxt, config)?;
     let mut compact_homologies =
         LettersWithColors::new(ctxt.hmm_ctxt.num_consensus_columns(), dp_solutions);
-    compact_homologies.append_backbone_column_colors();
-    let formatted_homologies = compact_homologies.transl();
+    if config.only_queries {
+        compact_homologies.retain_query_colors();
+    } else {
+        compact_homologies.append_backbone_column_colors();
+    }
+    let mut formatted_homologies = compact_homologies.transl();
+    if config.trim {
+        formatted_homologies.mask_singleton_columns();
+    }
     info!(
         "output homologies formatted, output alignment will have {} columns",
         formatted_homologies.num_visual_columns
     );
-    let mut output_writer = BufWriter::new(File::create(outfile)?);
+    Ok(formatted_homologies)
+}
+
+pub fn align_queries_using_scores(
+    ctxt: AdderContext,
+    outfile: &Path,
+    base_alignment_path: &Path,
+    config: &ExternalContext,
+) -> anyhow::Result<()> {
+    let formatted_homologies = format_homologies(&ctxt, config)?;
+    let mut output_writer = OutputFile::create(outfile)?;
     formatted_homologies.write_all_sequences(
         &ctxt.queries,
         base_alignment_path,
+        config.output_format,
         &mut output_writer,
     )?;
-    Ok(())
+    output_writer.finish()
+}
+
+/// same as `align_queries_using_scores`, but returns the aligned rows instead of writing them
+pub fn align_queries_to_records(
+    ctxt: AdderContext,
+    base_alignment_path: &Path,
+    config: &ExternalContext,
+) -> anyhow::Result<Vec<OwnedRecord>> {
+    let formatted_homologies = format_homologies(&ctxt, config)?;
+    formatted_homologies.aligned_records(&ctxt.queries, base_alignment_path)
 }
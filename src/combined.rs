@@ -1,30 +1,81 @@
 use crate::{
-    adder::{align_queries_using_scores, AdderContext},
-    config::ExternalContext,
+    adder::{align_queries_to_records, align_queries_using_scores, AdderContext},
+    compression::{self, OutputFile, Sequences},
+    config::{
+        AlignBackend, BackboneAligner, BackboneSelection, BuildBackend, DecompositionStrategy,
+        ExternalContext, GuideTreeOptions, HmmerTools, OutputFormat, ResourceLimits,
+        ScoringBackend,
+    },
+    denovo::{align_backbone, select_backbone},
+    error::RetryPolicy,
+    formats::AlignmentWriter,
+    hmm::{Alphabet, LetterCounts, ProfileHmm},
     melt::oneshot_melt,
-    score_calc::ScoringCtxt,
-    structures::CrucibleCtxt,
+    score_calc::{load_profiles, ScoringCtxt},
+    streaming::align_in_batches,
+    structures::{AdderPayload, CrucibleCtxt},
+    validate::{fix_query, fix_query_with_qualities, validate, validate_records, Report},
 };
-use anyhow::bail;
+use anyhow::{bail, Context};
+use derive_builder::Builder;
+use fixedbitset::FixedBitSet;
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use seq_io::fasta::{OwnedRecord, Record};
 use std::{
     fs::{self, File},
     io::BufReader,
-    path::PathBuf,
+    path::{Path, PathBuf},
     time::Instant,
 };
-use tracing::info;
+use tracing::{info, warn};
 
-pub fn combined_analysis(
-    input_path: PathBuf,
+/// load an existing eHMM, checking that its HMMs match the decomposition in `melt.json`
+/// and the alphabet, if set in `config`
+fn load_ehmm(ehmm_path: &Path, config: &ExternalContext) -> anyhow::Result<CrucibleCtxt> {
+    let mut ctxt: CrucibleCtxt =
+        serde_json::from_reader(BufReader::new(File::open(ehmm_path.join("melt.json"))?))?;
+    let alphabets = ctxt
+        .metadata
+        .par_iter()
+        .enumerate()
+        .map(|(i, meta)| {
+            let hmm = ProfileHmm::from_path(ehmm_path.join("subsets").join(format!("{}.hmm", i)))?;
+            hmm.check_against(meta)?;
+            Ok(hmm.alphabet)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .with_context(|| format!("eHMM at {:?} is inconsistent with its melt.json", ehmm_path))?;
+    // eHMMs built by older versions do not record the alphabet, but their HMMs do
+    let alphabet = ctxt.alphabet.unwrap_or(alphabets[0]);
+    if let Some(&other) = alphabets.iter().find(|&&a| a != alphabet) {
+        bail!(
+            "eHMM at {:?} mixes HMMs of alphabets {:?} and {:?}",
+            ehmm_path,
+            alphabet,
+            other
+        );
+    }
+    if let Some(requested) = config.alphabet {
+        if requested != alphabet {
+            bail!(
+                "eHMM at {:?} was built for alphabet {:?}, but {:?} was requested",
+                ehmm_path,
+                alphabet,
+                requested
+            );
+        }
+    }
+    ctxt.alphabet = Some(alphabet);
+    Ok(ctxt)
+}
+
+/// decide the eHMM path and also the backbone MSA path, building the eHMM if necessary
+fn prepare_ehmm(
     mut backbone_path: PathBuf,
-    output_path: PathBuf,
     ehmm_path: Option<PathBuf>,
     tree_path: Option<PathBuf>,
     config: &ExternalContext,
-) -> anyhow::Result<()> {
-    if config.trim || config.only_queries {
-        bail!("Trimming and only-queries are not implemented yet");
-    }
+) -> anyhow::Result<(PathBuf, CrucibleCtxt, PathBuf)> {
     if config.db.as_ref().map(|it| it.was_recovered()) == Some(true) && ehmm_path.is_none() {
         info!("recovered from checkpoint file, trying to reuse existing eHMM");
         backbone_path.set_extension("ehmm");
@@ -32,12 +83,10 @@ pub fn combined_analysis(
             bail!("checkpoint file exists but eHMM does not. Please provide the eHMM path or remove the checkpoint");
         }
     }
-    // we first decide the eHMM path and also the backbone MSA path
-    let (actual_backbone_path, ehmm_ctxt, ehmm_path) = if fs::metadata(&backbone_path)?.is_dir() {
-        let crucible_ctxt: CrucibleCtxt =
-            serde_json::from_reader(BufReader::new(File::open(backbone_path.join("melt.json"))?))?;
+    if fs::metadata(&backbone_path)?.is_dir() {
+        let crucible_ctxt = load_ehmm(&backbone_path, config)?;
         let bb_path = backbone_path.join("subsets").join("0.afa");
-        (bb_path, crucible_ctxt, backbone_path)
+        Ok((bb_path, crucible_ctxt, backbone_path))
     } else {
         let actual_ehmm_dir = if let Some(ehmm_path) = ehmm_path {
             ehmm_path
@@ -48,15 +97,18 @@ pub fn combined_analysis(
         };
         let ctxt = oneshot_melt(
             &backbone_path,
-            &tree_path.expect("building eHMM must use a backbone tree"),
+            tree_path.as_deref(),
             &actual_ehmm_dir,
             config,
         )?;
-        (backbone_path, ctxt, actual_ehmm_dir)
-    };
-    // then we start scoring everything
-    let scorer = ScoringCtxt::from_ehmms_ctxt(ehmm_path.clone(), ehmm_ctxt, &input_path)?;
-    // scoring finished
+        Ok((backbone_path, ctxt, actual_ehmm_dir))
+    }
+}
+
+pub(crate) fn score_queries(
+    scorer: &ScoringCtxt,
+    config: &ExternalContext,
+) -> anyhow::Result<AdderPayload> {
     let t = Instant::now();
     let scored = scorer.produce_payload(config)?;
     let elapsed = t.elapsed();
@@ -64,7 +116,457 @@ pub fn combined_analysis(
         "all-against-all hmmsearch (with adjusted bitscore calculation) took {:?}",
         elapsed
     );
+    Ok(scored)
+}
+
+/// fails on the problems of `report` that are not fixed by `fix_queries` (if `fix_inputs`)
+fn check_report(report: &Report, fix_inputs: bool) -> anyhow::Result<()> {
+    report.check(fix_inputs)?;
+    for (&summary, count) in &report.fixable_counts {
+        let examples = report
+            .problems
+            .iter()
+            .filter(|p| p.kind.fixable_summary() == Some(summary))
+            .map(|p| format!("{}:{}", p.path.display(), p.line))
+            .collect::<Vec<_>>()
+            .join(", ");
+        warn!(
+            "fixing the queries that {} ({} of them, e.g., at {})",
+            summary, count, examples
+        );
+    }
+    Ok(())
+}
+
+/// checks the queries and the backbone before any work is done, failing on the problems that are
+/// not fixed by `read_queries`
+fn check_inputs(
+    input_path: &Path,
+    backbone_path: &Path,
+    config: &ExternalContext,
+) -> anyhow::Result<()> {
+    let report = validate(Some(input_path), Some(backbone_path))?;
+    check_report(&report, config.fix_inputs)
+}
+
+/// drops the qualities of the queries unless `config.quality_weights`, and removes gaps from and
+/// upper-cases the queries if `config.fix_inputs`
+pub(crate) fn prepare_queries(queries: &mut Sequences, config: &ExternalContext) {
+    if !config.quality_weights {
+        queries.qualities = None;
+    }
+    if config.fix_inputs {
+        match &mut queries.qualities {
+            Some(qualities) => {
+                for (r, q) in queries.records.iter_mut().zip(qualities) {
+                    fix_query_with_qualities(r, q);
+                }
+            }
+            None => queries.records.iter_mut().for_each(fix_query),
+        }
+    }
+}
+
+/// reads the queries (FASTA or FASTQ), preparing them as `prepare_queries`
+fn read_queries(input_path: &Path, config: &ExternalContext) -> anyhow::Result<Sequences> {
+    let mut queries = compression::read_sequences(input_path)?;
+    if config.quality_weights && queries.qualities.is_none() {
+        warn!("the queries are not FASTQ, so they have no qualities to weigh residues by");
+    }
+    prepare_queries(&mut queries, config);
+    info!("read {} query sequences", queries.records.len());
+    Ok(queries)
+}
+
+/// the scoring context of the queries at `input_path`
+fn scoring_ctxt(
+    input_path: &Path,
+    ehmm_path: &Path,
+    ehmm_ctxt: CrucibleCtxt,
+    config: &ExternalContext,
+) -> anyhow::Result<ScoringCtxt> {
+    let queries = read_queries(input_path, config)?;
+    Ok(
+        ScoringCtxt::from_records(ehmm_path.to_path_buf(), ehmm_ctxt, queries.records)?
+            .with_qualities(queries.qualities),
+    )
+}
+
+pub fn combined_analysis(
+    input_path: PathBuf,
+    backbone_path: PathBuf,
+    output_path: PathBuf,
+    ehmm_path: Option<PathBuf>,
+    tree_path: Option<PathBuf>,
+    config: &ExternalContext,
+) -> anyhow::Result<()> {
+    check_inputs(&input_path, &backbone_path, config)?;
+    let (actual_backbone_path, ehmm_ctxt, ehmm_path) =
+        prepare_ehmm(backbone_path, ehmm_path, tree_path, config)?;
+    if let Some(batch_size) = config.batch_size {
+        return align_in_batches(
+            &input_path,
+            &actual_backbone_path,
+            &output_path,
+            &ehmm_path,
+            ehmm_ctxt,
+            batch_size,
+            config,
+        );
+    }
+    // then we start scoring everything
+    let profiles = load_profiles(&ehmm_path, &ehmm_ctxt, config)?;
+    let scorer = scoring_ctxt(&input_path, &ehmm_path, ehmm_ctxt, config)?.with_profiles(profiles);
+    let scored = score_queries(&scorer, config)?;
+    // scoring finished
     let adder = AdderContext::from_scoring_ctxt(&ehmm_path, scorer, scored)?;
     align_queries_using_scores(adder, &output_path, &actual_backbone_path, config)?;
     Ok(())
 }
+
+/// the first half of `combined_analysis`, saving the top hits of each query to `scores_path`
+pub fn score_analysis(
+    input_path: PathBuf,
+    backbone_path: PathBuf,
+    scores_path: PathBuf,
+    ehmm_path: Option<PathBuf>,
+    tree_path: Option<PathBuf>,
+    config: &ExternalContext,
+) -> anyhow::Result<()> {
+    check_inputs(&input_path, &backbone_path, config)?;
+    let (_, ehmm_ctxt, ehmm_path) = prepare_ehmm(backbone_path, ehmm_path, tree_path, config)?;
+    let scorer = scoring_ctxt(&input_path, &ehmm_path, ehmm_ctxt, config)?;
+    let scored = score_queries(&scorer, config)?;
+    scored.to_path(&scores_path)?;
+    info!(
+        "scores written to {:?}, eHMM at {:?}",
+        scores_path, ehmm_path
+    );
+    Ok(())
+}
+
+/// the second half of `combined_analysis`, using the top hits saved by `score_analysis`
+pub fn align_analysis(
+    input_path: PathBuf,
+    backbone_path: PathBuf,
+    scores_path: PathBuf,
+    output_path: PathBuf,
+    ehmm_path: Option<PathBuf>,
+    config: &ExternalContext,
+) -> anyhow::Result<()> {
+    let (actual_backbone_path, ehmm_path) = if fs::metadata(&backbone_path)?.is_dir() {
+        (backbone_path.join("subsets").join("0.afa"), backbone_path)
+    } else {
+        let ehmm_path = ehmm_path.unwrap_or_else(|| backbone_path.with_extension("ehmm"));
+        if !ehmm_path.exists() {
+            bail!(
+                "eHMM not found at {:?}; align must reuse the eHMM used for scoring",
+                ehmm_path
+            );
+        }
+        (backbone_path, ehmm_path)
+    };
+    check_inputs(&input_path, &actual_backbone_path, config)?;
+    let ehmm_ctxt = load_ehmm(&ehmm_path, config)?;
+    let scorer = scoring_ctxt(&input_path, &ehmm_path, ehmm_ctxt, config)?;
+    let scored = AdderPayload::from_path(&scores_path)?;
+    if scored.sequence_tophits.len() != scorer.queries.len() {
+        bail!(
+            "scores at {:?} are for {} queries, but {} queries were read",
+            scores_path,
+            scored.sequence_tophits.len(),
+            scorer.queries.len()
+        );
+    }
+    let num_hmms = scorer.hmm_ctxt.num_hmms() as u32;
+    if scored
+        .sequence_tophits
+        .iter()
+        .flatten()
+        .any(|&(hmm_id, _)| hmm_id >= num_hmms)
+    {
+        bail!(
+            "scores at {:?} refer to HMMs not in the eHMM at {:?}",
+            scores_path,
+            ehmm_path
+        );
+    }
+    let adder = AdderContext::from_scoring_ctxt(&ehmm_path, scorer, scored)?;
+    align_queries_using_scores(adder, &output_path, &actual_backbone_path, config)?;
+    Ok(())
+}
+
+/// aligns the unaligned sequences at `input_path` from scratch: a backbone is selected among them
+/// and aligned, then the other sequences are added to it as in `combined_analysis`. The backbone,
+/// the queries, the guide tree and the eHMM are kept in `workdir`. Only the backbone is held in
+/// memory, the queries being written to `workdir` as they are read
+pub fn denovo_analysis(
+    input_path: PathBuf,
+    output_path: PathBuf,
+    workdir: PathBuf,
+    selection: &BackboneSelection,
+    aligner: &BackboneAligner,
+    config: &ExternalContext,
+) -> anyhow::Result<()> {
+    // gaps and lower-case letters are expected in unaligned sequences, and fixed in any case
+    validate(Some(&input_path), None)?.check(true)?;
+    // the sequences are read twice so that only the backbone is kept in memory: first for their
+    // lengths and letters, then to split them into the backbone and the queries
+    let mut lengths = vec![];
+    let mut letters = LetterCounts::default();
+    let mut reader = compression::fasta_reader(&input_path)?;
+    for r in reader.records() {
+        let mut r = r?;
+        fix_query(&mut r);
+        letters.add(&r.seq);
+        lengths.push(r.seq.len());
+    }
+    let alphabet = match config.alphabet {
+        Some(alphabet) => alphabet,
+        None => {
+            let alphabet = letters.guess();
+            info!(?alphabet, "detected alphabet of the sequences");
+            alphabet
+        }
+    };
+    let config = ExternalContext {
+        alphabet: Some(alphabet),
+        ..config.clone()
+    };
+    let in_backbone = select_backbone(&lengths, selection)?;
+    fs::create_dir_all(&workdir)?;
+    let queries_path = workdir.join("queries.fa");
+    let mut queries_writer = OutputFile::create(&queries_path)?;
+    let mut backbone = vec![];
+    let mut num_read = 0usize;
+    let mut reader = compression::fasta_reader(&input_path)?;
+    for r in reader.records() {
+        let mut r = r?;
+        fix_query(&mut r);
+        if lengths.get(num_read) != Some(&r.seq.len()) {
+            bail!("{:?} changed while being read", input_path);
+        }
+        if in_backbone[num_read] {
+            backbone.push(r);
+        } else {
+            r.write_wrap(&mut queries_writer, 60)?;
+        }
+        num_read += 1;
+    }
+    queries_writer.finish()?;
+    if num_read != lengths.len() {
+        bail!("{:?} changed while being read", input_path);
+    }
+    let num_queries = num_read - backbone.len();
+    info!(
+        num_backbone = backbone.len(),
+        num_queries, "selected the backbone"
+    );
+    let t = Instant::now();
+    let aligned = align_backbone(&backbone, aligner, alphabet, &config)?;
+    info!(
+        num_columns = aligned[0].seq.len(),
+        "aligning the backbone took {:?}",
+        t.elapsed()
+    );
+    if num_queries == 0 {
+        info!("all sequences are in the backbone, writing its alignment");
+        fs::remove_file(&queries_path)?;
+        return write_backbone(&aligned, &output_path, config.output_format);
+    }
+    let backbone_path = workdir.join("backbone.afa");
+    write_records(&aligned, &backbone_path)?;
+    combined_analysis(
+        queries_path,
+        backbone_path,
+        output_path,
+        Some(workdir.join("backbone.ehmm")),
+        None,
+        &config,
+    )
+}
+
+/// writes a backbone alignment, all of whose columns are backbone columns, in `format`
+fn write_backbone(
+    records: &[OwnedRecord],
+    path: &Path,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let mut file = OutputFile::create(path)?;
+    let mut match_columns = FixedBitSet::with_capacity(records.first().map_or(0, |r| r.seq.len()));
+    match_columns.insert_range(..);
+    let mut writer = AlignmentWriter::new(&mut file, format, match_columns, records.len())?;
+    for r in records {
+        writer.write_row(&r.head, r.seq.clone())?;
+    }
+    writer.finish()?;
+    file.finish()
+}
+
+fn write_records(records: &[OwnedRecord], path: &Path) -> anyhow::Result<()> {
+    let mut writer = OutputFile::create(path)?;
+    for r in records {
+        r.write_wrap(&mut writer, 60)?;
+    }
+    writer.finish()
+}
+
+/// Typed configuration of the whole pipeline (`combined_analysis`), for use as a library
+///
+/// ```ignore
+/// let rows = WitchConfigBuilder::default()
+///     .backbone("backbone.afa")
+///     .tree("backbone.tre")
+///     .trim(true)
+///     .build()?
+///     .align(queries)?;
+/// ```
+#[derive(Debug, Clone, Builder)]
+#[builder(setter(into))]
+pub struct WitchConfig {
+    /// either a full-length MSA or a directory of eHMMs
+    pub backbone: PathBuf,
+    /// backbone tree for the full-length MSA, inferred from the MSA if not given
+    #[builder(setter(strip_option), default)]
+    pub tree: Option<PathBuf>,
+    /// where to build the eHMMs, defaults to the backbone MSA path with "ehmm" extension
+    #[builder(setter(strip_option), default)]
+    pub ehmm_path: Option<PathBuf>,
+    /// how many sequences must each HMM contain
+    #[builder(default = "10")]
+    pub hmm_size_lb: usize,
+    /// how the backbone tree is decomposed
+    #[builder(default = "DecompositionStrategy::Balanced")]
+    pub decomposition: DecompositionStrategy,
+    /// use backbone sequences missing from the tree in the root HMM only, instead of failing
+    #[builder(default)]
+    pub attach_missing_taxa: bool,
+    /// how to infer a tree for a backbone MSA given without `tree`
+    #[builder(default)]
+    pub guide_tree: GuideTreeOptions,
+    /// leave out singleton columns in the output
+    #[builder(default)]
+    pub trim: bool,
+    /// leave out the backbone sequences in the output
+    #[builder(default)]
+    pub only_queries: bool,
+    /// remove gaps from and upper-case the queries
+    #[builder(default)]
+    pub fix_inputs: bool,
+    /// weigh the residues of FASTQ queries by their Phred qualities; only for `align_file`, as the
+    /// queries given to `align` have none
+    #[builder(default)]
+    pub quality_weights: bool,
+    /// format of the output alignment; only for `align_file`, as `align` returns the rows
+    #[builder(default = "OutputFormat::Fasta")]
+    pub output_format: OutputFormat,
+    /// score and align the queries this many at a time; only for `align_file`, which reads them
+    #[builder(setter(strip_option), default)]
+    pub batch_size: Option<usize>,
+    /// level of parallelism
+    #[builder(default = "num_cpus::get()")]
+    pub num_workers: usize,
+    /// how the HMMs are built, if the eHMM is built
+    #[builder(default = "BuildBackend::Hmmbuild")]
+    pub builder: BuildBackend,
+    /// how queries are scored against the HMMs
+    #[builder(default = "ScoringBackend::Hmmsearch")]
+    pub scorer: ScoringBackend,
+    /// how queries are aligned to the HMMs
+    #[builder(default = "AlignBackend::Hmmalign")]
+    pub aligner: AlignBackend,
+    /// locations of and extra arguments to the HMMER programs
+    #[builder(default)]
+    pub hmmer: HmmerTools,
+    /// how failed runs of the HMMER programs are retried
+    #[builder(default)]
+    pub retry: RetryPolicy,
+    /// limits on each run of the HMMER programs
+    #[builder(default)]
+    pub limits: ResourceLimits,
+    /// alphabet of the sequences, detected from the backbone if not set
+    #[builder(default)]
+    pub alphabet: Option<Alphabet>,
+}
+
+impl WitchConfig {
+    pub fn external_context(&self) -> ExternalContext {
+        ExternalContext {
+            hmm_size_lb: self.hmm_size_lb,
+            decomposition: self.decomposition,
+            attach_missing_taxa: self.attach_missing_taxa,
+            guide_tree: self.guide_tree,
+            trim: self.trim,
+            only_queries: self.only_queries,
+            fix_inputs: self.fix_inputs,
+            quality_weights: self.quality_weights,
+            output_format: self.output_format,
+            batch_size: self.batch_size,
+            num_workers: self.num_workers,
+            scorer: self.scorer,
+            aligner: self.aligner,
+            builder: self.builder,
+            hmmer: self.hmmer.clone(),
+            retry: self.retry,
+            limits: self.limits,
+            alphabet: self.alphabet,
+            ..Default::default()
+        }
+    }
+
+    /// add `queries` to the backbone, returning the rows of the extended alignment. The queries and
+    /// the backbone are checked first, as by the command line
+    pub fn align(&self, mut queries: Vec<OwnedRecord>) -> anyhow::Result<Vec<OwnedRecord>> {
+        if self.quality_weights || self.output_format != OutputFormat::Fasta {
+            bail!("quality weights and output formats only apply to align_file");
+        }
+        if self.batch_size.is_some() {
+            bail!("batches only apply to align_file, which reads the queries from a file");
+        }
+        check_report(
+            &validate_records(&queries, Some(&self.backbone))?,
+            self.fix_inputs,
+        )?;
+        if self.fix_inputs {
+            queries.iter_mut().for_each(fix_query);
+        }
+        let config = self.external_context();
+        config.check_hmmer(!self.backbone.is_dir(), true, true)?;
+        config.create_full_pool().install(|| {
+            let (actual_backbone_path, ehmm_ctxt, ehmm_path) = prepare_ehmm(
+                self.backbone.clone(),
+                self.ehmm_path.clone(),
+                self.tree.clone(),
+                &config,
+            )?;
+            let profiles = load_profiles(&ehmm_path, &ehmm_ctxt, &config)?;
+            let scorer = ScoringCtxt::from_records(ehmm_path.clone(), ehmm_ctxt, queries)?
+                .with_profiles(profiles);
+            let scored = score_queries(&scorer, &config)?;
+            let adder = AdderContext::from_scoring_ctxt(&ehmm_path, scorer, scored)?;
+            align_queries_to_records(adder, &actual_backbone_path, &config)
+        })
+    }
+
+    /// add the queries at `input_path` (FASTA or FASTQ) to the backbone, writing the extended
+    /// alignment to `output_path` in `output_format`, as `witch-ng add` does
+    pub fn align_file(
+        &self,
+        input_path: impl Into<PathBuf>,
+        output_path: impl Into<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let (input_path, output_path) = (input_path.into(), output_path.into());
+        let config = self.external_context();
+        config.check_hmmer(!self.backbone.is_dir(), true, true)?;
+        config.create_full_pool().install(|| {
+            combined_analysis(
+                input_path,
+                self.backbone.clone(),
+                output_path,
+                self.ehmm_path.clone(),
+                self.tree.clone(),
+                &config,
+            )
+        })
+    }
+}
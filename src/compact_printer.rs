@@ -1,11 +1,9 @@
-use std::{io::Write, path::PathBuf};
+use std::{io::Write, path::Path};
 
+use crate::{compression, config::OutputFormat, formats::AlignmentWriter};
 use fixedbitset::FixedBitSet;
 use itertools::Itertools;
-use seq_io::{
-    fasta::{OwnedRecord, Reader},
-    BaseRecord,
-};
+use seq_io::{fasta::OwnedRecord, BaseRecord};
 
 /// data structure for keeping track of singleton columns efficiently
 pub struct LettersWithColors {
@@ -13,6 +11,8 @@ pub struct LettersWithColors {
     pub num_colors: usize,
     /// each sequence (by query seq id) and its positional homologies (-1 if not homologous to anything)
     pub letter_colors: Vec<Vec<i32>>,
+    /// if the last entry of `letter_colors` is the backbone
+    pub has_backbone: bool,
 }
 
 /// the target data structure derived from CompactHomologies, see CompactHomologies::transl()
@@ -23,6 +23,12 @@ pub struct FormattedHomologies {
     pub singleton_letters: Vec<FixedBitSet>,
     /// where each letter should go (ix by query seq id)
     pub letter_positions: Vec<Vec<u32>>,
+    /// the printtable column of each backbone homology class, in backbone order
+    pub homology_columns: Vec<u32>,
+    /// if singleton letters (and thus their columns) are left out of the output
+    pub singletons_masked: bool,
+    /// if the last entry of `letter_positions` is the backbone
+    pub has_backbone: bool,
 }
 
 impl LettersWithColors {
@@ -30,32 +36,61 @@ impl LettersWithColors {
         Self {
             num_colors: num_columns,
             letter_colors: homology_hits,
+            has_backbone: false,
         }
     }
 
     pub fn append_backbone_column_colors(&mut self) {
         let new_positions = (0..(self.num_colors as i32)).collect_vec();
         self.letter_colors.push(new_positions);
+        self.has_backbone = true;
     }
 
-    // main logic in the module: convert positional homologies to "global" printtable homologies
-    pub fn transl(self) -> FormattedHomologies {
+    /// the homology classes that some query letter belongs to
+    pub fn used_colors(&self) -> FixedBitSet {
+        assert!(
+            !self.has_backbone,
+            "backbone colors must not be counted as used"
+        );
+        let mut used = FixedBitSet::with_capacity(self.num_colors);
+        for hits in &self.letter_colors {
+            used.extend(hits.iter().filter(|&&h| h >= 0).map(|&h| h as usize));
+        }
+        used
+    }
+
+    /// drop the homology classes not in `used`, keeping the backbone order
+    pub fn retain_colors(&mut self, used: &FixedBitSet) {
+        let mut new_colors = vec![-1i32; self.num_colors];
+        for (num_used, c) in used.ones().enumerate() {
+            new_colors[c] = num_used as i32;
+        }
+        for hits in self.letter_colors.iter_mut() {
+            for h in hits.iter_mut().filter(|h| **h >= 0) {
+                *h = new_colors[*h as usize];
+            }
+        }
+        self.num_colors = used.count_ones(..);
+    }
+
+    /// drop the homology classes that no query letter belongs to, keeping the backbone order
+    pub fn retain_query_colors(&mut self) {
+        let used = self.used_colors();
+        self.retain_colors(&used);
+    }
+
+    /// raise `front_paddings` (one entry per homology class, then one for the end) to the number
+    /// of singleton letters that must fit right before each class for these letters. The first
+    /// entry is for the singletons in front of the first homologous letter of each sequence
+    pub fn update_front_paddings(&self, front_paddings: &mut [u32]) {
         let k = self.num_colors;
-        let mut front_paddings = vec![0u32; k + 1];
-        let seq_lengths = self.letter_colors.iter().map(|v| v.len()).collect_vec();
-        let mut is_singletons = self
-            .letter_colors
-            .iter()
-            .map(|h| FixedBitSet::with_capacity(h.len()))
-            .collect_vec();
-        for (i, hits) in self.letter_colors.iter().enumerate() {
+        assert_eq!(front_paddings.len(), k + 1);
+        for hits in &self.letter_colors {
             let mut num_singletons_in_front = 0u32;
             let mut first_hit = true; // true when we have only seen one non-negative value
-            let is_singleton = is_singletons.get_mut(i).unwrap();
-            for (j, &h) in hits.iter().enumerate() {
+            for &h in hits {
                 if h < 0 {
                     num_singletons_in_front += 1;
-                    is_singleton.set(j, true);
                 } else {
                     if first_hit {
                         front_paddings[0] = num_singletons_in_front.max(front_paddings[0]);
@@ -72,6 +107,31 @@ impl LettersWithColors {
                 front_paddings[k] = num_singletons_in_front.max(front_paddings[k]);
             }
         }
+    }
+
+    // main logic in the module: convert positional homologies to "global" printtable homologies
+    pub fn transl(self) -> FormattedHomologies {
+        let front_paddings = vec![0u32; self.num_colors + 1];
+        self.transl_with(front_paddings)
+    }
+
+    /// same as `transl`, but with columns for at least `front_paddings` singletons before each
+    /// homology class (see `update_front_paddings`). Letters translated in separate batches with
+    /// the paddings of all batches thus land in the same columns as if translated at once
+    pub fn transl_with(self, mut front_paddings: Vec<u32>) -> FormattedHomologies {
+        let k = self.num_colors;
+        let has_backbone = self.has_backbone;
+        self.update_front_paddings(&mut front_paddings);
+        let seq_lengths = self.letter_colors.iter().map(|v| v.len()).collect_vec();
+        let is_singletons = self
+            .letter_colors
+            .iter()
+            .map(|hits| {
+                let mut is_singleton = FixedBitSet::with_capacity(hits.len());
+                is_singleton.extend(hits.iter().positions(|&h| h < 0));
+                is_singleton
+            })
+            .collect_vec();
         let expanded_num_cols = front_paddings.iter().sum::<u32>() as usize + k;
         let mut shifted_columns = vec![0u32; k];
         for c in 0..k {
@@ -135,51 +195,164 @@ impl LettersWithColors {
             num_visual_columns: expanded_num_cols,
             singleton_letters: is_singletons,
             letter_positions: positions,
+            homology_columns: shifted_columns,
+            singletons_masked: false,
+            has_backbone,
         }
     }
 }
 
 impl FormattedHomologies {
-    pub fn write_all_sequences<W>(
-        &self,
-        queries: &[OwnedRecord],
-        base_alignment_path: &PathBuf,
-        w: &mut W,
-    ) -> anyhow::Result<()>
+    /// drop all columns holding only singleton letters, keeping the backbone column order
+    pub fn mask_singleton_columns(&mut self) {
+        let mut new_column = vec![u32::MAX; self.num_visual_columns];
+        for (i, &c) in self.homology_columns.iter().enumerate() {
+            new_column[c as usize] = i as u32;
+        }
+        for (positions, singletons) in self
+            .letter_positions
+            .iter_mut()
+            .zip(self.singleton_letters.iter())
+        {
+            for (j, p) in positions.iter_mut().enumerate() {
+                if !singletons[j] {
+                    *p = new_column[*p as usize];
+                }
+            }
+        }
+        self.homology_columns = (0..self.homology_columns.len() as u32).collect();
+        self.num_visual_columns = self.homology_columns.len();
+        self.singletons_masked = true;
+    }
+
+    /// the aligned row of the i-th query
+    fn format_query(&self, i: usize, seq: &[u8]) -> Vec<u8> {
+        let mut buf: Vec<u8> = vec![b'-'; self.num_visual_columns];
+        for (j, &c) in seq.iter().enumerate() {
+            let is_singleton = self.singleton_letters[i][j];
+            if is_singleton && self.singletons_masked {
+                continue;
+            }
+            let target_pos = self.letter_positions[i][j];
+            buf[target_pos as usize] = if is_singleton {
+                c.to_ascii_lowercase()
+            } else {
+                c.to_ascii_uppercase()
+            };
+        }
+        buf
+    }
+
+    /// reads the backbone alignment, calling `f` on the header and aligned row of each record
+    fn for_each_backbone_row<F>(&self, base_alignment_path: &Path, mut f: F) -> anyhow::Result<()>
     where
-        W: Write,
+        F: FnMut(&[u8], Vec<u8>) -> anyhow::Result<()>,
     {
-        let mut reader = Reader::from_path(base_alignment_path)?;
+        let mut reader = compression::fasta_reader(base_alignment_path)?;
         let base_pos_map = self.letter_positions.last().unwrap();
         while let Some(record_iffy) = reader.next() {
             let record = record_iffy?;
-            w.write_all(b">")?;
-            w.write_all(record.head())?;
-            w.write_all(b"\n")?;
             let mut buf = vec![b'-'; self.num_visual_columns];
             for (i, &c) in record.seq_lines().flatten().enumerate() {
                 let target_pos = base_pos_map[i];
                 buf[target_pos as usize] = c;
             }
-            w.write_all(&buf)?;
-            w.write_all(b"\n")?;
+            f(record.head(), buf)?;
+        }
+        Ok(())
+    }
+
+    /// which output columns are backbone homology columns, as opposed to insertion columns
+    pub fn match_columns(&self) -> FixedBitSet {
+        let mut res = FixedBitSet::with_capacity(self.num_visual_columns);
+        for &c in &self.homology_columns {
+            res.insert(c as usize);
+        }
+        res
+    }
+
+    /// the number of backbone rows written, none unless the backbone is kept
+    pub fn num_backbone_rows(&self, base_alignment_path: &Path) -> anyhow::Result<usize> {
+        let mut num_rows = 0;
+        if self.has_backbone {
+            self.for_each_backbone_row(base_alignment_path, |_, _| {
+                num_rows += 1;
+                Ok(())
+            })?;
+        }
+        Ok(num_rows)
+    }
+
+    /// writes the backbone rows, if the backbone is kept
+    pub fn write_backbone<W: Write>(
+        &self,
+        base_alignment_path: &Path,
+        writer: &mut AlignmentWriter<W>,
+    ) -> anyhow::Result<()> {
+        if self.has_backbone {
+            self.for_each_backbone_row(base_alignment_path, |head, row| {
+                writer.write_row(head, row)
+            })?;
         }
+        Ok(())
+    }
+
+    /// writes the rows of the queries, in the order of their homologies
+    pub fn write_queries<W: Write>(
+        &self,
+        queries: &[OwnedRecord],
+        writer: &mut AlignmentWriter<W>,
+    ) -> anyhow::Result<()> {
         for (i, q) in queries.iter().enumerate() {
-            w.write_all(b">")?;
-            w.write_all(&q.head)?;
-            w.write_all(b"\n")?;
-            let mut buf: Vec<u8> = vec![b'-'; self.num_visual_columns];
-            for (j, &c) in q.seq.iter().enumerate() {
-                let target_pos = self.letter_positions[i][j];
-                buf[target_pos as usize] = if self.singleton_letters[i][j] {
-                    c.to_ascii_lowercase()
-                } else {
-                    c.to_ascii_uppercase()
-                };
-            }
-            w.write_all(&buf)?;
-            w.write_all(b"\n")?;
+            writer.write_row(&q.head, self.format_query(i, &q.seq))?;
         }
         Ok(())
     }
+
+    /// writes the backbone (if kept) and the queries in `format`
+    pub fn write_all_sequences<W>(
+        &self,
+        queries: &[OwnedRecord],
+        base_alignment_path: &Path,
+        format: OutputFormat,
+        w: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        let mut num_rows = queries.len();
+        if format == OutputFormat::Phylip {
+            // the header of PHYLIP counts the rows
+            num_rows += self.num_backbone_rows(base_alignment_path)?;
+        }
+        let mut writer = AlignmentWriter::new(w, format, self.match_columns(), num_rows)?;
+        self.write_backbone(base_alignment_path, &mut writer)?;
+        self.write_queries(queries, &mut writer)?;
+        writer.finish()
+    }
+
+    /// same as `write_all_sequences`, but collects the aligned rows in memory
+    pub fn aligned_records(
+        &self,
+        queries: &[OwnedRecord],
+        base_alignment_path: &Path,
+    ) -> anyhow::Result<Vec<OwnedRecord>> {
+        let mut res = vec![];
+        if self.has_backbone {
+            self.for_each_backbone_row(base_alignment_path, |head, row| {
+                res.push(OwnedRecord {
+                    head: head.to_vec(),
+                    seq: row,
+                });
+                Ok(())
+            })?;
+        }
+        for (i, q) in queries.iter().enumerate() {
+            res.push(OwnedRecord {
+                head: q.head.clone(),
+                seq: self.format_query(i, &q.seq),
+            });
+        }
+        Ok(res)
+    }
 }
@@ -0,0 +1,251 @@
+//! Reading and writing FASTA (and reading FASTQ) files that may be compressed with gzip (or bgzip) or zstd
+use anyhow::{bail, Context};
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use seq_io::{
+    fasta::{self, OwnedRecord, Reader},
+    fastq,
+};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// opens a file for reading, decompressing it if it starts with the magic bytes of gzip or zstd.
+/// bgzip files are read as the concatenated gzip members they are
+pub fn open(path: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+    let file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    let mut reader = BufReader::new(file);
+    let head = reader.fill_buf()?;
+    Ok(if head.starts_with(GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(reader))
+    } else if head.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// a FASTA reader of a possibly compressed file
+pub fn fasta_reader(path: &Path) -> anyhow::Result<Reader<Box<dyn Read + Send>>> {
+    Ok(Reader::new(open(path)?))
+}
+
+/// the offset of the Phred qualities of FASTQ files ('!' for a quality of 0)
+const PHRED_OFFSET: u8 = b'!';
+
+/// the sequences of a FASTA or FASTQ file
+pub struct Sequences {
+    pub records: Vec<OwnedRecord>,
+    /// the Phred quality of each residue, for FASTQ files
+    pub qualities: Option<Vec<Vec<u8>>>,
+}
+
+/// if the (possibly compressed) file is FASTQ rather than FASTA, from its first character
+pub fn is_fastq(path: &Path) -> anyhow::Result<bool> {
+    let mut reader = BufReader::new(open(path)?);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(false);
+        }
+        if let Some(&c) = buf.iter().find(|c| !c.is_ascii_whitespace()) {
+            return Ok(c == b'@');
+        }
+        let n = buf.len();
+        reader.consume(n);
+    }
+}
+
+/// a FASTQ record as a FASTA record and the Phred qualities of its residues
+fn from_fastq(r: fastq::OwnedRecord, path: &Path) -> anyhow::Result<(OwnedRecord, Vec<u8>)> {
+    if r.qual.len() != r.seq.len() {
+        bail!(
+            "{:?} has {} qualities for {} residues in {:?}",
+            String::from_utf8_lossy(&r.head),
+            r.qual.len(),
+            r.seq.len(),
+            path
+        );
+    }
+    let phred = r
+        .qual
+        .iter()
+        .map(|&q| q.checked_sub(PHRED_OFFSET).filter(|_| q.is_ascii_graphic()))
+        .collect::<Option<Vec<_>>>();
+    match phred {
+        Some(phred) => Ok((
+            OwnedRecord {
+                head: r.head,
+                seq: r.seq,
+            },
+            phred,
+        )),
+        None => bail!(
+            "{:?} has qualities outside of Phred+33 in {:?}",
+            String::from_utf8_lossy(&r.head),
+            path
+        ),
+    }
+}
+
+/// reads a (possibly compressed) FASTA or FASTQ file
+pub fn read_sequences(path: &Path) -> anyhow::Result<Sequences> {
+    if !is_fastq(path)? {
+        return Ok(Sequences {
+            records: fasta_reader(path)?
+                .records()
+                .collect::<Result<Vec<_>, _>>()?,
+            qualities: None,
+        });
+    }
+    let mut records = vec![];
+    let mut qualities = vec![];
+    for r in fastq::Reader::new(open(path)?).records() {
+        let (record, phred) = from_fastq(r?, path)?;
+        records.push(record);
+        qualities.push(phred);
+    }
+    Ok(Sequences {
+        records,
+        qualities: Some(qualities),
+    })
+}
+
+enum Records {
+    Fasta(fasta::RecordsIntoIter<Box<dyn Read + Send>>),
+    Fastq(fastq::RecordsIntoIter<Box<dyn Read + Send>>),
+}
+
+/// the sequences of a (possibly compressed) FASTA or FASTQ file, read in batches of at most
+/// `batch_size` sequences so that only one batch is in memory at a time
+pub struct SequenceBatches {
+    path: PathBuf,
+    records: Records,
+    batch_size: usize,
+}
+
+impl SequenceBatches {
+    pub fn new(path: &Path, batch_size: usize) -> anyhow::Result<Self> {
+        let records = if is_fastq(path)? {
+            Records::Fastq(fastq::Reader::new(open(path)?).into_records())
+        } else {
+            Records::Fasta(fasta_reader(path)?.into_records())
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            records,
+            batch_size: batch_size.max(1),
+        })
+    }
+
+    fn next_batch(&mut self) -> anyhow::Result<Sequences> {
+        let mut records = vec![];
+        match &mut self.records {
+            Records::Fasta(it) => {
+                for r in it.take(self.batch_size) {
+                    records.push(r?);
+                }
+                Ok(Sequences {
+                    records,
+                    qualities: None,
+                })
+            }
+            Records::Fastq(it) => {
+                let mut qualities = vec![];
+                for r in it.take(self.batch_size) {
+                    let (record, phred) = from_fastq(r?, &self.path)?;
+                    records.push(record);
+                    qualities.push(phred);
+                }
+                Ok(Sequences {
+                    records,
+                    qualities: Some(qualities),
+                })
+            }
+        }
+    }
+}
+
+impl Iterator for SequenceBatches {
+    type Item = anyhow::Result<Sequences>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_batch() {
+            Ok(batch) if batch.records.is_empty() => None,
+            res => Some(res),
+        }
+    }
+}
+
+/// writes a FASTQ record with the given Phred qualities
+pub fn write_fastq<W: Write>(w: &mut W, record: &OwnedRecord, qualities: &[u8]) -> io::Result<()> {
+    w.write_all(b"@")?;
+    w.write_all(&record.head)?;
+    w.write_all(b"\n")?;
+    w.write_all(&record.seq)?;
+    w.write_all(b"\n+\n")?;
+    let encoded = qualities
+        .iter()
+        .map(|q| q + PHRED_OFFSET)
+        .collect::<Vec<_>>();
+    w.write_all(&encoded)?;
+    w.write_all(b"\n")
+}
+
+/// a file being written, compressed according to its extension (".gz" or ".zst");
+/// `finish` must be called to complete it
+pub enum OutputFile {
+    Plain(BufWriter<File>),
+    Gzip(BufWriter<GzEncoder<File>>),
+    Zstd(BufWriter<zstd::Encoder<'static, File>>),
+}
+
+impl OutputFile {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::create(path).with_context(|| format!("failed to create {:?}", path))?;
+        Ok(match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Self::Gzip(BufWriter::new(GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            ))),
+            Some("zst") => Self::Zstd(BufWriter::new(zstd::Encoder::new(file, 0)?)),
+            _ => Self::Plain(BufWriter::new(file)),
+        })
+    }
+
+    /// flushes the buffered output and writes the end of the compressed stream
+    pub fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Self::Plain(mut w) => w.flush()?,
+            Self::Gzip(w) => {
+                w.into_inner().map_err(|e| e.into_error())?.finish()?;
+            }
+            Self::Zstd(w) => {
+                w.into_inner().map_err(|e| e.into_error())?.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+            Self::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            Self::Zstd(w) => w.flush(),
+        }
+    }
+}
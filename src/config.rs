@@ -1,16 +1,257 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::ValueEnum;
+use ordered_float::NotNan;
 use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::RetryPolicy, external, hmm::Alphabet};
+
+/// how queries are scored against the HMMs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum ScoringBackend {
+    /// run `hmmsearch`
+    Hmmsearch,
+    /// in-process Forward algorithm, close to the scores of `hmmsearch`
+    Forward,
+    /// in-process Viterbi algorithm, faster than Forward but less close to `hmmsearch`
+    Viterbi,
+}
+
+/// how queries are aligned to the HMMs they are assigned to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum AlignBackend {
+    /// run `hmmalign`
+    Hmmalign,
+    /// in-process Viterbi alignment to the whole HMM
+    Viterbi,
+}
+
+/// the format of the output alignment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum OutputFormat {
+    /// aligned FASTA, lower-case letters marking singleton insertions
+    Fasta,
+    /// Stockholm, with a `#=GC RF` line marking the backbone columns ('x') and insertion columns ('.')
+    Stockholm,
+    /// aligned FASTA with '.' as the gaps of insertion columns, as written by HMMER
+    A2m,
+    /// A2M without the gaps of insertion columns
+    A3m,
+    /// relaxed (sequential) PHYLIP, with names up to their first whitespace
+    Phylip,
+    /// Clustal, with names up to their first whitespace
+    Clustal,
+}
+
+/// how the HMMs of the eHMM are built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum BuildBackend {
+    /// run `hmmbuild`
+    Hmmbuild,
+    /// in-process construction, see `construct::build_hmm`
+    Native,
+}
+
+/// how the backbone tree is split into the subsets of the eHMM, recorded in `melt.json`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum DecompositionStrategy {
+    /// split at the most balanced internal node until subsets have at most `hmm_size_lb` taxa
+    Balanced,
+    /// split at the centroid edge as in UPP and the original WITCH, until subsets have at most
+    /// `max_size` taxa, never splitting off fewer than `min_size` taxa
+    CentroidEdge { max_size: usize, min_size: usize },
+    /// split subsets whose diameter (the largest patristic distance between two of their taxa)
+    /// exceeds `max_diameter` at the middle of their longest path, and subsets of more than
+    /// `max_size` taxa at the centroid edge, never splitting off fewer than `min_size` taxa. Trees
+    /// without branch lengths are split as `CentroidEdge` instead
+    Diameter {
+        max_diameter: NotNan<f64>,
+        max_size: usize,
+        min_size: usize,
+    },
+}
+
+/// how the guide tree of a backbone given without a tree is inferred
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum GuideTreeMethod {
+    /// neighbor joining, rooted at the midpoint
+    #[clap(name = "nj")]
+    NeighborJoining,
+    /// UPGMA, assuming a molecular clock
+    Upgma,
+}
+
+/// the distances between backbone sequences the guide tree is inferred from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum DistanceModel {
+    /// proportion of differing residues
+    PDistance,
+    /// LogDet (paralinear) distance, robust to differences in composition
+    LogDet,
+}
+
+/// how to infer the guide tree of a backbone given without a tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GuideTreeOptions {
+    pub method: GuideTreeMethod,
+    pub distance: DistanceModel,
+}
+
+impl Default for GuideTreeOptions {
+    fn default() -> Self {
+        Self {
+            method: GuideTreeMethod::NeighborJoining,
+            distance: DistanceModel::PDistance,
+        }
+    }
+}
+
+/// a HMMER program (or another program WITCH-NG runs) and the extra arguments passed to it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HmmerTool {
+    /// name of the program, for messages
+    pub name: &'static str,
+    /// path to the executable, or its name in `PATH`
+    pub program: PathBuf,
+    /// passed after the arguments set by WITCH-NG, and thus overriding them
+    pub extra_args: Vec<String>,
+}
+
+impl HmmerTool {
+    /// the program of this name in `PATH`, without extra arguments
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            program: PathBuf::from(name),
+            extra_args: vec![],
+        }
+    }
+}
+
+/// the HMMER programs that WITCH-NG runs
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HmmerTools {
+    pub hmmbuild: HmmerTool,
+    pub hmmsearch: HmmerTool,
+    pub hmmalign: HmmerTool,
+}
+
+impl Default for HmmerTools {
+    fn default() -> Self {
+        Self {
+            hmmbuild: HmmerTool::new("hmmbuild"),
+            hmmsearch: HmmerTool::new("hmmsearch"),
+            hmmalign: HmmerTool::new("hmmalign"),
+        }
+    }
+}
+
+/// how the backbone of a de novo alignment is chosen among the input sequences, as in UPP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackboneSelection {
+    /// the most sequences in the backbone
+    pub max_size: usize,
+    /// sequences within this fraction of the median length are full-length, and can be in the backbone
+    pub length_tolerance: f64,
+    /// seed of the random sample of the full-length sequences
+    pub seed: u64,
+}
+
+impl Default for BackboneSelection {
+    fn default() -> Self {
+        Self {
+            max_size: 1000,
+            length_tolerance: 0.25,
+            seed: 0,
+        }
+    }
+}
+
+/// how the backbone of a de novo alignment is aligned
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BackboneAligner {
+    /// the built-in progressive aligner, see `denovo::progressive_align`
+    Builtin,
+    /// a program reading unaligned FASTA on its stdin and writing aligned FASTA to its stdout
+    External(HmmerTool),
+}
+
+/// limits on each run of a HMMER program
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ResourceLimits {
+    /// wall-clock time after which the program is killed
+    pub timeout: Option<Duration>,
+    /// maximum size of the address space of the program, in bytes
+    pub memory: Option<u64>,
+}
 
 #[derive(Debug, Clone)]
 /// For the lack of a better name, a collection of user-specified "hyper-parameters" for the program
 pub struct ExternalContext {
     pub hmm_size_lb: usize,
+    pub decomposition: DecompositionStrategy,
+    /// use backbone sequences missing from the tree in the root HMM only, instead of failing
+    pub attach_missing_taxa: bool,
+    /// how to infer a tree for backbones given without one
+    pub guide_tree: GuideTreeOptions,
     pub show_progress: bool,
     pub io_bound: bool,
     pub trim: bool,
     pub only_queries: bool,
+    /// format of the output alignment
+    pub output_format: OutputFormat,
+    /// scale the homologies of each residue of FASTQ queries by the probability that its base call
+    /// is correct, from its Phred quality
+    pub quality_weights: bool,
+    /// remove gaps from and upper-case the queries instead of failing on them
+    pub fix_inputs: bool,
+    /// keep the raw hits behind the top HMMs of each query, for the scores file
+    pub record_hits: bool,
+    /// score and align the queries this many at a time, keeping only one batch in memory
+    pub batch_size: Option<usize>,
     pub num_workers: usize,
     pub num_threads_per_worker: usize,
     pub db: Option<sled::Db>,
+    pub scorer: ScoringBackend,
+    pub aligner: AlignBackend,
+    pub builder: BuildBackend,
+    pub hmmer: HmmerTools,
+    pub retry: RetryPolicy,
+    pub limits: ResourceLimits,
+    /// alphabet of the sequences, detected from the backbone if not set
+    pub alphabet: Option<Alphabet>,
+}
+
+impl Default for ExternalContext {
+    fn default() -> Self {
+        Self {
+            hmm_size_lb: 10,
+            decomposition: DecompositionStrategy::Balanced,
+            attach_missing_taxa: false,
+            guide_tree: GuideTreeOptions::default(),
+            show_progress: false,
+            io_bound: false,
+            trim: false,
+            only_queries: false,
+            output_format: OutputFormat::Fasta,
+            quality_weights: false,
+            fix_inputs: false,
+            record_hits: false,
+            batch_size: None,
+            num_workers: num_cpus::get(),
+            num_threads_per_worker: 1,
+            db: None,
+            scorer: ScoringBackend::Hmmsearch,
+            aligner: AlignBackend::Hmmalign,
+            builder: BuildBackend::Hmmbuild,
+            hmmer: HmmerTools::default(),
+            retry: RetryPolicy::default(),
+            limits: ResourceLimits::default(),
+            alphabet: None,
+        }
+    }
 }
 
 impl ExternalContext {
@@ -18,10 +259,33 @@ impl ExternalContext {
         self.num_workers
     }
 
+    /// check that the HMMER programs needed for building the eHMM, scoring and aligning (as
+    /// chosen by the backends) exist and are recent enough, before any work is done
+    pub fn check_hmmer(&self, build: bool, search: bool, align: bool) -> anyhow::Result<()> {
+        let needed = [
+            (
+                build && self.builder == BuildBackend::Hmmbuild,
+                &self.hmmer.hmmbuild,
+            ),
+            (
+                search && self.scorer == ScoringBackend::Hmmsearch,
+                &self.hmmer.hmmsearch,
+            ),
+            (
+                align && self.aligner == AlignBackend::Hmmalign,
+                &self.hmmer.hmmalign,
+            ),
+        ];
+        for (_, tool) in needed.iter().filter(|(needed, _)| *needed) {
+            external::check_version(tool)?;
+        }
+        Ok(())
+    }
+
     pub fn create_full_pool(&self) -> ThreadPool {
         ThreadPoolBuilder::new()
             .num_threads(self.total_threads())
             .build()
             .expect("Failed to create thread pool.")
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,504 @@
+//! In-process construction of profile HMMs from the subsets of the backbone, as an alternative to
+//! running `hmmbuild --symfrac 0.0 --ere 0.59`
+use std::f64::consts::LN_2;
+
+use anyhow::bail;
+use itertools::Itertools;
+use ndarray::{Array, Ix2};
+use rand::{distributions::WeightedIndex, prelude::Distribution, rngs::StdRng, SeedableRng};
+use seq_io::fasta::OwnedRecord;
+
+use crate::{
+    hmm::{Alphabet, EvalueParams, ProfileHmm, T_DD, T_DM, T_II, T_IM, T_MD, T_MI, T_MM},
+    prior::Priors,
+    profile::{DpAlgorithm, SearchProfile},
+};
+
+/// target mean relative entropy (bits) of the match emissions, `--ere`
+const TARGET_RELATIVE_ENTROPY: f64 = 0.59;
+/// minimum total relative entropy (bits) kept by short models, `--esigma`
+const ENTROPY_SIGMA: f64 = 45.0;
+/// sequences spanning less than this fraction of the consensus columns are fragments, `--fragthresh`
+const FRAGMENT_THRESHOLD: f64 = 0.5;
+/// residue code of gaps in the digitized alignment
+const GAP: u8 = u8::MAX;
+
+/// weighted observations of an alignment, before applying the priors
+struct Counts {
+    /// (M + 1) x K match emission counts; row 0 is unused
+    match_emissions: Array<f64, Ix2>,
+    /// (M + 1) x 7 transition counts
+    transitions: Array<f64, Ix2>,
+}
+
+/// probabilities (not logarithms) estimated from `Counts`
+struct Estimate {
+    match_emissions: Array<f64, Ix2>,
+    insert_emissions: Array<f64, Ix2>,
+    transitions: Array<f64, Ix2>,
+}
+
+/// Henikoff position-based weights over the consensus columns, summing to the number of sequences
+fn position_based_weights(
+    rows: &[Vec<u8>],
+    match_columns: &[usize],
+    alphabet: Alphabet,
+) -> Vec<f64> {
+    let k = alphabet.size();
+    let mut weights = vec![0f64; rows.len()];
+    let mut lengths = vec![0usize; rows.len()];
+    let mut residue_counts = vec![0usize; k];
+    for &j in match_columns {
+        residue_counts.fill(0);
+        // only canonical residues count, as in HMMER
+        for r in rows.iter().filter(|r| (r[j] as usize) < k) {
+            residue_counts[r[j] as usize] += 1;
+        }
+        let num_distinct = residue_counts.iter().filter(|&&c| c > 0).count();
+        for (i, r) in rows.iter().enumerate() {
+            if (r[j] as usize) < k {
+                weights[i] += 1.0 / (num_distinct * residue_counts[r[j] as usize]) as f64;
+                lengths[i] += 1;
+            }
+        }
+    }
+    for (w, &l) in weights.iter_mut().zip(&lengths) {
+        if l > 0 {
+            *w /= l as f64;
+        }
+    }
+    let total: f64 = weights.iter().sum();
+    if total > 0.0 {
+        let scale = rows.len() as f64 / total;
+        weights.iter_mut().for_each(|w| *w *= scale);
+    } else {
+        weights.fill(1.0);
+    }
+    weights
+}
+
+/// count the emissions and transitions of each sequence along its path through the consensus
+/// columns. Leading and trailing gaps of fragments are missing data instead of deletions.
+fn count_observations(
+    rows: &[Vec<u8>],
+    weights: &[f64],
+    match_columns: &[usize],
+    alphabet: Alphabet,
+) -> Counts {
+    let m = match_columns.len();
+    let code_residues = (0..alphabet.num_codes() as u8)
+        .map(|c| alphabet.code_residues(c))
+        .collect_vec();
+    let mut match_emissions = Array::<f64, _>::zeros((m + 1, alphabet.size()));
+    let mut transitions = Array::<f64, _>::zeros((m + 1, 7));
+    for (row, &w) in rows.iter().zip(weights) {
+        let states = match_columns.iter().map(|&j| row[j]).collect_vec();
+        let (first, last) = match (
+            states.iter().position(|&c| c != GAP),
+            states.iter().rposition(|&c| c != GAP),
+        ) {
+            (Some(first), Some(last)) => (first, last),
+            _ => continue,
+        };
+        let is_fragment = ((last - first + 1) as f64) < FRAGMENT_THRESHOLD * m as f64;
+        let (first, last) = if is_fragment {
+            (first, last)
+        } else {
+            (0, m - 1)
+        };
+        if first == 0 {
+            let t = if states[0] != GAP { T_MM } else { T_MD };
+            transitions[[0, t]] += w;
+        }
+        // consensus column i is node i + 1
+        for i in first..=last {
+            let is_match = states[i] != GAP;
+            if is_match {
+                let residues = &code_residues[states[i] as usize];
+                for &x in residues {
+                    match_emissions[[i + 1, x]] += w / residues.len() as f64;
+                }
+            }
+            if i < last {
+                let t = match (is_match, states[i + 1] != GAP) {
+                    (true, true) => T_MM,
+                    (true, false) => T_MD,
+                    (false, true) => T_DM,
+                    (false, false) => T_DD,
+                };
+                transitions[[i + 1, t]] += w;
+            } else if i == m - 1 {
+                // transitions into the end state are stored as m->m and d->m of the last node
+                transitions[[m, if is_match { T_MM } else { T_DM }]] += w;
+            }
+        }
+    }
+    Counts {
+        match_emissions,
+        transitions,
+    }
+}
+
+/// posterior mean parameters given the counts, scaled by `scale`, under the priors
+fn estimate(counts: &Counts, scale: f64, priors: &Priors) -> Estimate {
+    let (m, k) = (
+        counts.match_emissions.nrows() - 1,
+        counts.match_emissions.ncols(),
+    );
+    let mut match_emissions = Array::<f64, _>::zeros((m + 1, k));
+    let mut insert_emissions = Array::<f64, _>::zeros((m + 1, k));
+    let mut transitions = Array::<f64, _>::zeros((m + 1, 7));
+    // no residue is ever assigned to an insert state when every non-gap column is a consensus column
+    let insert_probs = priors.insert_emissions.posterior_mean(&vec![0.0; k]);
+    for node in 0..=m {
+        if node > 0 {
+            let c = counts
+                .match_emissions
+                .row(node)
+                .iter()
+                .map(|c| c * scale)
+                .collect_vec();
+            for (x, p) in priors
+                .match_emissions
+                .posterior_mean(&c)
+                .into_iter()
+                .enumerate()
+            {
+                match_emissions[[node, x]] = p;
+            }
+        }
+        for (x, &p) in insert_probs.iter().enumerate() {
+            insert_emissions[[node, x]] = p;
+        }
+        let c = |t: usize| counts.transitions[[node, t]] * scale;
+        let tm = priors
+            .match_transitions
+            .posterior_mean(&[c(T_MM), c(T_MI), c(T_MD)]);
+        let ti = priors
+            .insert_transitions
+            .posterior_mean(&[c(T_IM), c(T_II)]);
+        let td = if node == 0 || node == m {
+            vec![1.0, 0.0]
+        } else {
+            priors
+                .delete_transitions
+                .posterior_mean(&[c(T_DM), c(T_DD)])
+        };
+        let (mm, mi, md) = if node == m {
+            // there is no delete state after the last node
+            (tm[0] / (tm[0] + tm[1]), tm[1] / (tm[0] + tm[1]), 0.0)
+        } else {
+            (tm[0], tm[1], tm[2])
+        };
+        for (t, p) in [
+            (T_MM, mm),
+            (T_MI, mi),
+            (T_MD, md),
+            (T_IM, ti[0]),
+            (T_II, ti[1]),
+            (T_DM, td[0]),
+            (T_DD, td[1]),
+        ] {
+            transitions[[node, t]] = p;
+        }
+    }
+    Estimate {
+        match_emissions,
+        insert_emissions,
+        transitions,
+    }
+}
+
+/// mean relative entropy (bits) of the match emissions to the background
+fn mean_relative_entropy(match_emissions: &Array<f64, Ix2>, bg: &[f64]) -> f64 {
+    let m = match_emissions.nrows() - 1;
+    let mut total = 0.0;
+    for node in 1..=m {
+        for (&p, &f) in match_emissions.row(node).iter().zip(bg) {
+            if p > 0.0 {
+                total += p * (p / f).log2();
+            }
+        }
+    }
+    total / m as f64
+}
+
+/// effective number of sequences: the total weight is reduced (by bisection) until the mean
+/// relative entropy drops to the target, as `hmmbuild --eent` does
+fn effective_seq_number(counts: &Counts, nseq: usize, priors: &Priors, bg: &[f64]) -> f64 {
+    let m = (counts.match_emissions.nrows() - 1) as f64;
+    let target =
+        ((ENTROPY_SIGMA - (2.0 / (m * (m + 1.0))).log2()) / m).max(TARGET_RELATIVE_ENTROPY);
+    let nseq = nseq as f64;
+    let relative_entropy = |effn: f64| {
+        mean_relative_entropy(&estimate(counts, effn / nseq, priors).match_emissions, bg)
+    };
+    if relative_entropy(nseq) <= target {
+        return nseq;
+    }
+    let (mut lo, mut hi) = (0.0, nseq);
+    while hi - lo > 1e-3 {
+        let mid = (lo + hi) / 2.0;
+        if relative_entropy(mid) > target {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+/// expected composition of the residues emitted by the model, weighted by state occupancy
+fn composition(estimate: &Estimate) -> Vec<f64> {
+    let t = &estimate.transitions;
+    let m = t.nrows() - 1;
+    let k = estimate.match_emissions.ncols();
+    let mut occupancy = vec![0.0; m + 1];
+    occupancy[1] = t[[0, T_MI]] + t[[0, T_MM]];
+    for node in 2..=m {
+        occupancy[node] = occupancy[node - 1] * (t[[node - 1, T_MM]] + t[[node - 1, T_MI]])
+            + (1.0 - occupancy[node - 1]) * t[[node - 1, T_DM]];
+    }
+    let mut compo = vec![0.0; k];
+    for node in 0..=m {
+        let match_occupancy = if node == 0 { 1.0 } else { occupancy[node] };
+        // expected number of insertions after the node
+        let insert_occupancy = match_occupancy * t[[node, T_MI]] / (1.0 - t[[node, T_II]]);
+        for (x, c) in compo.iter_mut().enumerate() {
+            if node > 0 {
+                *c += occupancy[node] * estimate.match_emissions[[node, x]];
+            }
+            *c += insert_occupancy * estimate.insert_emissions[[node, x]];
+        }
+    }
+    let z: f64 = compo.iter().sum();
+    compo.iter().map(|c| c / z).collect()
+}
+
+/// E-value parameters fitted on scores of random sequences, as `hmmbuild` calibrates with default settings
+fn calibrate(hmm: &ProfileHmm, relative_entropy: f64) -> EvalueParams {
+    const NUM_SAMPLES: usize = 200;
+    const FORWARD_TAIL: f64 = 0.04;
+    let profile = SearchProfile::new(hmm);
+    let lambda = LN_2 + 1.44 / (hmm.length as f64 * relative_entropy);
+    let mut rng = StdRng::seed_from_u64(42);
+    let residues = WeightedIndex::new(hmm.alphabet.background()).unwrap();
+    let symbols = hmm.alphabet.symbols();
+    let mut scores = |algorithm: DpAlgorithm, l: usize| {
+        (0..NUM_SAMPLES)
+            .map(|_| {
+                let seq = (0..l)
+                    .map(|_| symbols[residues.sample(&mut rng)])
+                    .collect_vec();
+                profile.score(&seq, algorithm)
+            })
+            .collect_vec()
+    };
+    // maximum likelihood Gumbel location with the slope fixed to lambda
+    let gumbel_location = |scores: &[f64]| {
+        let mean = scores.iter().map(|s| (-lambda * s).exp()).sum::<f64>() / scores.len() as f64;
+        -mean.ln() / lambda
+    };
+    let msv = gumbel_location(&scores(DpAlgorithm::Msv, 200));
+    let viterbi = gumbel_location(&scores(DpAlgorithm::Viterbi, 200));
+    // exponential tail of the Forward scores, with the slope fixed to lambda
+    let mut forward = scores(DpAlgorithm::Forward, 100);
+    forward.sort_unstable_by(|a, b| b.total_cmp(a));
+    let tail_start = forward[(NUM_SAMPLES as f64 * FORWARD_TAIL) as usize - 1];
+    let tau = tail_start + FORWARD_TAIL.ln() / lambda;
+    EvalueParams {
+        msv: (msv, lambda),
+        viterbi: (viterbi, lambda),
+        forward: (tau, lambda),
+    }
+}
+
+/// builds a profile HMM from aligned sequences, with every column holding a residue as a consensus column
+pub fn build_hmm<'a, R>(seqs: R, name: &str, alphabet: Alphabet) -> anyhow::Result<ProfileHmm>
+where
+    R: Iterator<Item = &'a OwnedRecord>,
+{
+    let table = alphabet.code_table();
+    let rows = seqs
+        .map(|r| {
+            r.seq
+                .iter()
+                .map(|&c| match c {
+                    b'-' | b'.' | b'_' | b'~' => GAP,
+                    _ => table[c.to_ascii_uppercase() as usize],
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+    if rows.is_empty() {
+        bail!("cannot build HMM {} without sequences", name);
+    }
+    let alignment_length = rows[0].len();
+    if rows.iter().any(|r| r.len() != alignment_length) {
+        bail!("sequences of HMM {} are not all of the same length", name);
+    }
+    let match_columns = (0..alignment_length)
+        .filter(|&j| rows.iter().any(|r| r[j] != GAP))
+        .collect_vec();
+    if match_columns.is_empty() {
+        bail!("cannot build HMM {} from sequences without residues", name);
+    }
+    let m = match_columns.len();
+    let weights = position_based_weights(&rows, &match_columns, alphabet);
+    let counts = count_observations(&rows, &weights, &match_columns, alphabet);
+    let priors = Priors::default_for(alphabet);
+    let bg = alphabet.background();
+    let effn = effective_seq_number(&counts, rows.len(), &priors, &bg);
+    let estimate = estimate(&counts, effn / rows.len() as f64, &priors);
+    let relative_entropy = mean_relative_entropy(&estimate.match_emissions, &bg);
+    let consensus_threshold = if alphabet == Alphabet::Amino {
+        0.5
+    } else {
+        0.9
+    };
+    let consensus = (1..=m)
+        .map(|node| {
+            let (x, &p) = estimate
+                .match_emissions
+                .row(node)
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap();
+            let c = alphabet.symbols()[x];
+            if p >= consensus_threshold {
+                c
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect_vec();
+    let mut hmm = ProfileHmm {
+        name: name.to_string(),
+        alphabet,
+        length: m,
+        nseq: Some(rows.len()),
+        effn: Some(effn),
+        stats: None,
+        compo: Some(composition(&estimate).iter().map(|p| p.ln()).collect()),
+        match_emissions: estimate.match_emissions.mapv(f64::ln),
+        insert_emissions: estimate.insert_emissions.mapv(f64::ln),
+        transitions: estimate.transitions.mapv(f64::ln),
+        map: Some(match_columns.iter().map(|&j| j + 1).collect()),
+        consensus: Some(consensus),
+        rf: None,
+        cs: None,
+    };
+    // row 0 holds no match emissions
+    hmm.match_emissions.row_mut(0).fill(f64::NEG_INFINITY);
+    hmm.stats = Some(calibrate(&hmm, relative_entropy));
+    Ok(hmm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ExternalContext, HmmerTool},
+        external,
+    };
+
+    fn records(seqs: &[&str]) -> Vec<OwnedRecord> {
+        seqs.iter()
+            .enumerate()
+            .map(|(i, s)| OwnedRecord {
+                head: format!("s{}", i).into_bytes(),
+                seq: s.as_bytes().to_vec(),
+            })
+            .collect()
+    }
+
+    /// globin fragments, with a deletion and two sequences missing an end
+    const AMINO_ALIGNMENT: [&str; 8] = [
+        "VLSPADKTNVKAAWGKVGAHAGEYGAEALE",
+        "VLSEGEWQLVLHVWAKVEADVAGHGQDILI",
+        "VHLTPEEKSAVTALWGKV--NVDEVGGEAL",
+        "VLSAADKTNVKAAWSKVGGHAGEYGAEALE",
+        "MLSPADKTNVKAAWGKVGAHAGEYGAEALE",
+        "VLSGEDKSNIKAAWGKIGGHGAEYGAEALE",
+        "VLSPADKSNVKAAWGKVGAHAGEY------",
+        "------KTNVKAAWGKVGAHAGEYGAEALE",
+    ];
+
+    #[test]
+    fn nucleotide_emissions_use_laplace_prior() {
+        let hmm = build_hmm(
+            records(&["ACGT", "ACGT", "ACGT"]).iter(),
+            "t",
+            Alphabet::Dna,
+        )
+        .unwrap();
+        // a model this short keeps all of its sequences (the --esigma bound)
+        assert_eq!(hmm.effn, Some(3.0));
+        for node in 1..=4 {
+            for x in 0..4 {
+                let expected = if x == node - 1 { 4.0 / 7.0 } else { 1.0 / 7.0 };
+                assert!((hmm.match_emissions[[node, x]].exp() - expected).abs() < 1e-12);
+            }
+        }
+        for p in hmm.insert_emissions.iter() {
+            assert!((p.exp() - 0.25).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn entropy_weighting_reaches_target() {
+        let seq = "ACGGTCATTGCAGTCCATGAGCTTAGCAAGTCCGATTACG".repeat(3);
+        let hmm = build_hmm(records(&[seq.as_str(); 10]).iter(), "t", Alphabet::Dna).unwrap();
+        let effn = hmm.effn.unwrap();
+        assert!(effn > 0.0 && effn < 10.0, "effective number {}", effn);
+        let relative_entropy = mean_relative_entropy(
+            &hmm.match_emissions.mapv(f64::exp),
+            &Alphabet::Dna.background(),
+        );
+        assert!((relative_entropy - TARGET_RELATIVE_ENTROPY).abs() < 1e-3);
+    }
+
+    /// emissions and effective sequence number against those of `hmmbuild --ere 0.59 --symfrac 0`,
+    /// on amino acids, whose priors are HMMER's. Skipped without HMMER.
+    #[test]
+    fn matches_hmmbuild() {
+        if external::check_version(&HmmerTool::new("hmmbuild")).is_err() {
+            eprintln!("HMMER not found, skipping");
+            return;
+        }
+        let path =
+            std::env::temp_dir().join(format!("witch-ng-construct-{}.hmm", std::process::id()));
+        let alignment = records(&AMINO_ALIGNMENT);
+        let config = ExternalContext::default();
+        external::hmmbuild(alignment.iter(), "t", &path, Alphabet::Amino, &config).unwrap();
+        let expected = ProfileHmm::from_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let hmm = build_hmm(alignment.iter(), "t", Alphabet::Amino).unwrap();
+        assert_eq!(hmm.length, expected.length);
+        assert_eq!(hmm.map, expected.map);
+        let (effn, expected_effn) = (hmm.effn.unwrap(), expected.effn.unwrap());
+        assert!(
+            (effn - expected_effn).abs() < 0.02 * expected_effn,
+            "effective number {} instead of {}",
+            effn,
+            expected_effn
+        );
+        for (emissions, expected_emissions, tolerance) in [
+            (&hmm.match_emissions, &expected.match_emissions, 0.01),
+            (&hmm.insert_emissions, &expected.insert_emissions, 1e-3),
+        ] {
+            for ((node, x), &lp) in emissions.indexed_iter() {
+                let (p, expected_p) = (lp.exp(), expected_emissions[[node, x]].exp());
+                assert!(
+                    (p - expected_p).abs() < tolerance,
+                    "node {}, residue {}: {} instead of {}",
+                    node,
+                    x,
+                    p,
+                    expected_p
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! De novo alignment: choosing a backbone among unaligned sequences and aligning it from scratch
+use crate::{
+    config::{BackboneAligner, BackboneSelection, ExternalContext},
+    external,
+    guide_tree::{upgma, DistanceMatrix},
+    hmm::Alphabet,
+};
+use ahash::AHashMap;
+use anyhow::{anyhow, bail};
+use rand::{rngs::StdRng, seq::index::sample, SeedableRng};
+use seq_io::fasta::{OwnedRecord, Reader};
+use tracing::info;
+
+/// which of the sequences of lengths `lengths` are in the backbone, UPP-style: a random sample of
+/// the full-length sequences (those of positive length within the length tolerance of the median
+/// length). Only the lengths are needed, so that the sequences can be streamed
+pub fn select_backbone(
+    lengths: &[usize],
+    selection: &BackboneSelection,
+) -> anyhow::Result<Vec<bool>> {
+    if lengths.is_empty() {
+        bail!("no sequences to align");
+    }
+    let mut sorted = lengths.to_vec();
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2] as f64;
+    let (lb, ub) = (
+        median * (1.0 - selection.length_tolerance),
+        median * (1.0 + selection.length_tolerance),
+    );
+    let full_length = lengths
+        .iter()
+        .enumerate()
+        .filter(|(_, &l)| l > 0 && (lb..=ub).contains(&(l as f64)))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    info!(
+        median_length = median,
+        num_full_length = full_length.len(),
+        "full-length sequences are of length {:.0} to {:.0}",
+        lb.ceil(),
+        ub.floor()
+    );
+    if full_length.is_empty() {
+        bail!(
+            "no sequence is full-length (non-empty and of length {:.0} to {:.0}) to build the backbone from",
+            lb.ceil(),
+            ub.floor()
+        );
+    }
+    let mut in_backbone = vec![false; lengths.len()];
+    if full_length.len() <= selection.max_size {
+        full_length.iter().for_each(|&i| in_backbone[i] = true);
+    } else {
+        let mut rng = StdRng::seed_from_u64(selection.seed);
+        for k in sample(&mut rng, full_length.len(), selection.max_size) {
+            in_backbone[full_length[k]] = true;
+        }
+    }
+    Ok(in_backbone)
+}
+
+/// aligns the (unaligned) backbone sequences, returning them in the same order, in upper case and
+/// with '-' as the only gap character
+pub fn align_backbone(
+    backbone: &[OwnedRecord],
+    aligner: &BackboneAligner,
+    alphabet: Alphabet,
+    config: &ExternalContext,
+) -> anyhow::Result<Vec<OwnedRecord>> {
+    let aligned = match aligner {
+        BackboneAligner::Builtin => progressive_align(backbone, alphabet),
+        BackboneAligner::External(tool) => {
+            let out = external::msa(tool, backbone.iter(), &config.limits)
+                .map_err(|e| anyhow!("aligning the backbone failed: {}", e))?;
+            let mut reader = Reader::new(&out[..]);
+            let rows = reader
+                .records()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| anyhow!("failed to read the backbone alignment: {}", e))?;
+            reorder_rows(backbone, rows)?
+        }
+    };
+    Ok(aligned
+        .into_iter()
+        .map(|mut r| {
+            for c in r.seq.iter_mut() {
+                *c = if *c == b'.' {
+                    b'-'
+                } else {
+                    c.to_ascii_uppercase()
+                };
+            }
+            r
+        })
+        .collect())
+}
+
+/// puts the rows written by an external aligner in the order of `backbone`, checking that they
+/// are the backbone sequences, all of the same length
+fn reorder_rows(
+    backbone: &[OwnedRecord],
+    rows: Vec<OwnedRecord>,
+) -> anyhow::Result<Vec<OwnedRecord>> {
+    if rows.len() != backbone.len() {
+        bail!(
+            "the backbone aligner returned {} sequences instead of {}",
+            rows.len(),
+            backbone.len()
+        );
+    }
+    let width = rows[0].seq.len();
+    let mut by_name = AHashMap::new();
+    for r in rows {
+        if r.seq.len() != width {
+            bail!(
+                "the backbone aligner returned rows of different lengths ({} and {})",
+                width,
+                r.seq.len()
+            );
+        }
+        by_name.insert(r.head.clone(), r);
+    }
+    backbone
+        .iter()
+        .map(|b| {
+            let row = by_name.remove(&b.head).ok_or_else(|| {
+                anyhow!(
+                    "the backbone aligner did not return sequence {:?}",
+                    String::from_utf8_lossy(&b.head)
+                )
+            })?;
+            let residues = row.seq.iter().filter(|&&c| c != b'-' && c != b'.');
+            if !residues
+                .map(u8::to_ascii_uppercase)
+                .eq(b.seq.iter().map(u8::to_ascii_uppercase))
+            {
+                bail!(
+                    "the backbone aligner changed the residues of sequence {:?}",
+                    String::from_utf8_lossy(&b.head)
+                );
+            }
+            Ok(row)
+        })
+        .collect()
+}
+
+/// scores of the built-in progressive aligner, for residues of the (canonical) alphabet
+const MATCH: f32 = 1.0;
+const MISMATCH: f32 = -1.0;
+const GAP_OPEN: f32 = -4.0;
+const GAP_EXTEND: f32 = -0.5;
+
+/// a marker for gaps in the rows of a profile
+const GAP: u32 = u32::MAX;
+
+/// aligned sequences, as positions in their sequences (or `GAP`)
+struct Profile {
+    members: Vec<usize>,
+    rows: Vec<Vec<u32>>,
+}
+
+/// a column of a profile, as the frequencies of the canonical residues among its rows
+type Column = Vec<f32>;
+
+/// k-mers of a digitized sequence (without the k-mers having non-canonical residues), sorted
+fn kmers(codes: &[u8], k: usize, alphabet_size: usize) -> Vec<u32> {
+    let mut res = codes
+        .windows(k)
+        .filter(|w| w.iter().all(|&c| (c as usize) < alphabet_size))
+        .map(|w| {
+            w.iter()
+                .fold(0u32, |acc, &c| acc * alphabet_size as u32 + c as u32)
+        })
+        .collect::<Vec<_>>();
+    res.sort_unstable();
+    res
+}
+
+/// 1 minus the fraction of shared k-mers (counted with multiplicity) of the sequence with fewer
+/// k-mers, or `None` if either has none
+fn kmer_distance(x: &[u32], y: &[u32]) -> Option<f64> {
+    if x.is_empty() || y.is_empty() {
+        return None;
+    }
+    let (mut i, mut j, mut shared) = (0, 0, 0usize);
+    while i < x.len() && j < y.len() {
+        match x[i].cmp(&y[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    Some(1.0 - shared as f64 / x.len().min(y.len()) as f64)
+}
+
+/// aligns unaligned sequences from scratch: sequences are joined along a UPGMA tree on k-mer
+/// distances, aligning profiles with affine gap penalties (end gaps are free). Meant for small
+/// backbones; external aligners (e.g. MAFFT) are usually more accurate
+pub fn progressive_align(records: &[OwnedRecord], alphabet: Alphabet) -> Vec<OwnedRecord> {
+    let table = alphabet.code_table();
+    let k = alphabet.size();
+    let digitized = records
+        .iter()
+        .map(|r| {
+            r.seq
+                .iter()
+                .map(|&c| table[c.to_ascii_uppercase() as usize])
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let word = if k > 4 { 3 } else { 6 };
+    let words = digitized
+        .iter()
+        .map(|codes| kmers(codes, word, k))
+        .collect::<Vec<_>>();
+    let dist = DistanceMatrix::from_fn(records.len(), |i, j| kmer_distance(&words[i], &words[j]));
+    let singletons = digitized
+        .iter()
+        .enumerate()
+        .map(|(i, codes)| Profile {
+            members: vec![i],
+            rows: vec![(0..codes.len() as u32).collect()],
+        })
+        .collect::<Vec<_>>();
+    let columns = |p: &Profile| -> Vec<Column> {
+        let width = p.rows[0].len();
+        let weight = 1.0 / p.rows.len() as f32;
+        let mut cols = vec![vec![0f32; k]; width];
+        for (&m, row) in p.members.iter().zip(&p.rows) {
+            for (col, &pos) in cols.iter_mut().zip(row) {
+                if pos != GAP {
+                    let code = digitized[m][pos as usize] as usize;
+                    if code < k {
+                        col[code] += weight;
+                    }
+                }
+            }
+        }
+        cols
+    };
+    let profile = upgma(singletons, dist, |a, b, _, _| {
+        let ops = align_profiles(&columns(&a), &columns(&b));
+        merge_profiles(a, b, &ops)
+    });
+    let mut aligned = vec![OwnedRecord::default(); records.len()];
+    for (m, row) in profile.members.into_iter().zip(profile.rows) {
+        aligned[m] = OwnedRecord {
+            head: records[m].head.clone(),
+            seq: row
+                .into_iter()
+                .map(|pos| {
+                    if pos == GAP {
+                        b'-'
+                    } else {
+                        records[m].seq[pos as usize]
+                    }
+                })
+                .collect(),
+        };
+    }
+    aligned
+}
+
+/// a column of the alignment of two profiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    /// columns of both profiles
+    Both,
+    /// a column of the first profile, gaps in the second
+    First,
+    /// a column of the second profile, gaps in the first
+    Second,
+}
+
+/// states of the alignment of two profiles, in the order of `Op`
+const STATES: [Op; 3] = [Op::Both, Op::First, Op::Second];
+
+/// the best global alignment of two profiles (Gotoh), with free end gaps
+fn align_profiles(a: &[Column], b: &[Column]) -> Vec<Op> {
+    let (n, m) = (a.len(), b.len());
+    let score = |x: &Column, y: &Column| {
+        let same = x.iter().zip(y).map(|(p, q)| p * q).sum::<f32>();
+        let (px, py) = (x.iter().sum::<f32>(), y.iter().sum::<f32>());
+        MATCH * same + MISMATCH * (px * py - same)
+    };
+    // gaps at the ends of the other profile are free
+    let gap = |at: usize, len: usize, cost: f32| if at == 0 || at == len { 0.0 } else { cost };
+    let neg = f32::NEG_INFINITY;
+    // for each cell, the best previous state of each state, two bits each
+    let mut back = vec![0u8; (n + 1) * (m + 1)];
+    let mut prev = vec![[neg; 3]; m + 1];
+    let mut cur = vec![[neg; 3]; m + 1];
+    let best = |s: [f32; 3], add: [f32; 3]| {
+        (0..3)
+            .map(|t| (s[t] + add[t], t))
+            .fold((neg, 0), |acc, x| if x.0 > acc.0 { x } else { acc })
+    };
+    for i in 0..=n {
+        for j in 0..=m {
+            if i == 0 && j == 0 {
+                cur[0] = [0.0, neg, neg];
+                continue;
+            }
+            let mut cell = [neg; 3];
+            let mut ptr = 0u8;
+            if i > 0 && j > 0 {
+                let (s, t) = best(prev[j - 1], [0.0; 3]);
+                cell[0] = s + score(&a[i - 1], &b[j - 1]);
+                ptr |= t as u8;
+            }
+            if i > 0 {
+                let (open, extend) = (gap(j, m, GAP_OPEN), gap(j, m, GAP_EXTEND));
+                let (s, t) = best(prev[j], [open, extend, open]);
+                cell[1] = s;
+                ptr |= (t as u8) << 2;
+            }
+            if j > 0 {
+                let (open, extend) = (gap(i, n, GAP_OPEN), gap(i, n, GAP_EXTEND));
+                let (s, t) = best(cur[j - 1], [open, open, extend]);
+                cell[2] = s;
+                ptr |= (t as u8) << 4;
+            }
+            cur[j] = cell;
+            back[i * (m + 1) + j] = ptr;
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    let (_, mut state) = best(prev[m], [0.0; 3]);
+    let (mut i, mut j) = (n, m);
+    let mut ops = vec![];
+    while i > 0 || j > 0 {
+        ops.push(STATES[state]);
+        let ptr = back[i * (m + 1) + j];
+        let next = ((ptr >> (2 * state)) & 3) as usize;
+        match STATES[state] {
+            Op::Both => {
+                i -= 1;
+                j -= 1;
+            }
+            Op::First => i -= 1,
+            Op::Second => j -= 1,
+        }
+        state = next;
+    }
+    ops.reverse();
+    ops
+}
+
+/// the profile of the rows of both profiles, aligned by `ops`
+fn merge_profiles(a: Profile, b: Profile, ops: &[Op]) -> Profile {
+    let expand = |rows: Vec<Vec<u32>>, present: Op| {
+        rows.into_iter()
+            .map(|row| {
+                let mut it = row.into_iter();
+                ops.iter()
+                    .map(|&op| {
+                        if op == Op::Both || op == present {
+                            it.next().expect("alignment longer than the profile")
+                        } else {
+                            GAP
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut rows = expand(a.rows, Op::First);
+    rows.extend(expand(b.rows, Op::Second));
+    let mut members = a.members;
+    members.extend(b.members);
+    Profile { members, rows }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(seqs: &[&str]) -> Vec<OwnedRecord> {
+        seqs.iter()
+            .enumerate()
+            .map(|(i, s)| OwnedRecord {
+                head: format!("s{}", i).into_bytes(),
+                seq: s.as_bytes().to_vec(),
+            })
+            .collect()
+    }
+
+    /// the columns of an unaligned DNA sequence, as a profile of its own
+    fn columns(seq: &str) -> Vec<Column> {
+        let table = Alphabet::Dna.code_table();
+        seq.bytes()
+            .map(|c| {
+                let mut col = vec![0f32; 4];
+                col[table[c as usize] as usize] = 1.0;
+                col
+            })
+            .collect()
+    }
+
+    #[test]
+    fn align_profiles_opens_one_gap() {
+        let ops = align_profiles(&columns("ACGTACGTAC"), &columns("ACGTCGTAC"));
+        let mut expected = vec![Op::Both; 4];
+        expected.push(Op::First);
+        expected.extend([Op::Both; 5]);
+        assert_eq!(ops, expected);
+        let ops = align_profiles(&columns("ACGTCGTAC"), &columns("ACGTACGTAC"));
+        assert_eq!(ops[4], Op::Second);
+    }
+
+    #[test]
+    fn progressive_align_short_sequences() {
+        let input = records(&["ACGTACGTAC", "ACGTACGTAC", "ACGTCGTAC", "ACGTGACGTAC"]);
+        let aligned = progressive_align(&input, Alphabet::Dna);
+        let rows = aligned
+            .iter()
+            .map(|r| std::str::from_utf8(&r.seq).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            ["ACGT-ACGTAC", "ACGT-ACGTAC", "ACGT--CGTAC", "ACGTGACGTAC"]
+        );
+        assert!(aligned.iter().zip(&input).all(|(a, b)| a.head == b.head));
+    }
+
+    #[test]
+    fn select_backbone_needs_positive_lengths() {
+        let selection = BackboneSelection {
+            max_size: 10,
+            length_tolerance: 0.25,
+            seed: 0,
+        };
+        assert!(select_backbone(&[0, 0, 4], &selection).is_err());
+        assert_eq!(
+            select_backbone(&[4, 3, 8, 4], &selection).unwrap(),
+            [true, true, false, true]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Errors of the units of work run in parallel (building, scoring and aligning against one HMM),
+//! and the policy for retrying them
+use std::{fmt, io, path::PathBuf, process::ExitStatus, time::Duration};
+
+use tracing::warn;
+
+/// the stage of the pipeline a unit of work belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// building the HMMs of the eHMM
+    Build,
+    /// scoring queries against the HMMs
+    Search,
+    /// aligning queries to the HMMs
+    Align,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Build => "building",
+            Stage::Search => "scoring",
+            Stage::Align => "aligning",
+        })
+    }
+}
+
+/// what went wrong in a unit of work
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// the program could not be started
+    Spawn { program: PathBuf, source: io::Error },
+    /// the program exited unsuccessfully
+    Exit {
+        program: PathBuf,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// the program ran longer than allowed and was killed
+    Timeout { program: PathBuf, timeout: Duration },
+    /// the output of the program could not be read or understood
+    InvalidOutput(anyhow::Error),
+    /// failures not involving a subprocess, e.g. of in-process backends
+    Other(anyhow::Error),
+}
+
+impl ErrorKind {
+    /// if running the same work again might succeed; a program that is missing or not executable
+    /// will stay so, and a run that timed out would likely take as long again
+    pub fn is_transient(&self) -> bool {
+        if let ErrorKind::Spawn { source, .. } = self {
+            return !matches!(
+                source.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+            );
+        }
+        matches!(self, ErrorKind::Exit { .. } | ErrorKind::InvalidOutput(_))
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Spawn { program, source } => {
+                write!(f, "failed to run {:?}: {}", program, source)
+            }
+            ErrorKind::Exit {
+                program,
+                status,
+                stderr,
+            } => {
+                write!(f, "{:?} failed ({})", program, status)?;
+                if !stderr.trim().is_empty() {
+                    write!(f, ", stderr:\n{}", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+            ErrorKind::Timeout { program, timeout } => {
+                write!(f, "{:?} killed after running for {:?}", program, timeout)
+            }
+            ErrorKind::InvalidOutput(e) => write!(f, "unexpected output: {:#}", e),
+            ErrorKind::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+/// a failed unit of work, located in the pipeline
+#[derive(Debug)]
+pub struct StageError {
+    pub stage: Stage,
+    pub hmm_id: u32,
+    /// which chunk of the queries, if the work was on a chunk
+    pub chunk_id: Option<usize>,
+    /// how many times the work was tried
+    pub attempts: usize,
+    pub kind: ErrorKind,
+}
+
+/// where a unit of work is in the pipeline, e.g. "scoring failed for HMM 3 (query chunk 2)"
+fn failure_location(stage: Stage, hmm_id: u32, chunk_id: Option<usize>) -> String {
+    match chunk_id {
+        Some(chunk_id) => format!(
+            "{} failed for HMM {} (query chunk {})",
+            stage, hmm_id, chunk_id
+        ),
+        None => format!("{} failed for HMM {}", stage, hmm_id),
+    }
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} after {} attempt(s): {}",
+            failure_location(self.stage, self.hmm_id, self.chunk_id),
+            self.attempts,
+            self.kind
+        )
+    }
+}
+
+impl std::error::Error for StageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Spawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// how often failing units of work are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// retries after the first attempt; only transient failures are retried
+    pub max_retries: usize,
+    /// wait before the first retry, doubled for each further retry
+    pub delay: Duration,
+    /// also retry the runs killed for exceeding their timeout
+    pub retry_timeouts: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            delay: Duration::from_secs(1),
+            retry_timeouts: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// if a failure is worth another attempt under this policy
+    fn should_retry(&self, kind: &ErrorKind) -> bool {
+        kind.is_transient() || self.retry_timeouts && matches!(kind, ErrorKind::Timeout { .. })
+    }
+
+    /// runs `work` until it succeeds, fails permanently or runs out of retries
+    pub fn run<T, F>(
+        &self,
+        stage: Stage,
+        hmm_id: u32,
+        chunk_id: Option<usize>,
+        mut work: F,
+    ) -> Result<T, StageError>
+    where
+        F: FnMut() -> Result<T, ErrorKind>,
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match work() {
+                Ok(res) => return Ok(res),
+                Err(kind) if self.should_retry(&kind) && attempts <= self.max_retries => {
+                    let delay = self.delay * 2u32.saturating_pow(attempts as u32 - 1);
+                    warn!(
+                        attempts,
+                        "{}, retrying in {:?}: {}",
+                        failure_location(stage, hmm_id, chunk_id),
+                        delay,
+                        kind
+                    );
+                    std::thread::sleep(delay);
+                }
+                Err(kind) => {
+                    return Err(StageError {
+                        stage,
+                        hmm_id,
+                        chunk_id,
+                        attempts,
+                        kind,
+                    })
+                }
+            }
+        }
+    }
+}
@@ -1,136 +1,530 @@
 use ahash::AHashMap;
-use anyhow::bail;
-use lazy_static::lazy_static;
-use regex::Regex;
+use anyhow::{anyhow, bail, Context};
 use seq_io::fasta::OwnedRecord;
 use seq_io::BaseRecord;
-use std::process::Stdio;
+use std::io::{self, Read, Write};
+use std::process::{Child, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::{path::PathBuf, process::Command};
 use tracing::debug;
 
-use crate::config::ExternalContext;
+use crate::{
+    config::{ExternalContext, HmmerTool, ResourceLimits},
+    error::ErrorKind,
+    hmm::Alphabet,
+    structures::{DomainHit, SequenceHit},
+};
 
-pub fn hmmalign<'a, R>(hmm_path: &PathBuf, seqs: R) -> anyhow::Result<Vec<u8>>
+/// serializes records as FASTA, to be fed to a program
+fn to_fasta<'a, R>(seqs: R) -> Vec<u8>
 where
     R: Iterator<Item = &'a OwnedRecord>,
 {
-    let mut child = Command::new("hmmalign")
-        .arg("--informat")
-        .arg("fasta")
-        .arg("--outformat")
-        .arg("afa")
-        .arg(hmm_path)
-        .arg("-")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-    if let Some(mut stdin) = child.stdin.take() {
-        for s in seqs {
-            s.write(&mut stdin)?;
-        }
-    } else {
-        bail!("Failed to get stdin handle");
-    }
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        bail!("hmmalign failed: {:?}", output);
+    let mut buf = vec![];
+    for s in seqs {
+        s.write(&mut buf).expect("writing to a Vec cannot fail");
     }
-    Ok(output.stdout)
+    buf
 }
 
-pub fn hmmbuild<'a, R>(seqs: R, name: &str, outpath: &PathBuf) -> anyhow::Result<()>
+/// serializes queries as FASTA named by their internal ids, so that programs never see (and
+/// cannot truncate or split) their headers
+fn to_fasta_by_id<'a, R>(seqs: R) -> Vec<u8>
 where
-    R: Iterator<Item = &'a OwnedRecord>,
+    R: Iterator<Item = (u32, &'a OwnedRecord)>,
 {
-    let mut child = Command::new("hmmbuild")
-        .arg("--cpu")
-        .arg("0")
-        .arg("--informat")
-        .arg("afa")
-        .arg("--ere")
-        .arg("0.59")
-        .arg("--symfrac")
-        .arg("0.0")
-        .arg("-n")
-        .arg(name)
-        .arg(outpath)
-        .arg("-")
+    let mut buf = vec![];
+    for (id, s) in seqs {
+        writeln!(buf, ">{}", id).expect("writing to a Vec cannot fail");
+        buf.extend_from_slice(&s.seq);
+        buf.push(b'\n');
+    }
+    buf
+}
+
+/// the internal id of a query, as named by `to_fasta_by_id`
+pub fn parse_id(name: &[u8]) -> anyhow::Result<u32> {
+    std::str::from_utf8(name)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .ok_or_else(|| anyhow!("unknown sequence {:?}", String::from_utf8_lossy(name)))
+}
+
+/// how often a running program is checked for having exceeded its timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// limits the address space of the program run by `command` to `bytes`
+#[cfg(unix)]
+fn limit_memory(command: &mut Command, bytes: u64) -> Result<(), ErrorKind> {
+    use std::os::unix::process::CommandExt;
+    // SAFETY: setrlimit is async-signal-safe, and nothing else is done between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            let limit = libc::rlimit {
+                rlim_cur: bytes as libc::rlim_t,
+                rlim_max: bytes as libc::rlim_t,
+            };
+            if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn limit_memory(_command: &mut Command, _bytes: u64) -> Result<(), ErrorKind> {
+    Err(ErrorKind::Other(anyhow!(
+        "memory limits are only supported on unix"
+    )))
+}
+
+/// starts the program run by `command` in a process group of its own, so that `kill_all` also
+/// kills the processes it starts (e.g., those of a backbone aligner that is a script)
+#[cfg(unix)]
+fn own_process_group(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_command: &mut Command) {}
+
+/// kills the program of `child`, along with its process group if it has its own
+fn kill_all(child: &mut Child) {
+    #[cfg(unix)]
+    // SAFETY: kill only sends a signal; the group is the one created by `own_process_group`
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+}
+
+/// runs `command` of `tool` to completion with `input` as its stdin, returning its stdout.
+/// The input is written and the outputs are read from other threads, so that a program that hangs
+/// can still be killed once it exceeds the timeout of `limits`. With a timeout, the program runs
+/// in a process group of its own, all of which is killed, so that no process keeps the pipes open
+fn run(
+    tool: &HmmerTool,
+    command: &mut Command,
+    input: Vec<u8>,
+    limits: &ResourceLimits,
+) -> Result<Vec<u8>, ErrorKind> {
+    let spawn_error = |source| ErrorKind::Spawn {
+        program: tool.program.clone(),
+        source,
+    };
+    if let Some(bytes) = limits.memory {
+        limit_memory(command, bytes)?;
+    }
+    if limits.timeout.is_some() {
+        own_process_group(command);
+    }
+    let mut child = command
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn()?;
-    if let Some(mut stdin) = child.stdin.take() {
-        for s in seqs {
-            s.write(&mut stdin)?;
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error)?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let drain = |mut r: Box<dyn Read + Send>| {
+        std::thread::spawn(move || {
+            let mut buf = vec![];
+            r.read_to_end(&mut buf).map(|_| buf)
+        })
+    };
+    let stdout = drain(Box::new(child.stdout.take().expect("stdout is piped")));
+    let stderr = drain(Box::new(child.stderr.take().expect("stderr is piped")));
+    let deadline = limits.timeout.map(|t| Instant::now() + t);
+    let status = loop {
+        match deadline {
+            None => break child.wait().map_err(spawn_error)?,
+            Some(deadline) => {
+                if let Some(status) = child.try_wait().map_err(spawn_error)? {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    kill_all(&mut child);
+                    let _ = child.wait();
+                    // with every process holding the pipes killed, the writer fails on the broken
+                    // pipe (dropping stdin) and the readers reach the end of the outputs
+                    let _ = writer.join();
+                    let _ = stdout.join();
+                    let _ = stderr.join();
+                    return Err(ErrorKind::Timeout {
+                        program: tool.program.clone(),
+                        timeout: limits.timeout.unwrap(),
+                    });
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
         }
-    } else {
-        bail!("Failed to get stdin handle");
+    };
+    let written = writer.join().expect("stdin writer panicked");
+    let stdout = stdout.join().expect("stdout reader panicked");
+    let stderr = stderr.join().expect("stderr reader panicked");
+    if !status.success() {
+        return Err(ErrorKind::Exit {
+            program: tool.program.clone(),
+            status,
+            stderr: String::from_utf8_lossy(&stderr.unwrap_or_default()).into_owned(),
+        });
     }
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        bail!("hmmbuild failed: {:?}", output);
-    }
-    Ok(())
+    // a program exiting successfully without reading all of its input is still suspicious
+    written
+        .with_context(|| format!("failed to write the input of {}", tool.name))
+        .and_then(|_| stdout.with_context(|| format!("failed to read the output of {}", tool.name)))
+        .map_err(ErrorKind::InvalidOutput)
 }
 
-pub fn hmmsearch<'a, R>(
+/// aligns the queries (with their internal ids) to the HMM, returning the rows named by their ids
+pub fn hmmalign<'a, R>(
     hmm_path: &PathBuf,
     seqs: R,
-    seq_id: &AHashMap<String, u32>,
+    alphabet: Option<Alphabet>,
     config: &ExternalContext,
-) -> anyhow::Result<Vec<(u32, f64)>>
+) -> Result<Vec<u8>, ErrorKind>
+where
+    R: Iterator<Item = (u32, &'a OwnedRecord)>,
+{
+    let tool = &config.hmmer.hmmalign;
+    run(
+        tool,
+        Command::new(&tool.program)
+            .arg("--informat")
+            .arg("fasta")
+            .arg("--outformat")
+            .arg("afa")
+            .args(alphabet.map(|a| a.hmmer_flag()))
+            .args(&tool.extra_args)
+            .arg(hmm_path)
+            .arg("-"),
+        to_fasta_by_id(seqs),
+        &config.limits,
+    )
+}
+
+/// aligns unaligned `seqs` from scratch with the backbone aligner `tool`, returning its output
+pub fn msa<'a, R>(tool: &HmmerTool, seqs: R, limits: &ResourceLimits) -> Result<Vec<u8>, ErrorKind>
 where
     R: Iterator<Item = &'a OwnedRecord>,
 {
-    let mut child = Command::new("hmmsearch")
-        .arg("--cpu")
-        .arg(if config.io_bound { "1" } else { "0" })
-        .arg("--noali")
-        .arg("--max")
-        .arg("-E")
-        .arg("999999999")
-        .arg(hmm_path)
-        .arg("-")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-    let mut cnt = 0;
-    if let Some(mut stdin) = child.stdin.take() {
-        for s in seqs {
-            s.write(&mut stdin)?;
-            cnt += 1;
-        }
+    run(
+        tool,
+        Command::new(&tool.program).args(&tool.extra_args),
+        to_fasta(seqs),
+        limits,
+    )
+}
+
+pub fn hmmbuild<'a, R>(
+    seqs: R,
+    name: &str,
+    outpath: &PathBuf,
+    alphabet: Alphabet,
+    config: &ExternalContext,
+) -> Result<(), ErrorKind>
+where
+    R: Iterator<Item = &'a OwnedRecord>,
+{
+    let tool = &config.hmmer.hmmbuild;
+    run(
+        tool,
+        Command::new(&tool.program)
+            .arg("--cpu")
+            .arg("0")
+            .arg("--informat")
+            .arg("afa")
+            .arg("--ere")
+            .arg("0.59")
+            .arg("--symfrac")
+            .arg("0.0")
+            .arg("-n")
+            .arg(name)
+            .arg(alphabet.hmmer_flag())
+            .args(&tool.extra_args)
+            .arg(outpath)
+            .arg("-"),
+        to_fasta(seqs),
+        &config.limits,
+    )?;
+    Ok(())
+}
+
+/// the oldest HMMER release that WITCH-NG supports
+pub const MIN_HMMER_VERSION: &str = "3.1b2";
+
+/// orders HMMER versions such as "3.1b2", "3.2" and "3.3.2", with betas before releases
+fn version_key(version: &str) -> Option<(u32, u32, u32, u32)> {
+    let (major, rest) = version.split_once('.')?;
+    let minor_len = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (minor, suffix) = rest.split_at(minor_len);
+    let (is_release, patch) = if let Some(beta) = suffix.strip_prefix('b') {
+        (0, beta.parse().ok()?)
+    } else if let Some(patch) = suffix.strip_prefix('.') {
+        (1, patch.parse().ok()?)
+    } else if suffix.is_empty() {
+        (1, 0)
     } else {
-        bail!("Failed to get stdin handle");
+        return None;
+    };
+    Some((major.parse().ok()?, minor.parse().ok()?, is_release, patch))
+}
+
+/// runs `<tool> -h` to check that the tool exists and is at least `MIN_HMMER_VERSION`, returning its version
+pub fn check_version(tool: &HmmerTool) -> anyhow::Result<String> {
+    let output = Command::new(&tool.program)
+        .arg("-h")
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| {
+            format!(
+                "failed to run {} at {:?}; is HMMER installed? (the path can be set with --{})",
+                tool.name, tool.program, tool.name
+            )
+        })?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // the banner reads "# HMMER 3.3.2 (Nov 2020); http://hmmer.org/"
+    let version = stdout
+        .lines()
+        .find_map(|l| l.split_once("HMMER ").map(|(_, v)| v))
+        .and_then(|v| v.split_whitespace().next())
+        .ok_or_else(|| {
+            anyhow!(
+                "could not find the HMMER version in the output of {:?} -h",
+                tool.program
+            )
+        })?;
+    let key = version_key(version)
+        .ok_or_else(|| anyhow!("unrecognized HMMER version {:?} of {}", version, tool.name))?;
+    if key < version_key(MIN_HMMER_VERSION).unwrap() {
+        bail!(
+            "{} at {:?} is HMMER {}, but at least HMMER {} is required",
+            tool.name,
+            tool.program,
+            version,
+            MIN_HMMER_VERSION
+        );
     }
-    debug!("{} sequences written to hmmsearch", cnt);
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        bail!("hmmsearch failed: {:?}", output);
+    debug!("using {} {} at {:?}", tool.name, version, tool.program);
+    Ok(version.to_string())
+}
+
+/// a uniquely named file in the temporary directory, removed when dropped
+struct ScratchFile(PathBuf);
+
+impl ScratchFile {
+    fn new(suffix: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self(std::env::temp_dir().join(format!(
+            "witch-ng-{}-{}.{}",
+            std::process::id(),
+            id,
+            suffix
+        )))
     }
-    // the following translates logic directly from UPP
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"([^\s]+)\s+([^\s]+)\s+([^\s]+)\s+([^\s]+)\s+([^\s]+)\s+([^\s]+)\s+([^\s]+)\s+([^\s]+)\s+([^\s]+)").unwrap();
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
     }
-    let raw_output = String::from_utf8(output.stdout)?;
-    let mut res: Vec<(u32, f64)> = vec![];
-    let mut start_reading = false;
-    for l in raw_output.lines().map(|l| l.trim()) {
-        if !start_reading && l.starts_with("E-value") {
-            start_reading = true;
-        } else if start_reading && l.is_empty() {
-            break;
-        } else if start_reading {
-            if let Some(caps) = RE.captures(l) {
-                let entire = caps.get(0).unwrap().as_str();
-                if entire.contains("--") {
-                    continue;
+}
+
+/// the rows of a HMMER tabular output, each split into `num_fields` fields with the last one
+/// (the free text description) possibly containing spaces
+fn table_rows(raw: &str, num_fields: usize) -> impl Iterator<Item = anyhow::Result<Vec<&str>>> {
+    raw.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.starts_with('#') && !l.trim().is_empty())
+        .map(move |(i, l)| {
+            let mut fields = Vec::with_capacity(num_fields);
+            let mut rest = l.trim_start();
+            while fields.len() + 1 < num_fields {
+                match rest.split_once(char::is_whitespace) {
+                    Some((field, r)) => {
+                        fields.push(field);
+                        rest = r.trim_start();
+                    }
+                    None => bail!(
+                        "line {}: expected {} fields, found {}",
+                        i + 1,
+                        num_fields,
+                        fields.len() + 1
+                    ),
                 }
-                let seq_name = caps.get(9).unwrap().as_str();
-                let seq_id = *seq_id.get(seq_name).unwrap();
-                let bitscore = caps.get(2).unwrap().as_str().parse::<f64>()?;
-                res.push((seq_id, bitscore));
             }
-        }
+            fields.push(rest.trim_end());
+            Ok(fields)
+        })
+}
+
+fn parse_field<T>(fields: &[&str], i: usize) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    fields[i]
+        .parse()
+        .with_context(|| format!("invalid value {:?} in column {}", fields[i], i + 1))
+}
+
+/// reads the sequence hits of `--tblout` and the domains of `--domtblout`
+fn parse_tabular_hits(tblout: &str, domtblout: &str) -> anyhow::Result<Vec<SequenceHit>> {
+    let lookup =
+        |name: &str| parse_id(name.as_bytes()).context("hmmsearch reported an unknown sequence");
+    let mut res: Vec<SequenceHit> = vec![];
+    let mut hit_ix: AHashMap<u32, usize> = AHashMap::new();
+    for row in table_rows(tblout, 19) {
+        let fields = row.context("malformed --tblout output")?;
+        let id = lookup(fields[0])?;
+        hit_ix.insert(id, res.len());
+        res.push(SequenceHit {
+            seq_id: id,
+            evalue: Some(parse_field(&fields, 4)?),
+            score: parse_field(&fields, 5)?,
+            bias: parse_field(&fields, 6)?,
+            domains: vec![],
+        });
+    }
+    for row in table_rows(domtblout, 23) {
+        let fields = row.context("malformed --domtblout output")?;
+        let id = lookup(fields[0])?;
+        let ix = *hit_ix
+            .get(&id)
+            .ok_or_else(|| anyhow!("domain of unreported sequence {:?}", fields[0]))?;
+        res[ix].domains.push(DomainHit {
+            evalue: parse_field(&fields, 12)?,
+            score: parse_field(&fields, 13)?,
+            bias: parse_field(&fields, 14)?,
+            hmm_range: (parse_field(&fields, 15)?, parse_field(&fields, 16)?),
+            alignment_range: (parse_field(&fields, 17)?, parse_field(&fields, 18)?),
+            envelope_range: (parse_field(&fields, 19)?, parse_field(&fields, 20)?),
+        });
     }
     Ok(res)
 }
+
+/// scores the queries (with their internal ids) against the HMM. hmmsearch has no alphabet
+/// options; it reads the queries in the alphabet of the HMM
+pub fn hmmsearch<'a, R>(
+    hmm_path: &PathBuf,
+    seqs: R,
+    config: &ExternalContext,
+) -> Result<Vec<SequenceHit>, ErrorKind>
+where
+    R: Iterator<Item = (u32, &'a OwnedRecord)>,
+{
+    let tool = &config.hmmer.hmmsearch;
+    let tblout = ScratchFile::new("tbl");
+    let domtblout = ScratchFile::new("domtbl");
+    let input = to_fasta_by_id(seqs);
+    debug!("{} bytes of sequences written to hmmsearch", input.len());
+    run(
+        tool,
+        Command::new(&tool.program)
+            .arg("--cpu")
+            .arg(if config.io_bound { "1" } else { "0" })
+            .arg("--noali")
+            .arg("--max")
+            .arg("-E")
+            .arg("999999999")
+            .arg("--domE")
+            .arg("999999999")
+            .arg("-o")
+            .arg("/dev/null")
+            .arg("--tblout")
+            .arg(&tblout.0)
+            .arg("--domtblout")
+            .arg(&domtblout.0)
+            .args(&tool.extra_args)
+            .arg(hmm_path)
+            .arg("-"),
+        input,
+        &config.limits,
+    )?;
+    let read = |f: &ScratchFile| {
+        std::fs::read_to_string(&f.0).with_context(|| format!("failed to read {:?}", f.0))
+    };
+    read(&tblout)
+        .and_then(|tbl| parse_tabular_hits(&tbl, &read(&domtblout)?))
+        .map_err(ErrorKind::InvalidOutput)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// in the layout of hmmsearch 3.3.2; HMMER only writes a description when the query has one
+    const TBLOUT: &str = "\
+#                                                               --- full sequence ---- --- best 1 domain ---- --- domain number estimation ----
+# target name        accession  query name           accession    E-value  score  bias   E-value  score  bias   exp reg clu  ov env dom rep inc description of target
+#------------------- ---------- -------------------- ---------- --------- ------ ----- --------- ------ -----   --- --- --- --- --- --- --- --- ---------------------
+3                    -          7                    -            2.1e-12   38.4   0.1   1.3e-07   22.6   0.0   2.0   2   0   0   2   2   2   2 -
+0                    -          7                    -                1.8   -2.5   4.3       3.5   -3.4   4.3   1.4   1   1   0   1   1   1   0 low complexity,  repeats
+#
+# Program:         hmmsearch
+# [ok]
+";
+
+    const DOMTBLOUT: &str = "\
+#                                                                            --- full sequence --- -------------- this domain -------------   hmm coord   ali coord   env coord
+# target name        accession   tlen query name           accession   qlen   E-value  score  bias   #  of  c-Evalue  i-Evalue  score  bias  from    to  from    to  from    to  acc description of target
+#------------------- ---------- ----- -------------------- ---------- ----- --------- ------ ----- --- --- --------- --------- ------ ----- ----- ----- ----- ----- ----- ----- ---- ---------------------
+3                    -             50 7                    -             24   2.1e-12   38.4   0.1   1   2   6.4e-08   1.3e-07   22.6   0.0     1    24     1    24     1    24 0.98 -
+3                    -             50 7                    -             24   2.1e-12   38.4   0.1   2   2   1.1e-06   2.2e-06   18.5   0.0     3    22    29    48    27    50 0.95 -
+0                    -             24 7                    -             24       1.8   -2.5   4.3   1   1       1.7       3.5   -3.4   4.3     5    12     9    16     2    24 0.71 low complexity,  repeats
+#
+# Program:         hmmsearch
+# [ok]
+";
+
+    #[test]
+    fn tabular_hits() {
+        let hits = parse_tabular_hits(TBLOUT, DOMTBLOUT).unwrap();
+        assert_eq!(hits.len(), 2);
+        let (first, second) = (&hits[0], &hits[1]);
+        assert_eq!(
+            (first.seq_id, first.evalue, first.score, first.bias),
+            (3, Some(2.1e-12), 38.4, 0.1)
+        );
+        assert_eq!(first.domains.len(), 2);
+        let domain = &first.domains[1];
+        assert_eq!(
+            (domain.evalue, domain.score, domain.bias),
+            (2.2e-06, 18.5, 0.0)
+        );
+        assert_eq!(domain.hmm_range, (3, 22));
+        assert_eq!(domain.alignment_range, (29, 48));
+        assert_eq!(domain.envelope_range, (27, 50));
+        // the description, with its spaces, is the last field and shifts no other
+        assert_eq!((second.seq_id, second.score, second.bias), (0, -2.5, 4.3));
+        assert_eq!(second.domains.len(), 1);
+        assert_eq!(second.domains[0].envelope_range, (2, 24));
+    }
+
+    #[test]
+    fn malformed_tables() {
+        let rows = table_rows(DOMTBLOUT, 23)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows[2][22], "low complexity,  repeats");
+        // --tblout lines have 19 fields, too few for the domains
+        let err = parse_tabular_hits(TBLOUT, TBLOUT).unwrap_err();
+        assert!(format!("{:#}", err).contains("expected 23 fields, found 19"));
+        // a line missing its description
+        let truncated = TBLOUT.replacen("   2 -\n", "   2\n", 1);
+        let err = parse_tabular_hits(&truncated, DOMTBLOUT).unwrap_err();
+        assert!(format!("{:#}", err).contains("expected 19 fields, found 18"));
+        // a domain of a sequence that is not in --tblout
+        let renamed = DOMTBLOUT.replacen("\n0   ", "\n1   ", 1);
+        assert!(parse_tabular_hits(TBLOUT, &renamed).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Writing the output alignment in the formats read by other tools
+use crate::config::OutputFormat;
+use ahash::AHashSet;
+use anyhow::bail;
+use fixedbitset::FixedBitSet;
+use std::io::Write;
+
+/// columns per block of the interleaved Clustal format
+const CLUSTAL_BLOCK: usize = 60;
+
+/// writes the rows of an alignment one at a time in `format`; `finish` must be called after the
+/// last row. Only Clustal, being interleaved, keeps the rows in memory
+pub struct AlignmentWriter<'a, W: Write> {
+    w: &'a mut W,
+    format: OutputFormat,
+    /// if each column is a backbone (homology) column, as opposed to an insertion column
+    match_columns: FixedBitSet,
+    /// names written so far, for the formats that cut them at the first whitespace
+    ids: AHashSet<Vec<u8>>,
+    clustal_rows: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// the name of a sequence up to its first whitespace, and the description after it
+fn split_header(head: &[u8]) -> (&[u8], &[u8]) {
+    let end = head
+        .iter()
+        .position(|c| c.is_ascii_whitespace())
+        .unwrap_or(head.len());
+    let desc_start = head[end..]
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .map_or(head.len(), |i| end + i);
+    (&head[..end], &head[desc_start..])
+}
+
+impl<'a, W: Write> AlignmentWriter<'a, W> {
+    /// starts writing `num_rows` rows, whose columns are backbone columns when set in
+    /// `match_columns`
+    pub fn new(
+        w: &'a mut W,
+        format: OutputFormat,
+        match_columns: FixedBitSet,
+        num_rows: usize,
+    ) -> anyhow::Result<Self> {
+        match format {
+            OutputFormat::Stockholm => writeln!(w, "# STOCKHOLM 1.0")?,
+            OutputFormat::Phylip => writeln!(w, "{} {}", num_rows, match_columns.len())?,
+            _ => {}
+        }
+        Ok(Self {
+            w,
+            format,
+            match_columns,
+            ids: AHashSet::new(),
+            clustal_rows: vec![],
+        })
+    }
+
+    /// the name of a sequence in the formats without descriptions, which must stay unique
+    fn id<'h>(&mut self, head: &'h [u8]) -> anyhow::Result<&'h [u8]> {
+        let (id, _) = split_header(head);
+        if id.is_empty() {
+            bail!(
+                "a sequence without a name cannot be written in {:?}",
+                self.format
+            );
+        }
+        if !self.ids.insert(id.to_vec()) {
+            bail!(
+                "more than one sequence is named {:?} up to the first whitespace, which {:?} requires to be unique",
+                String::from_utf8_lossy(id),
+                self.format
+            );
+        }
+        Ok(id)
+    }
+
+    /// `row` with the gaps of insertion columns written as '.'
+    fn with_insert_gaps(&self, mut row: Vec<u8>) -> Vec<u8> {
+        for (i, c) in row.iter_mut().enumerate() {
+            if *c == b'-' && !self.match_columns[i] {
+                *c = b'.';
+            }
+        }
+        row
+    }
+
+    pub fn write_row(&mut self, head: &[u8], row: Vec<u8>) -> anyhow::Result<()> {
+        match self.format {
+            OutputFormat::Fasta | OutputFormat::A2m | OutputFormat::A3m => {
+                let row = match self.format {
+                    OutputFormat::Fasta => row,
+                    OutputFormat::A2m => self.with_insert_gaps(row),
+                    _ => row
+                        .into_iter()
+                        .enumerate()
+                        .filter(|&(i, c)| c != b'-' || self.match_columns[i])
+                        .map(|(_, c)| c)
+                        .collect(),
+                };
+                self.w.write_all(b">")?;
+                self.w.write_all(head)?;
+                self.w.write_all(b"\n")?;
+                self.w.write_all(&row)?;
+                self.w.write_all(b"\n")?;
+            }
+            OutputFormat::Stockholm => {
+                let id = self.id(head)?;
+                let (_, desc) = split_header(head);
+                if !desc.is_empty() {
+                    self.w.write_all(b"#=GS ")?;
+                    self.w.write_all(id)?;
+                    self.w.write_all(b" DE ")?;
+                    self.w.write_all(desc)?;
+                    self.w.write_all(b"\n")?;
+                }
+                let row = self.with_insert_gaps(row);
+                self.w.write_all(id)?;
+                self.w.write_all(b" ")?;
+                self.w.write_all(&row)?;
+                self.w.write_all(b"\n")?;
+            }
+            OutputFormat::Phylip => {
+                let id = self.id(head)?;
+                self.w.write_all(id)?;
+                self.w.write_all(b" ")?;
+                self.w.write_all(&row)?;
+                self.w.write_all(b"\n")?;
+            }
+            OutputFormat::Clustal => {
+                let id = self.id(head)?.to_vec();
+                self.clustal_rows.push((id, row));
+            }
+        }
+        Ok(())
+    }
+
+    /// writes what follows the last row
+    pub fn finish(self) -> anyhow::Result<()> {
+        match self.format {
+            OutputFormat::Stockholm => {
+                let rf = (0..self.match_columns.len())
+                    .map(|i| if self.match_columns[i] { b'x' } else { b'.' })
+                    .collect::<Vec<_>>();
+                self.w.write_all(b"#=GC RF ")?;
+                self.w.write_all(&rf)?;
+                self.w.write_all(b"\n//\n")?;
+            }
+            OutputFormat::Clustal => {
+                writeln!(self.w, "CLUSTAL W multiple sequence alignment\n")?;
+                let width = self.clustal_rows.iter().map(|(id, _)| id.len()).max();
+                let width = width.unwrap_or(0) + 1;
+                for start in (0..self.match_columns.len()).step_by(CLUSTAL_BLOCK) {
+                    let end = (start + CLUSTAL_BLOCK).min(self.match_columns.len());
+                    for (id, row) in &self.clustal_rows {
+                        self.w.write_all(id)?;
+                        write!(self.w, "{:1$}", "", width - id.len())?;
+                        self.w.write_all(&row[start..end])?;
+                        self.w.write_all(b"\n")?;
+                    }
+                    // '*' marks the columns where all rows have the same residue
+                    let conserved = (start..end)
+                        .map(|j| {
+                            let mut column = self.clustal_rows.iter().map(|(_, r)| r[j]);
+                            let first = column.next().unwrap_or(b'-');
+                            let same = first.is_ascii_alphabetic()
+                                && column.all(|c| c.eq_ignore_ascii_case(&first));
+                            if same {
+                                b'*'
+                            } else {
+                                b' '
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    write!(self.w, "{:1$}", "", width)?;
+                    self.w.write_all(&conserved)?;
+                    self.w.write_all(b"\n\n")?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compact_printer::FormattedHomologies;
+    use seq_io::fasta::OwnedRecord;
+    use std::fs;
+
+    /// a backbone of three columns (rows "ACG" and "A-G") and two queries: "ACTG", whose T is a
+    /// singleton insertion between the second and third backbone columns, and "AG"
+    fn homologies(has_backbone: bool) -> FormattedHomologies {
+        let mut singleton_letters =
+            vec![FixedBitSet::with_capacity(4), FixedBitSet::with_capacity(2)];
+        singleton_letters[0].insert(2);
+        let mut letter_positions = vec![vec![0, 1, 2, 3], vec![0, 3]];
+        if has_backbone {
+            singleton_letters.push(FixedBitSet::with_capacity(3));
+            letter_positions.push(vec![0, 1, 3]);
+        }
+        FormattedHomologies {
+            num_visual_columns: 4,
+            singleton_letters,
+            letter_positions,
+            homology_columns: vec![0, 1, 3],
+            singletons_masked: false,
+            has_backbone,
+        }
+    }
+
+    fn queries() -> Vec<OwnedRecord> {
+        vec![
+            OwnedRecord {
+                head: b"q1 first read".to_vec(),
+                seq: b"ACTG".to_vec(),
+            },
+            OwnedRecord {
+                head: b"q2".to_vec(),
+                seq: b"AG".to_vec(),
+            },
+        ]
+    }
+
+    /// the output alignment in `format`
+    fn write(format: OutputFormat, has_backbone: bool) -> String {
+        let backbone = std::env::temp_dir().join(format!(
+            "witch-ng-formats-{}-{:?}-{}.afa",
+            std::process::id(),
+            format,
+            has_backbone
+        ));
+        fs::write(&backbone, ">b1\nACG\n>b2\nA-G\n").unwrap();
+        let mut out = vec![];
+        homologies(has_backbone)
+            .write_all_sequences(&queries(), &backbone, format, &mut out)
+            .unwrap();
+        fs::remove_file(&backbone).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn fasta() {
+        assert_eq!(
+            write(OutputFormat::Fasta, true),
+            ">b1\nAC-G\n>b2\nA--G\n>q1 first read\nACtG\n>q2\nA--G\n"
+        );
+    }
+
+    #[test]
+    fn stockholm_marks_backbone_columns() {
+        assert_eq!(
+            write(OutputFormat::Stockholm, true),
+            "# STOCKHOLM 1.0\nb1 AC.G\nb2 A-.G\n#=GS q1 DE first read\nq1 ACtG\nq2 A-.G\n#=GC RF xx.x\n//\n"
+        );
+    }
+
+    #[test]
+    fn a2m_dots_insert_gaps() {
+        assert_eq!(
+            write(OutputFormat::A2m, true),
+            ">b1\nAC.G\n>b2\nA-.G\n>q1 first read\nACtG\n>q2\nA-.G\n"
+        );
+    }
+
+    #[test]
+    fn a3m_drops_insert_gaps() {
+        assert_eq!(
+            write(OutputFormat::A3m, true),
+            ">b1\nACG\n>b2\nA-G\n>q1 first read\nACtG\n>q2\nA-G\n"
+        );
+    }
+
+    #[test]
+    fn phylip_counts_written_rows() {
+        assert_eq!(
+            write(OutputFormat::Phylip, true),
+            "4 4\nb1 AC-G\nb2 A--G\nq1 ACtG\nq2 A--G\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Phylip, false),
+            "2 4\nq1 ACtG\nq2 A--G\n"
+        );
+    }
+
+    #[test]
+    fn clustal_wraps_blocks() {
+        let width = CLUSTAL_BLOCK + 5;
+        let mut match_columns = FixedBitSet::with_capacity(width);
+        match_columns.insert_range(..);
+        let mut out = vec![];
+        let mut writer =
+            AlignmentWriter::new(&mut out, OutputFormat::Clustal, match_columns, 2).unwrap();
+        writer.write_row(b"r1 desc", vec![b'A'; width]).unwrap();
+        let mut row = vec![b'A'; CLUSTAL_BLOCK];
+        row.extend(b"C-CCC");
+        writer.write_row(b"r22", row).unwrap();
+        writer.finish().unwrap();
+        let block = "A".repeat(CLUSTAL_BLOCK);
+        let expected = format!(
+            "CLUSTAL W multiple sequence alignment\n\n\
+             r1  {block}\nr22 {block}\n    {stars}\n\n\
+             r1  AAAAA\nr22 C-CCC\n         \n\n",
+            block = block,
+            stars = "*".repeat(CLUSTAL_BLOCK)
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
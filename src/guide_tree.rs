@@ -0,0 +1,318 @@
+//! Inferring a guide tree from the backbone alignment, for backbones given without a tree
+use crate::{
+    config::{DistanceModel, GuideTreeMethod, GuideTreeOptions},
+    hmm::Alphabet,
+    tree::is_plain_newick_label,
+};
+use anyhow::bail;
+use rayon::prelude::*;
+use seq_io::fasta::OwnedRecord;
+use std::fmt::Write;
+
+/// a symmetric matrix of distances between sequences
+pub(crate) struct DistanceMatrix {
+    n: usize,
+    values: Vec<f64>,
+}
+
+impl DistanceMatrix {
+    /// the distances `dist(i, j)` between `n` sequences, computed in parallel. Undefined
+    /// distances are set to the largest defined one (or 1 if none is), so that those sequences are
+    /// placed as outliers
+    pub(crate) fn from_fn<F>(n: usize, dist: F) -> Self
+    where
+        F: Fn(usize, usize) -> Option<f64> + Sync,
+    {
+        let rows = (0..n)
+            .into_par_iter()
+            .map(|i| {
+                (0..n)
+                    .map(|j| if i == j { Some(0.0) } else { dist(i, j) })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let fallback = rows
+            .iter()
+            .flatten()
+            .flatten()
+            .copied()
+            .max_by(f64::total_cmp)
+            .filter(|&d| d > 0.0)
+            .unwrap_or(1.0);
+        Self {
+            n,
+            values: rows
+                .into_iter()
+                .flatten()
+                .map(|d| d.unwrap_or(fallback))
+                .collect(),
+        }
+    }
+
+    fn get(&self, i: usize, j: usize) -> f64 {
+        self.values[i * self.n + j]
+    }
+
+    fn set(&mut self, i: usize, j: usize, d: f64) {
+        self.values[i * self.n + j] = d;
+        self.values[j * self.n + i] = d;
+    }
+}
+
+/// the proportion of differing residues among the columns where both sequences have a canonical
+/// residue, or `None` if there is no such column
+fn p_distance(x: &[u8], y: &[u8]) -> Option<f64> {
+    let (mut sites, mut diffs) = (0usize, 0usize);
+    for (&a, &b) in x.iter().zip(y) {
+        if a != u8::MAX && b != u8::MAX {
+            sites += 1;
+            if a != b {
+                diffs += 1;
+            }
+        }
+    }
+    (sites > 0).then(|| diffs as f64 / sites as f64)
+}
+
+/// determinant by Gaussian elimination with partial pivoting
+fn determinant(mut m: Vec<f64>, k: usize) -> f64 {
+    let mut det = 1.0;
+    for c in 0..k {
+        let pivot = (c..k)
+            .max_by(|&a, &b| m[a * k + c].abs().total_cmp(&m[b * k + c].abs()))
+            .unwrap();
+        if m[pivot * k + c] == 0.0 {
+            return 0.0;
+        }
+        if pivot != c {
+            for j in 0..k {
+                m.swap(pivot * k + j, c * k + j);
+            }
+            det = -det;
+        }
+        det *= m[c * k + c];
+        for r in c + 1..k {
+            let f = m[r * k + c] / m[c * k + c];
+            for j in c..k {
+                m[r * k + j] -= f * m[c * k + j];
+            }
+        }
+    }
+    det
+}
+
+/// the LogDet (paralinear) distance of Lake (1994), or `None` if it is undefined, e.g., for
+/// sequences too divergent or too short to have a non-singular divergence matrix
+fn logdet_distance(x: &[u8], y: &[u8], k: usize) -> Option<f64> {
+    let mut f = vec![0f64; k * k];
+    let mut sites = 0usize;
+    for (&a, &b) in x.iter().zip(y) {
+        if a != u8::MAX && b != u8::MAX {
+            f[a as usize * k + b as usize] += 1.0;
+            sites += 1;
+        }
+    }
+    if sites == 0 {
+        return None;
+    }
+    f.iter_mut().for_each(|v| *v /= sites as f64);
+    let row_freqs = (0..k).map(|i| f[i * k..(i + 1) * k].iter().sum::<f64>());
+    let col_freqs = (0..k).map(|j| (0..k).map(|i| f[i * k + j]).sum::<f64>());
+    let log_marginals = row_freqs
+        .chain(col_freqs)
+        .map(|p| (p > 0.0).then(|| p.ln()))
+        .sum::<Option<f64>>()?;
+    let det = determinant(f, k);
+    if det <= 0.0 {
+        return None;
+    }
+    Some((-(det.ln() - log_marginals / 2.0) / k as f64).max(0.0))
+}
+
+/// pairwise distances between the rows of the alignment
+fn distance_matrix(
+    records: &[OwnedRecord],
+    alphabet: Alphabet,
+    model: DistanceModel,
+) -> DistanceMatrix {
+    let table = alphabet.code_table();
+    let k = alphabet.size();
+    // canonical residues as their codes, everything else as u8::MAX
+    let digitized = records
+        .iter()
+        .map(|r| {
+            r.seq
+                .iter()
+                .map(|&c| {
+                    let code = table[c.to_ascii_uppercase() as usize];
+                    if c.is_ascii_alphabetic() && (code as usize) < k {
+                        code
+                    } else {
+                        u8::MAX
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    DistanceMatrix::from_fn(records.len(), |i, j| match model {
+        DistanceModel::PDistance => p_distance(&digitized[i], &digitized[j]),
+        DistanceModel::LogDet => logdet_distance(&digitized[i], &digitized[j], k),
+    })
+}
+
+/// the clusters still to be joined, with the distances between them
+struct Clusters<T> {
+    items: Vec<Option<T>>,
+    active: Vec<usize>,
+    dist: DistanceMatrix,
+}
+
+impl<T> Clusters<T> {
+    fn new(items: Vec<T>, dist: DistanceMatrix) -> Self {
+        Self {
+            active: (0..items.len()).collect(),
+            items: items.into_iter().map(Some).collect(),
+            dist,
+        }
+    }
+
+    fn take(&mut self, i: usize) -> T {
+        self.items[i].take().expect("cluster already joined")
+    }
+
+    /// replaces `i` by the result of joining `i` and `j`, and forgets `j`
+    fn join<F>(&mut self, i: usize, j: usize, join: F)
+    where
+        F: FnOnce(T, T) -> T,
+    {
+        let (a, b) = (self.take(i), self.take(j));
+        self.items[i] = Some(join(a, b));
+        self.active.retain(|&x| x != j);
+    }
+}
+
+/// a Newick subtree joining two subtrees with the given branch lengths
+fn join_newick(a: String, b: String, la: f64, lb: f64) -> String {
+    let mut joined = String::new();
+    write!(joined, "({}:{},{}:{})", a, la.max(0.0), b, lb.max(0.0)).unwrap();
+    joined
+}
+
+/// neighbor joining (Saitou and Nei, 1987), giving an unrooted tree
+fn neighbor_joining(names: &[String], dist: DistanceMatrix) -> String {
+    let mut c = Clusters::new(names.to_vec(), dist);
+    while c.active.len() > 3 {
+        let m = c.active.len();
+        let row_sums = c
+            .active
+            .iter()
+            .map(|&i| c.active.iter().map(|&j| c.dist.get(i, j)).sum::<f64>())
+            .collect::<Vec<_>>();
+        let (mut best, mut best_q) = ((0, 1), f64::INFINITY);
+        for a in 0..m {
+            for b in a + 1..m {
+                let (i, j) = (c.active[a], c.active[b]);
+                let q = (m - 2) as f64 * c.dist.get(i, j) - row_sums[a] - row_sums[b];
+                if q < best_q {
+                    best_q = q;
+                    best = (a, b);
+                }
+            }
+        }
+        let (a, b) = best;
+        let (i, j) = (c.active[a], c.active[b]);
+        let dij = c.dist.get(i, j);
+        let li = dij / 2.0 + (row_sums[a] - row_sums[b]) / (2.0 * (m - 2) as f64);
+        for &x in &c.active {
+            if x != i && x != j {
+                let d = (c.dist.get(i, x) + c.dist.get(j, x) - dij) / 2.0;
+                c.dist.set(i, x, d);
+            }
+        }
+        c.join(i, j, |a, b| join_newick(a, b, li, dij - li));
+    }
+    match c.active[..] {
+        [i] => format!("{};", c.take(i)),
+        [i, j] => {
+            let d = c.dist.get(i, j) / 2.0;
+            format!("{};", join_newick(c.take(i), c.take(j), d, d))
+        }
+        [i, j, x] => {
+            let (dij, dix, djx) = (c.dist.get(i, j), c.dist.get(i, x), c.dist.get(j, x));
+            format!(
+                "({}:{},{}:{},{}:{});",
+                c.take(i),
+                ((dij + dix - djx) / 2.0).max(0.0),
+                c.take(j),
+                ((dij + djx - dix) / 2.0).max(0.0),
+                c.take(x),
+                ((dix + djx - dij) / 2.0).max(0.0)
+            )
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// UPGMA, joining `items` (at least one) into a single one along an ultrametric rooted tree;
+/// `join` is given the two items joined and the lengths of the branches to them
+pub(crate) fn upgma<T, F>(items: Vec<T>, dist: DistanceMatrix, mut join: F) -> T
+where
+    F: FnMut(T, T, f64, f64) -> T,
+{
+    let n = items.len();
+    let mut c = Clusters::new(items, dist);
+    let mut size = vec![1usize; n];
+    let mut height = vec![0f64; n];
+    while c.active.len() > 1 {
+        let (mut best, mut best_d) = ((c.active[0], c.active[1]), f64::INFINITY);
+        for (a, &i) in c.active.iter().enumerate() {
+            for &j in &c.active[a + 1..] {
+                if c.dist.get(i, j) < best_d {
+                    best_d = c.dist.get(i, j);
+                    best = (i, j);
+                }
+            }
+        }
+        let (i, j) = best;
+        let h = (best_d / 2.0).max(height[i]).max(height[j]);
+        for &x in &c.active {
+            if x != i && x != j {
+                let d = (c.dist.get(i, x) * size[i] as f64 + c.dist.get(j, x) * size[j] as f64)
+                    / (size[i] + size[j]) as f64;
+                c.dist.set(i, x, d);
+            }
+        }
+        let (li, lj) = (h - height[i], h - height[j]);
+        c.join(i, j, |a, b| join(a, b, li, lj));
+        size[i] += size[j];
+        height[i] = h;
+    }
+    let last = c.active[0];
+    c.take(last)
+}
+
+/// infers a tree (in Newick) of the rows of the backbone alignment, named by their headers
+pub fn guide_tree(
+    records: &[OwnedRecord],
+    alphabet: Alphabet,
+    options: GuideTreeOptions,
+) -> anyhow::Result<String> {
+    let names = records
+        .iter()
+        .map(|r| String::from_utf8(r.head.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if names.is_empty() {
+        bail!("cannot infer a tree of an empty backbone");
+    }
+    if let Some(name) = names.iter().find(|name| !is_plain_newick_label(name)) {
+        bail!(
+            "sequence name {:?} cannot be written in a Newick tree; rename the sequence or give a backbone tree with --tree",
+            name
+        );
+    }
+    let dist = distance_matrix(records, alphabet, options.distance);
+    Ok(match options.method {
+        GuideTreeMethod::NeighborJoining => neighbor_joining(&names, dist),
+        GuideTreeMethod::Upgma => format!("{};", upgma(names, dist, join_newick)),
+    })
+}
@@ -0,0 +1,760 @@
+//! Reading and writing HMMER3 profile HMMs (the `subsets/*.hmm` files of an eHMM)
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context};
+use clap::ValueEnum;
+use ndarray::{Array, Ix2};
+use serde::{Deserialize, Serialize};
+
+use crate::structures::HmmMeta;
+
+/// transitions of each node, in the order they appear in the file
+pub const TRANSITIONS: [&str; 7] = ["m->m", "m->i", "m->d", "i->m", "i->i", "d->m", "d->d"];
+pub const T_MM: usize = 0;
+pub const T_MI: usize = 1;
+pub const T_MD: usize = 2;
+pub const T_IM: usize = 3;
+pub const T_II: usize = 4;
+pub const T_DM: usize = 5;
+pub const T_DD: usize = 6;
+
+/// the residues of the sequences an HMM is built from, as in the ALPH line of HMMER files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+pub enum Alphabet {
+    /// protein sequences
+    Amino,
+    /// DNA sequences, with T
+    Dna,
+    /// RNA sequences, with U
+    Rna,
+}
+
+impl Alphabet {
+    /// the canonical residues, in HMMER's order
+    pub fn symbols(&self) -> &'static [u8] {
+        match self {
+            Alphabet::Amino => b"ACDEFGHIKLMNPQRSTVWY",
+            Alphabet::Dna => b"ACGT",
+            Alphabet::Rna => b"ACGU",
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.symbols().len()
+    }
+
+    pub fn from_hmmer_name(name: &str) -> anyhow::Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "amino" => Ok(Alphabet::Amino),
+            "dna" => Ok(Alphabet::Dna),
+            "rna" => Ok(Alphabet::Rna),
+            _ => bail!("unsupported alphabet {:?}", name),
+        }
+    }
+
+    /// degenerate residue codes (besides the "any residue" code) and the canonical residues they stand for
+    pub fn degenerate_symbols(&self) -> &'static [(u8, &'static [u8])] {
+        match self {
+            Alphabet::Amino => &[
+                (b'B', b"DN"),
+                (b'J', b"IL"),
+                (b'Z', b"EQ"),
+                (b'O', b"K"),
+                (b'U', b"C"),
+            ],
+            Alphabet::Dna => &[
+                (b'R', b"AG"),
+                (b'Y', b"CT"),
+                (b'M', b"AC"),
+                (b'K', b"GT"),
+                (b'S', b"CG"),
+                (b'W', b"AT"),
+                (b'H', b"ACT"),
+                (b'B', b"CGT"),
+                (b'V', b"ACG"),
+                (b'D', b"AGT"),
+                (b'U', b"T"),
+            ],
+            Alphabet::Rna => &[
+                (b'R', b"AG"),
+                (b'Y', b"CU"),
+                (b'M', b"AC"),
+                (b'K', b"GU"),
+                (b'S', b"CG"),
+                (b'W', b"AU"),
+                (b'H', b"ACU"),
+                (b'B', b"CGU"),
+                (b'V', b"ACG"),
+                (b'D', b"AGU"),
+                (b'T', b"U"),
+            ],
+        }
+    }
+
+    /// number of residue codes produced by `digitize`
+    pub fn num_codes(&self) -> usize {
+        self.size() + self.degenerate_symbols().len() + 1
+    }
+
+    /// the canonical residues that a residue code stands for
+    pub fn code_residues(&self, code: u8) -> Vec<usize> {
+        let k = self.size();
+        let code = code as usize;
+        if code < k {
+            vec![code]
+        } else if code - k < self.degenerate_symbols().len() {
+            let symbols = self.symbols();
+            self.degenerate_symbols()[code - k]
+                .1
+                .iter()
+                .map(|c| symbols.iter().position(|s| s == c).unwrap())
+                .collect()
+        } else {
+            (0..k).collect()
+        }
+    }
+
+    /// residue code of each (uppercase) letter: canonical residues become `0..K`, degenerate
+    /// residues follow, and any other letter becomes the "any residue" code
+    pub fn code_table(&self) -> [u8; 256] {
+        let mut table = [(self.num_codes() - 1) as u8; 256];
+        for (i, &c) in self.symbols().iter().enumerate() {
+            table[c as usize] = i as u8;
+        }
+        for (i, &(c, _)) in self.degenerate_symbols().iter().enumerate() {
+            table[c as usize] = (self.size() + i) as u8;
+        }
+        table
+    }
+
+    /// the letter standing for any residue
+    fn any_symbol(&self) -> u8 {
+        match self {
+            Alphabet::Amino => b'X',
+            Alphabet::Dna | Alphabet::Rna => b'N',
+        }
+    }
+
+    /// the first letter of a (possibly gapped) sequence, and its position, that HMMER would not
+    /// accept in this alphabet. Besides residues, `*` is accepted, and `X` in nucleotides
+    pub fn find_invalid(&self, seq: &[u8]) -> Option<(usize, u8)> {
+        let mut valid = [false; 256];
+        let letters = self
+            .symbols()
+            .iter()
+            .chain(self.degenerate_symbols().iter().map(|(c, _)| c))
+            .copied()
+            .chain([self.any_symbol(), b'X', b'*', b'-', b'.']);
+        for c in letters {
+            valid[c as usize] = true;
+        }
+        seq.iter()
+            .position(|c| !valid[c.to_ascii_uppercase() as usize])
+            .map(|i| (i, seq[i]))
+    }
+
+    /// convert a (possibly gapped) sequence to residue codes (see `code_table`), dropping gaps
+    pub fn digitize(&self, seq: &[u8]) -> Vec<u8> {
+        let table = self.code_table();
+        seq.iter()
+            .filter(|&&c| c != b'-' && c != b'.')
+            .map(|c| table[c.to_ascii_uppercase() as usize])
+            .collect()
+    }
+
+    /// guess the alphabet of some sequences: nucleotides if at least 90% of the letters are
+    /// A, C, G, T, U or N (RNA if there is U but no T), amino acids otherwise
+    pub fn guess<'a, I>(seqs: I) -> Self
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        let mut counts = LetterCounts::default();
+        seqs.for_each(|seq| counts.add(seq));
+        counts.guess()
+    }
+
+    /// background residue frequencies of the null model, same as HMMER's
+    pub fn background(&self) -> Vec<f64> {
+        match self {
+            Alphabet::Amino => {
+                let f = [
+                    0.0787945, 0.0151600, 0.0535222, 0.0668298, 0.0397062, 0.0695071, 0.0229198,
+                    0.0590092, 0.0594422, 0.0963728, 0.0237718, 0.0414386, 0.0482904, 0.0395639,
+                    0.0540978, 0.0683364, 0.0540687, 0.0673417, 0.0114135, 0.0304133,
+                ];
+                let total: f64 = f.iter().sum();
+                f.iter().map(|x| x / total).collect()
+            }
+            Alphabet::Dna | Alphabet::Rna => vec![0.25; 4],
+        }
+    }
+
+    /// the option of hmmbuild and hmmalign that sets this alphabet
+    pub fn hmmer_flag(&self) -> &'static str {
+        match self {
+            Alphabet::Amino => "--amino",
+            Alphabet::Dna => "--dna",
+            Alphabet::Rna => "--rna",
+        }
+    }
+
+    pub fn hmmer_name(&self) -> &'static str {
+        match self {
+            Alphabet::Amino => "amino",
+            Alphabet::Dna => "DNA",
+            Alphabet::Rna => "RNA",
+        }
+    }
+}
+
+/// the letters of some sequences, counted as they are read, to guess their alphabet without
+/// keeping them
+#[derive(Debug, Clone)]
+pub struct LetterCounts([usize; 256]);
+
+impl Default for LetterCounts {
+    fn default() -> Self {
+        Self([0; 256])
+    }
+}
+
+impl LetterCounts {
+    pub fn add(&mut self, seq: &[u8]) {
+        for &c in seq {
+            self.0[c.to_ascii_uppercase() as usize] += 1;
+        }
+    }
+
+    /// see `Alphabet::guess`
+    pub fn guess(&self) -> Alphabet {
+        let counts = &self.0;
+        let total: usize = (b'A'..=b'Z').map(|c| counts[c as usize]).sum();
+        let nucleotides: usize = b"ACGTUN".iter().map(|&c| counts[c as usize]).sum();
+        if total == 0 || (nucleotides as f64) < 0.9 * total as f64 {
+            Alphabet::Amino
+        } else if counts[b'U' as usize] > 0 && counts[b'T' as usize] == 0 {
+            Alphabet::Rna
+        } else {
+            Alphabet::Dna
+        }
+    }
+}
+
+/// location and slope of the Gumbel/exponential tails used for E-values (`STATS LOCAL ...`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EvalueParams {
+    pub msv: (f64, f64),
+    pub viterbi: (f64, f64),
+    pub forward: (f64, f64),
+}
+
+/// A profile HMM of length M, with nodes 0 (the begin node) to M.
+///
+/// All probabilities are stored as natural logarithms, `f64::NEG_INFINITY` for `*` (zero probability).
+#[derive(Debug, Clone)]
+pub struct ProfileHmm {
+    pub name: String,
+    pub alphabet: Alphabet,
+    /// number of match states (M)
+    pub length: usize,
+    pub nseq: Option<usize>,
+    pub effn: Option<f64>,
+    pub stats: Option<EvalueParams>,
+    /// average match emissions (`COMPO`), if present
+    pub compo: Option<Vec<f64>>,
+    /// (M + 1) x K match emissions; row 0 is unused
+    pub match_emissions: Array<f64, Ix2>,
+    /// (M + 1) x K insert emissions
+    pub insert_emissions: Array<f64, Ix2>,
+    /// (M + 1) x 7 transitions, see `TRANSITIONS`
+    pub transitions: Array<f64, Ix2>,
+    /// for each match state, the 1-based column in the alignment it was built from (`MAP`)
+    pub map: Option<Vec<usize>>,
+    /// consensus residue of each match state (`CONS`)
+    pub consensus: Option<Vec<u8>>,
+    /// reference annotation of each match state (`RF`)
+    pub rf: Option<Vec<u8>>,
+    /// consensus structure annotation of each match state (`CS`)
+    pub cs: Option<Vec<u8>>,
+}
+
+fn parse_log_prob(field: &str) -> anyhow::Result<f64> {
+    if field == "*" {
+        Ok(f64::NEG_INFINITY)
+    } else {
+        Ok(-field
+            .parse::<f64>()
+            .with_context(|| format!("invalid probability {:?}", field))?)
+    }
+}
+
+fn parse_log_probs(fields: &[&str], row: &mut [f64]) -> anyhow::Result<()> {
+    if fields.len() < row.len() {
+        bail!("expected {} values, found {}", row.len(), fields.len());
+    }
+    for (r, f) in row.iter_mut().zip(fields) {
+        *r = parse_log_prob(f)?;
+    }
+    Ok(())
+}
+
+/// the inverse of `parse_log_prob`, in HMMER's fixed width
+fn write_log_prob<W: Write>(w: &mut W, lp: f64) -> std::io::Result<()> {
+    if lp == f64::NEG_INFINITY {
+        write!(w, " {:>8}", "*")
+    } else {
+        write!(w, " {:8.5}", if lp == 0.0 { 0.0 } else { -lp })
+    }
+}
+
+fn is_flag_set(value: Option<&String>) -> bool {
+    value.map(|v| v.eq_ignore_ascii_case("yes")) == Some(true)
+}
+
+impl ProfileHmm {
+    pub fn from_path<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        Self::from_reader(BufReader::new(File::open(path)?))
+            .with_context(|| format!("failed to parse HMM at {:?}", path))
+    }
+
+    /// parses the first HMM in HMMER3 (3/a to 3/f) save file format
+    pub fn from_reader<R>(reader: R) -> anyhow::Result<Self>
+    where
+        R: BufRead,
+    {
+        let mut lines = reader
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i + 1, l))
+            .filter(|(_, l)| l.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true));
+        let mut next_line = || -> anyhow::Result<(usize, String)> {
+            match lines.next() {
+                Some((i, l)) => Ok((i, l?)),
+                None => bail!("unexpected end of file"),
+            }
+        };
+        let (_, magic) = next_line()?;
+        if !magic.starts_with("HMMER3/") {
+            bail!("not a HMMER3 file (found header {:?})", magic);
+        }
+        // match lines annotate MAP, CONS (since 3/e), RF, MM (since 3/f) and CS
+        let (has_cons_field, has_mm_field) = match magic.as_bytes().get(7) {
+            Some(b'f') => (true, true),
+            Some(b'e') => (true, false),
+            Some(b'a'..=b'd') => (false, false),
+            _ => bail!("unsupported HMMER3 format {:?}", magic),
+        };
+        let num_annotations = 3 + has_cons_field as usize + has_mm_field as usize;
+        let mut header: Vec<(String, String)> = vec![];
+        let mut stats: [Option<(f64, f64)>; 3] = [None; 3];
+        loop {
+            let (lineno, l) = next_line()?;
+            let (tag, rest) = l.split_once(char::is_whitespace).unwrap_or((&l, ""));
+            let rest = rest.trim();
+            match tag {
+                "HMM" => break,
+                "STATS" => {
+                    let fields = rest.split_whitespace().collect::<Vec<_>>();
+                    if fields.len() != 4 || fields[0] != "LOCAL" {
+                        bail!("line {}: malformed STATS line", lineno);
+                    }
+                    let ix = match fields[1] {
+                        "MSV" => 0,
+                        "VITERBI" => 1,
+                        "FORWARD" => 2,
+                        other => bail!("line {}: unknown STATS type {:?}", lineno, other),
+                    };
+                    stats[ix] = Some((fields[2].parse()?, fields[3].parse()?));
+                }
+                _ => header.push((tag.to_string(), rest.to_string())),
+            }
+        }
+        let get = |tag: &str| {
+            header
+                .iter()
+                .find(|(t, _)| t == tag)
+                .map(|(_, value)| value)
+        };
+        let name = get("NAME")
+            .ok_or_else(|| anyhow!("missing NAME"))?
+            .to_string();
+        let length: usize = get("LENG")
+            .ok_or_else(|| anyhow!("missing LENG"))?
+            .parse()
+            .context("invalid LENG")?;
+        let alphabet =
+            Alphabet::from_hmmer_name(get("ALPH").ok_or_else(|| anyhow!("missing ALPH"))?)?;
+        let nseq = get("NSEQ").map(|v| v.parse()).transpose()?;
+        let effn = get("EFFN").map(|v| v.parse()).transpose()?;
+        let has_map = is_flag_set(get("MAP"));
+        let has_cons = is_flag_set(get("CONS"));
+        let has_rf = is_flag_set(get("RF"));
+        let has_cs = is_flag_set(get("CS"));
+        let stats = match stats {
+            [Some(msv), Some(viterbi), Some(forward)] => Some(EvalueParams {
+                msv,
+                viterbi,
+                forward,
+            }),
+            _ => None,
+        };
+
+        let k = alphabet.size();
+        let mut match_emissions = Array::<f64, _>::from_elem((length + 1, k), f64::NEG_INFINITY);
+        let mut insert_emissions = Array::<f64, _>::from_elem((length + 1, k), f64::NEG_INFINITY);
+        let mut transitions = Array::<f64, _>::from_elem((length + 1, 7), f64::NEG_INFINITY);
+        let mut map = Vec::with_capacity(length);
+        let mut consensus = Vec::with_capacity(length);
+        let mut rf = Vec::with_capacity(length);
+        let mut cs = Vec::with_capacity(length);
+        let mut buf = vec![0f64; k];
+
+        // the transition header line, already past the "HMM" line with the alphabet symbols
+        let (lineno, l) = next_line()?;
+        if !l.trim_start().starts_with("m->m") {
+            bail!("line {}: expected transition header", lineno);
+        }
+        let (mut lineno, mut l) = next_line()?;
+        let mut compo = None;
+        if l.trim_start().starts_with("COMPO") {
+            let fields = l.split_whitespace().skip(1).collect::<Vec<_>>();
+            parse_log_probs(&fields, &mut buf).with_context(|| format!("line {}", lineno))?;
+            compo = Some(buf.clone());
+            (lineno, l) = next_line()?;
+        }
+        // node 0 only has insert emissions and transitions out of the begin state
+        for node in 0..=length {
+            if node > 0 {
+                let fields = l.split_whitespace().collect::<Vec<_>>();
+                if fields.first().and_then(|f| f.parse::<usize>().ok()) != Some(node) {
+                    bail!("line {}: expected match line of node {}", lineno, node);
+                }
+                parse_log_probs(&fields[1..], &mut buf)
+                    .with_context(|| format!("line {}", lineno))?;
+                for (j, &p) in buf.iter().enumerate() {
+                    match_emissions[[node, j]] = p;
+                }
+                let annotations = &fields[1 + k..];
+                if annotations.len() != num_annotations {
+                    bail!(
+                        "line {}: expected {} annotations, found {}",
+                        lineno,
+                        num_annotations,
+                        annotations.len()
+                    );
+                }
+                let mut annotations = annotations.iter().copied();
+                let mut next_annotation = || annotations.next().unwrap();
+                let map_field = next_annotation();
+                let cons_field = if has_cons_field {
+                    next_annotation()
+                } else {
+                    "-"
+                };
+                let rf_field = next_annotation();
+                if has_mm_field {
+                    next_annotation();
+                }
+                let cs_field = next_annotation();
+                if has_map {
+                    map.push(
+                        map_field
+                            .parse::<usize>()
+                            .with_context(|| format!("line {}: invalid MAP", lineno))?,
+                    );
+                }
+                consensus.push(cons_field.as_bytes()[0]);
+                rf.push(rf_field.as_bytes()[0]);
+                cs.push(cs_field.as_bytes()[0]);
+                (lineno, l) = next_line()?;
+            }
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            parse_log_probs(&fields, &mut buf).with_context(|| format!("line {}", lineno))?;
+            for (j, &p) in buf.iter().enumerate() {
+                insert_emissions[[node, j]] = p;
+            }
+            let (t_lineno, t) = next_line()?;
+            let fields = t.split_whitespace().collect::<Vec<_>>();
+            let mut t_buf = [0f64; 7];
+            parse_log_probs(&fields, &mut t_buf).with_context(|| format!("line {}", t_lineno))?;
+            for (j, &p) in t_buf.iter().enumerate() {
+                transitions[[node, j]] = p;
+            }
+            if node < length {
+                (lineno, l) = next_line()?;
+            }
+        }
+        let (lineno, l) = next_line()?;
+        if l.trim() != "//" {
+            bail!("line {}: expected end of HMM (\"//\")", lineno);
+        }
+        Ok(Self {
+            name,
+            alphabet,
+            length,
+            nseq,
+            effn,
+            stats,
+            compo,
+            match_emissions,
+            insert_emissions,
+            transitions,
+            map: has_map.then_some(map),
+            consensus: has_cons.then_some(consensus),
+            rf: has_rf.then_some(rf),
+            cs: has_cs.then_some(cs),
+        })
+    }
+
+    pub fn to_path<P>(&self, path: P) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// writes the HMM in HMMER3/f save file format, readable by HMMER itself
+    pub fn write_to<W>(&self, w: &mut W) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        let write_probs = |w: &mut W, values: &[f64]| -> anyhow::Result<()> {
+            for &lp in values {
+                write_log_prob(w, lp)?;
+            }
+            writeln!(w)?;
+            Ok(())
+        };
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        writeln!(w, "HMMER3/f [witch-ng {}]", env!("CARGO_PKG_VERSION"))?;
+        writeln!(w, "NAME  {}", self.name)?;
+        writeln!(w, "LENG  {}", self.length)?;
+        writeln!(w, "ALPH  {}", self.alphabet.hmmer_name())?;
+        writeln!(w, "RF    {}", yes_no(self.rf.is_some()))?;
+        writeln!(w, "MM    no")?;
+        writeln!(w, "CONS  {}", yes_no(self.consensus.is_some()))?;
+        writeln!(w, "CS    {}", yes_no(self.cs.is_some()))?;
+        writeln!(w, "MAP   {}", yes_no(self.map.is_some()))?;
+        if let Some(nseq) = self.nseq {
+            writeln!(w, "NSEQ  {}", nseq)?;
+        }
+        if let Some(effn) = self.effn {
+            writeln!(w, "EFFN  {:.6}", effn)?;
+        }
+        if let Some(stats) = &self.stats {
+            for (tag, (location, slope)) in [
+                ("MSV", stats.msv),
+                ("VITERBI", stats.viterbi),
+                ("FORWARD", stats.forward),
+            ] {
+                writeln!(w, "STATS LOCAL {:<8} {:8.4} {:8.5}", tag, location, slope)?;
+            }
+        }
+        write!(w, "HMM     ")?;
+        for &c in self.alphabet.symbols() {
+            write!(w, "     {}   ", c as char)?;
+        }
+        writeln!(w)?;
+        write!(w, "        ")?;
+        for t in TRANSITIONS {
+            write!(w, " {:>8}", t)?;
+        }
+        writeln!(w)?;
+        if let Some(compo) = &self.compo {
+            write!(w, "  COMPO ")?;
+            write_probs(w, compo)?;
+        }
+        let map_width = self
+            .map
+            .as_ref()
+            .and_then(|m| m.iter().max())
+            .map_or(1, |c| c.to_string().len());
+        let annotation = |a: &Option<Vec<u8>>, k: usize| a.as_ref().map_or('-', |a| a[k] as char);
+        for node in 0..=self.length {
+            if node > 0 {
+                write!(w, " {:>6} ", node)?;
+                for &lp in self.match_emissions.row(node) {
+                    write_log_prob(w, lp)?;
+                }
+                match &self.map {
+                    Some(map) => write!(w, " {:>width$}", map[node - 1], width = map_width)?,
+                    None => write!(w, " {:>width$}", "-", width = map_width)?,
+                }
+                writeln!(
+                    w,
+                    " {} {} - {}",
+                    annotation(&self.consensus, node - 1),
+                    annotation(&self.rf, node - 1),
+                    annotation(&self.cs, node - 1)
+                )?;
+            }
+            write!(w, "        ")?;
+            write_probs(w, &self.insert_emissions.row(node).to_vec())?;
+            write!(w, "        ")?;
+            write_probs(w, &self.transitions.row(node).to_vec())?;
+        }
+        writeln!(w, "//")?;
+        Ok(())
+    }
+
+    /// the 0-based alignment column of each match state, if `MAP` is present
+    pub fn match_columns(&self) -> Option<Vec<usize>> {
+        self.map
+            .as_ref()
+            .map(|m| m.iter().map(|&c| c - 1).collect())
+    }
+
+    /// check that this HMM was built from the subset described by `meta`
+    pub fn check_against(&self, meta: &HmmMeta) -> anyhow::Result<()> {
+        if self.length != meta.column_poitions.len() {
+            bail!(
+                "HMM {} has {} match states, but its subset has {} non-gap columns",
+                self.name,
+                self.length,
+                meta.column_poitions.len()
+            );
+        }
+        if let Some(nseq) = self.nseq {
+            if nseq != meta.num_seqs() {
+                bail!(
+                    "HMM {} was built on {} sequences, but its subset has {}",
+                    self.name,
+                    nseq,
+                    meta.num_seqs()
+                );
+            }
+        }
+        if let Some(columns) = self.match_columns() {
+            if columns != meta.column_poitions {
+                bail!(
+                    "match states of HMM {} do not map to the columns of its subset",
+                    self.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// in the layout of hmmbuild 3.3.2, with E-value parameters and a composition
+    const HMM_3F: &str = "\
+HMMER3/f [3.3.2 | Nov 2020]
+NAME  tiny
+LENG  3
+MAXL  12
+ALPH  DNA
+RF    no
+MM    no
+CONS  yes
+CS    no
+MAP   yes
+DATE  Sat Oct 17 12:00:00 2026
+NSEQ  4
+EFFN  2.750000
+CKSUM 1844674
+STATS LOCAL MSV      -5.1734  0.71911
+STATS LOCAL VITERBI  -5.6021  0.71911
+STATS LOCAL FORWARD  -3.3157  0.71911
+HMM          A        C        G        T   
+            m->m     m->i     m->d     i->m     i->i     d->m     d->d
+  COMPO   1.22152  1.51903  1.17612  1.68417
+          1.38629  1.38629  1.38629  1.38629
+          0.10536  2.99573  2.99573  0.51083  0.91629  0.00000        *
+      1   0.35667  2.30259  2.30259  2.30259      1 a - - -
+          1.38629  1.38629  1.38629  1.38629
+          0.16252  2.99573  2.30259  0.51083  0.91629  0.35667  1.20397
+      2   2.99573  2.99573  0.16252  2.99573      3 g - - -
+          1.38629  1.38629  1.38629  1.38629
+          0.22314  2.30259  2.30259  0.59784  0.79851  0.28768  1.38629
+      3   1.38629  1.38629  1.60944  1.20397      4 t - - -
+          1.38629  1.38629  1.38629  1.38629
+          0.10536  2.30259        *  0.51083  0.91629  0.00000        *
+//
+";
+
+    fn assert_same(a: &ProfileHmm, b: &ProfileHmm) {
+        assert_eq!(a.name, b.name);
+        assert_eq!(a.alphabet, b.alphabet);
+        assert_eq!(a.length, b.length);
+        assert_eq!(a.nseq, b.nseq);
+        assert_eq!(a.effn, b.effn);
+        assert_eq!(a.stats, b.stats);
+        assert_eq!(a.compo, b.compo);
+        assert_eq!(a.match_emissions, b.match_emissions);
+        assert_eq!(a.insert_emissions, b.insert_emissions);
+        assert_eq!(a.transitions, b.transitions);
+        assert_eq!(a.map, b.map);
+        assert_eq!(a.consensus, b.consensus);
+        assert_eq!(a.rf, b.rf);
+        assert_eq!(a.cs, b.cs);
+    }
+
+    #[test]
+    fn round_trip_3f() {
+        let hmm = ProfileHmm::from_reader(HMM_3F.as_bytes()).unwrap();
+        assert_eq!((hmm.name.as_str(), hmm.length), ("tiny", 3));
+        assert_eq!((hmm.nseq, hmm.effn), (Some(4), Some(2.75)));
+        assert_eq!(hmm.stats.unwrap().viterbi, (-5.6021, 0.71911));
+        assert_eq!(hmm.map, Some(vec![1, 3, 4]));
+        assert_eq!(hmm.consensus, Some(b"agt".to_vec()));
+        assert_eq!((hmm.rf.as_ref(), hmm.cs.as_ref()), (None, None));
+        assert_eq!(hmm.match_emissions[[1, 0]], -0.35667);
+        assert_eq!(hmm.transitions[[3, T_MD]], f64::NEG_INFINITY);
+        let mut written = vec![];
+        hmm.write_to(&mut written).unwrap();
+        let reread = ProfileHmm::from_reader(written.as_slice()).unwrap();
+        assert_same(&hmm, &reread);
+        let mut rewritten = vec![];
+        reread.write_to(&mut rewritten).unwrap();
+        assert_eq!(written, rewritten);
+    }
+
+    /// the fixture in an older format, with the annotations of its match lines replaced
+    fn older(version: &str, annotations: [&str; 3]) -> String {
+        let mut text = HMM_3F.replacen("HMMER3/f", version, 1);
+        for (old, new) in ["1 a - - -", "3 g - - -", "4 t - - -"]
+            .iter()
+            .zip(annotations)
+        {
+            text = text.replacen(old, new, 1);
+        }
+        text
+    }
+
+    #[test]
+    fn older_formats() {
+        let hmm = ProfileHmm::from_reader(HMM_3F.as_bytes()).unwrap();
+        // 3/e has no MM annotation
+        let e = older("HMMER3/e", ["1 a - -", "3 g - -", "4 t - -"]);
+        assert_same(&hmm, &ProfileHmm::from_reader(e.as_bytes()).unwrap());
+        // 3/b (HMMER 3.0) has no CONS annotation either
+        let b = older("HMMER3/b", ["1 - -", "3 - -", "4 - -"]).replacen("CONS  yes\n", "", 1);
+        let b = ProfileHmm::from_reader(b.as_bytes()).unwrap();
+        assert_eq!(b.consensus, None);
+        assert_eq!(b.map, hmm.map);
+        assert_eq!(b.match_emissions, hmm.match_emissions);
+        assert_eq!(b.transitions, hmm.transitions);
+        // the annotations must match the format
+        let err = ProfileHmm::from_reader(HMM_3F.replacen("HMMER3/f", "HMMER3/e", 1).as_bytes())
+            .unwrap_err();
+        assert!(err.to_string().contains("expected 4 annotations, found 5"));
+        assert!(
+            ProfileHmm::from_reader(HMM_3F.replacen("HMMER3/f", "HMMER3/g", 1).as_bytes()).is_err()
+        );
+    }
+}
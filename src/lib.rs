@@ -0,0 +1,49 @@
+//! # WITCH-NG
+//!
+//! WITCH-NG (code-name `crucible`) aims to be an efficient implementation of the WITCH algorithm
+//! for aligning fragments to an existing alignment (called a "reference"
+//! or "backbone" alignment).
+//!
+//! Besides the `witch-ng` binary, the pipeline can be driven as a library through [`WitchConfig`].
+//! The contexts of its stages ([`CrucibleCtxt`] → [`ScoringCtxt`] → [`AdderContext`]) and their
+//! [`ExternalContext`] are exported too; the other modules are internal.
+mod adder;
+mod combined;
+mod compact_printer;
+mod compression;
+pub mod config;
+mod construct;
+mod denovo;
+pub mod error;
+mod external;
+mod formats;
+mod guide_tree;
+mod hmm;
+mod matching;
+mod melt;
+mod prior;
+mod profile;
+mod progress_reporter;
+mod score_calc;
+mod streaming;
+pub mod structures;
+mod tree;
+mod validate;
+
+pub use adder::AdderContext;
+pub use combined::{WitchConfig, WitchConfigBuilder};
+pub use config::ExternalContext;
+pub use hmm::Alphabet;
+pub use score_calc::ScoringCtxt;
+pub use structures::{AdderPayload, CrucibleCtxt};
+
+/// the file-based stages run by the `witch-ng` binary; not part of the library API
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::{
+        combined::{align_analysis, combined_analysis, denovo_analysis, score_analysis},
+        compression::{read_sequences, write_fastq, OutputFile},
+        melt::{oneshot_melt, summarize_decomposition},
+        validate::{fix_query, fix_query_with_qualities, validate},
+    };
+}
@@ -1,142 +1,747 @@
-//! # WITCH-NG
-//!
-//! WITCH-NG (code-name `crucible`) aims to be an efficient implementation of the WITCH algorithm
-//! for aligning fragments to an existing alignment (called a "reference"
-//! or "backbone" alignment).
-mod adder;
-mod combined;
-mod compact_printer;
-mod config;
-mod external;
-mod matching;
-mod melt;
-mod progress_reporter;
-mod score_calc;
-mod structures;
-
-use anyhow::Ok;
-use clap::{Parser, Subcommand};
-use std::{path::PathBuf, time::Instant};
+//! Command line interface of WITCH-NG, see the library crate for the pipeline itself.
+use anyhow::{bail, Ok};
+use clap::{builder::RangedU64ValueParser, Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use ordered_float::NotNan;
+use seq_io::fasta::Record;
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 use tracing::{debug, info, warn};
 
-use crate::config::ExternalContext;
+use witch_ng::{
+    cli::{
+        align_analysis, combined_analysis, denovo_analysis, fix_query, fix_query_with_qualities,
+        oneshot_melt, read_sequences, score_analysis, summarize_decomposition, validate,
+        write_fastq, OutputFile,
+    },
+    config::{
+        AlignBackend, BackboneAligner, BackboneSelection, BuildBackend, DecompositionStrategy,
+        DistanceModel, GuideTreeMethod, GuideTreeOptions, HmmerTool, HmmerTools, OutputFormat,
+        ResourceLimits, ScoringBackend,
+    },
+    error::RetryPolicy,
+    Alphabet, ExternalContext,
+};
 
 #[derive(Parser, Debug, Hash, PartialEq)]
 #[clap(author, version, about)]
 struct Args {
     #[clap(subcommand)]
     cmd: SubCommand,
+    #[clap(flatten)]
+    hmmer: HmmerArgs,
+    /// Remove gaps from and upper-case the queries instead of stopping on them
+    #[clap(long, global = true)]
+    fix_inputs: bool,
+}
+
+/// Locations of the HMMER programs and extra arguments to them
+#[derive(ClapArgs, Debug, PartialEq, Hash)]
+struct HmmerArgs {
+    /// Path to hmmbuild
+    #[clap(
+        long,
+        global = true,
+        env = "WITCH_NG_HMMBUILD",
+        default_value = "hmmbuild"
+    )]
+    hmmbuild: PathBuf,
+    /// Path to hmmsearch
+    #[clap(
+        long,
+        global = true,
+        env = "WITCH_NG_HMMSEARCH",
+        default_value = "hmmsearch"
+    )]
+    hmmsearch: PathBuf,
+    /// Path to hmmalign
+    #[clap(
+        long,
+        global = true,
+        env = "WITCH_NG_HMMALIGN",
+        default_value = "hmmalign"
+    )]
+    hmmalign: PathBuf,
+    /// Extra arguments to hmmbuild, separated by spaces (e.g. "--amino --ere 0.7")
+    #[clap(
+        long,
+        global = true,
+        env = "WITCH_NG_HMMBUILD_ARGS",
+        allow_hyphen_values = true
+    )]
+    hmmbuild_args: Option<String>,
+    /// Extra arguments to hmmsearch, separated by spaces
+    #[clap(
+        long,
+        global = true,
+        env = "WITCH_NG_HMMSEARCH_ARGS",
+        allow_hyphen_values = true
+    )]
+    hmmsearch_args: Option<String>,
+    /// Extra arguments to hmmalign, separated by spaces
+    #[clap(
+        long,
+        global = true,
+        env = "WITCH_NG_HMMALIGN_ARGS",
+        allow_hyphen_values = true
+    )]
+    hmmalign_args: Option<String>,
+    /// How often to retry a failed run of a HMMER program before giving up
+    #[clap(long, global = true, default_value_t = 2)]
+    retries: usize,
+    /// Milliseconds to wait before the first retry, doubled for each further retry
+    #[clap(long, global = true, default_value_t = 1000)]
+    retry_delay_ms: u64,
+    /// Kill a run of a HMMER program after this many seconds
+    #[clap(long, global = true)]
+    timeout: Option<u64>,
+    /// Retry the runs killed by --timeout like other failures instead of giving up
+    #[clap(long, global = true)]
+    retry_timeouts: bool,
+    /// Limit the address space of each run of a HMMER program to this many megabytes (unix only)
+    #[clap(long, global = true)]
+    memory_limit: Option<u64>,
+}
+
+impl HmmerArgs {
+    fn tools(&self) -> HmmerTools {
+        let tool = |name, program: &PathBuf, args: &Option<String>| HmmerTool {
+            name,
+            program: program.clone(),
+            extra_args: args
+                .iter()
+                .flat_map(|a| a.split_whitespace().map(String::from))
+                .collect(),
+        };
+        HmmerTools {
+            hmmbuild: tool("hmmbuild", &self.hmmbuild, &self.hmmbuild_args),
+            hmmsearch: tool("hmmsearch", &self.hmmsearch, &self.hmmsearch_args),
+            hmmalign: tool("hmmalign", &self.hmmalign, &self.hmmalign_args),
+        }
+    }
+
+    fn retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.retries,
+            delay: Duration::from_millis(self.retry_delay_ms),
+            retry_timeouts: self.retry_timeouts,
+        }
+    }
+
+    fn limits(&self) -> anyhow::Result<ResourceLimits> {
+        if cfg!(not(unix)) && self.memory_limit.is_some() {
+            bail!("--memory-limit is only supported on unix");
+        }
+        Ok(ResourceLimits {
+            timeout: self.timeout.map(Duration::from_secs),
+            memory: self.memory_limit.map(|mb| mb << 20),
+        })
+    }
+}
+
+/// How the backbone tree is decomposed
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum DecompositionKind {
+    /// Split at the most balanced internal node until subsets have at most --hmm-size-lb taxa
+    Balanced,
+    /// Split at the centroid edge as in UPP and WITCH, bounded by --max-subset-size and --min-subset-size
+    CentroidEdge,
+    /// Split at the middle of the longest path until the tree diameter of each subset is at most --max-diameter
+    Diameter,
+}
+
+/// Options of the decomposition of the backbone tree into subsets
+#[derive(ClapArgs, Debug, PartialEq, Hash)]
+struct DecompositionArgs {
+    /// How the backbone tree is decomposed into the subsets of the eHMM
+    #[clap(long, value_enum, default_value_t = DecompositionKind::Balanced)]
+    decomposition: DecompositionKind,
+    /// Subsets with more taxa are split further (centroid-edge and diameter)
+    #[clap(long, default_value_t = 10, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    max_subset_size: usize,
+    /// Splits leaving fewer taxa on either side are not made (centroid-edge and diameter)
+    #[clap(long, default_value_t = 2, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    min_subset_size: usize,
+    /// Subsets whose largest patristic distance between two taxa exceeds this are split further (diameter only)
+    #[clap(long, required_if_eq("decomposition", "diameter"))]
+    max_diameter: Option<NotNan<f64>>,
+    /// Use backbone sequences missing from the tree in the root HMM only, instead of stopping with an error
+    #[clap(long)]
+    attach_missing_taxa: bool,
+    /// How the guide tree is inferred from the backbone when no tree is given
+    #[clap(long, value_enum, default_value_t = GuideTreeMethod::NeighborJoining)]
+    guide_tree: GuideTreeMethod,
+    /// Distances between backbone sequences the guide tree is inferred from
+    #[clap(long, value_enum, default_value_t = DistanceModel::PDistance)]
+    guide_tree_distance: DistanceModel,
+}
+
+impl DecompositionArgs {
+    fn guide_tree(&self) -> GuideTreeOptions {
+        GuideTreeOptions {
+            method: self.guide_tree,
+            distance: self.guide_tree_distance,
+        }
+    }
+
+    fn strategy(&self) -> DecompositionStrategy {
+        match self.decomposition {
+            DecompositionKind::Balanced => DecompositionStrategy::Balanced,
+            DecompositionKind::CentroidEdge => DecompositionStrategy::CentroidEdge {
+                max_size: self.max_subset_size,
+                min_size: self.min_subset_size,
+            },
+            DecompositionKind::Diameter => DecompositionStrategy::Diameter {
+                max_diameter: self
+                    .max_diameter
+                    .expect("--max-diameter is required by clap"),
+                max_size: self.max_subset_size,
+                min_size: self.min_subset_size,
+            },
+        }
+    }
+}
+
+/// Options locating (or building) the eHMM
+#[derive(ClapArgs, Debug, PartialEq, Hash)]
+struct EhmmArgs {
+    /// Either a path to a full-length MSA (in FASTA, optionally with a "tree") or a directory of eHMMs
+    #[clap(short, long)]
+    backbone: PathBuf,
+    /// Output path for the intermediate eHMMs (only used if backbone is MSA, not eHMMs, in which case defaults to backbone MSA path with "ehmm" extension)
+    #[clap(short, long)]
+    ehmm_path: Option<PathBuf>,
+    /// Path to backbone tree for the full-length MSA (when specified in "backbone"); inferred from the MSA if not given
+    #[clap(short, long)]
+    tree: Option<PathBuf>,
+    /// The HMM decomposition size lower bound; how many sequences must each HMM contain? Defaults to 10
+    #[clap(long)]
+    hmm_size_lb: Option<usize>,
+    /// How to build the HMMs of a new eHMM; "native" builds them in-process instead of running hmmbuild
+    #[clap(long, value_enum, default_value_t = BuildBackend::Hmmbuild)]
+    builder: BuildBackend,
+    /// Alphabet of the sequences; detected from the backbone by default
+    #[clap(long, value_enum)]
+    alphabet: Option<Alphabet>,
+    #[clap(flatten)]
+    decomposition: DecompositionArgs,
+}
+
+/// Options for the hmmsearch stage
+#[derive(ClapArgs, Debug, PartialEq, Hash)]
+struct SearchArgs {
+    /// Specify to use a strategy for IO bound situations; make each worker use two threads, with one thread for IO
+    #[clap(long)]
+    io_bound: bool,
+    /// Enable checkpointing in the hmmsearch stage; the checkpoint file will be a suffix of the output file
+    #[clap(long)]
+    checkpoint: bool,
+    /// Log progress every ten seconds for the search phase
+    #[clap(long)]
+    progress: bool,
+    /// How to score queries against HMMs; "forward" and "viterbi" score in-process instead of running hmmsearch
+    #[clap(long, value_enum, default_value_t = ScoringBackend::Hmmsearch)]
+    scorer: ScoringBackend,
+}
+
+/// Options for the alignment stage and the output alignment
+#[derive(ClapArgs, Debug, PartialEq, Hash)]
+struct OutputArgs {
+    /// How to align queries to HMMs; "viterbi" aligns in-process instead of running hmmalign
+    #[clap(long, value_enum, default_value_t = AlignBackend::Hmmalign)]
+    aligner: AlignBackend,
+    /// Trim singleton columns (columns with only lower-case letters) in the output
+    #[clap(long)]
+    trim: bool,
+    /// Forgo outputting the backbone; columns only used by the backbone are left out
+    #[clap(long)]
+    only_queries: bool,
+    /// Format of the output alignment
+    #[clap(long, value_enum, default_value_t = OutputFormat::Fasta)]
+    output_format: OutputFormat,
+    /// Scale how much each residue of FASTQ queries counts in the alignment by the probability that its base call is correct, from its Phred quality
+    #[clap(long)]
+    quality_weights: bool,
 }
 
 #[derive(Subcommand, Debug, PartialEq, Hash)]
 enum SubCommand {
     /// Add query sequences to a reference alignment
     Add {
-        /// Path to query sequences (fragments) in FASTA format
+        /// Path to query sequences (fragments) in FASTA or FASTQ format
+        #[clap(short, long)]
+        input: PathBuf,
+        #[clap(flatten)]
+        ehmm: EhmmArgs,
+        /// Output path of the merged MSA
+        #[clap(short, long)]
+        output: PathBuf,
+        #[clap(flatten)]
+        output_opts: OutputArgs,
+        #[clap(flatten)]
+        search: SearchArgs,
+        /// Score and align the queries this many at a time, in two passes over them, to bound memory use on very large query sets
+        #[clap(long)]
+        batch_size: Option<usize>,
+        /// Set level of parallelism; defaults to number of logical cores
+        #[clap(long)]
+        threads: Option<usize>,
+    },
+    /// Build an eHMM directory from a reference alignment, reusable as the "backbone" of "add"
+    Build {
+        /// Path to a full-length MSA in FASTA
+        #[clap(short, long)]
+        backbone: PathBuf,
+        /// Path to backbone tree for the full-length MSA; inferred from the MSA if not given
+        #[clap(short, long)]
+        tree: Option<PathBuf>,
+        /// Output path for the eHMM directory (defaults to backbone MSA path with "ehmm" extension)
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// The HMM decomposition size lower bound; how many sequences must each HMM contain? Defaults to 10
+        #[clap(long)]
+        hmm_size_lb: Option<usize>,
+        /// How to build the HMMs; "native" builds them in-process instead of running hmmbuild
+        #[clap(long, value_enum, default_value_t = BuildBackend::Hmmbuild)]
+        builder: BuildBackend,
+        /// Alphabet of the backbone; detected by default
+        #[clap(long, value_enum)]
+        alphabet: Option<Alphabet>,
+        #[clap(flatten)]
+        decomposition: DecompositionArgs,
+        /// Set level of parallelism; defaults to number of logical cores
+        #[clap(long)]
+        threads: Option<usize>,
+    },
+    /// Only run the scoring (hmmsearch) stage of "add", saving the top hits of each query
+    Score {
+        /// Path to query sequences (fragments) in FASTA or FASTQ format
+        #[clap(short, long)]
+        input: PathBuf,
+        #[clap(flatten)]
+        ehmm: EhmmArgs,
+        /// Output path of the scores (JSON)
+        #[clap(short, long)]
+        output: PathBuf,
+        /// Also write the raw hits behind the top HMMs of each query (scores, E-values and domains) to the scores
+        #[clap(long)]
+        record_hits: bool,
+        #[clap(flatten)]
+        search: SearchArgs,
+        /// Set level of parallelism; defaults to number of logical cores
+        #[clap(long)]
+        threads: Option<usize>,
+    },
+    /// Only run the alignment stage of "add", using the scores from "score"
+    Align {
+        /// Path to query sequences (fragments) in FASTA or FASTQ format, same as the ones given to "score"
         #[clap(short, long)]
         input: PathBuf,
-        /// Either a path to a full-length MSA (in FASTA, also requiring a "tree") or a directory of eHMMs
+        /// Either a directory of eHMMs or a path to a full-length MSA whose eHMMs were already built
         #[clap(short, long)]
         backbone: PathBuf,
-        /// Output path for the intermediate eHMMs (only used if backbone is MSA, not eHMMs, in which case defaults to backbone MSA path with "ehmm" extension)
+        /// Path to the eHMMs (only used if backbone is MSA, in which case defaults to backbone MSA path with "ehmm" extension)
         #[clap(short, long)]
         ehmm_path: Option<PathBuf>,
-        /// Path to backbone tree for the full-length MSA (when specified in "backbone")
+        /// Path to the scores produced by "score"
         #[clap(short, long)]
-        tree: Option<PathBuf>,
+        scores: PathBuf,
         /// Output path of the merged MSA
         #[clap(short, long)]
         output: PathBuf,
-        /// Trim singleton columns in the output. Not implemented.
+        #[clap(flatten)]
+        output_opts: OutputArgs,
+        /// Alphabet of the sequences; taken from the eHMM by default
+        #[clap(long, value_enum)]
+        alphabet: Option<Alphabet>,
+        /// Set level of parallelism; defaults to number of logical cores
         #[clap(long)]
-        trim: bool,
-        /// Forgo outputting the backbone; must go with "--trim"
+        threads: Option<usize>,
+    },
+    /// Check the queries and the backbone for problems, without aligning anything
+    Validate {
+        /// Path to query sequences in FASTA or FASTQ format
+        #[clap(short, long)]
+        input: Option<PathBuf>,
+        /// Either a path to a full-length MSA or a directory of eHMMs
+        #[clap(short, long)]
+        backbone: Option<PathBuf>,
+        /// Write the queries to this path with their fixable problems fixed, as by --fix-inputs
+        #[clap(short, long, requires = "input")]
+        output: Option<PathBuf>,
+    },
+    /// Align unaligned sequences from scratch, choosing a backbone among them and adding the others to it
+    Denovo {
+        /// Path to the unaligned sequences in FASTA format
+        #[clap(short, long)]
+        input: PathBuf,
+        /// Output path of the MSA
+        #[clap(short, long)]
+        output: PathBuf,
+        /// Directory for the backbone, the queries, the guide tree and the eHMM (defaults to output path with "denovo" extension)
+        #[clap(short, long)]
+        workdir: Option<PathBuf>,
+        /// The most sequences in the backbone; more full-length sequences are randomly sampled
+        #[clap(long, default_value_t = 1000, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        backbone_size: usize,
+        /// Sequences within this fraction of the median length are full-length, and can be in the backbone
+        #[clap(long, default_value = "0.25")]
+        length_tolerance: NotNan<f64>,
+        /// Seed of the random sample of the backbone
+        #[clap(long, default_value_t = 0)]
+        seed: u64,
+        /// Program aligning the backbone, reading unaligned FASTA on stdin and writing aligned FASTA to stdout (e.g. "mafft"); a built-in progressive aligner by default
         #[clap(long)]
-        only_queries: bool,
+        backbone_aligner: Option<PathBuf>,
+        /// Arguments to the backbone aligner, separated by spaces (e.g. "--auto -" for mafft)
+        #[clap(long, allow_hyphen_values = true, requires = "backbone-aligner")]
+        backbone_aligner_args: Option<String>,
         /// The HMM decomposition size lower bound; how many sequences must each HMM contain? Defaults to 10
         #[clap(long)]
         hmm_size_lb: Option<usize>,
-        /// Specify to use a strategy for IO bound situations; make each worker use two threads, with one thread for IO
-        #[clap(long)]
-        io_bound: bool,
-        /// Enable checkpointing in the hmmsearch stage; the checkpoint file will be a suffix of the output file
-        #[clap(long)]
-        checkpoint: bool,
-        /// Log progress every ten seconds for the search phase
+        /// How to build the HMMs; "native" builds them in-process instead of running hmmbuild
+        #[clap(long, value_enum, default_value_t = BuildBackend::Hmmbuild)]
+        builder: BuildBackend,
+        /// Alphabet of the sequences; detected by default
+        #[clap(long, value_enum)]
+        alphabet: Option<Alphabet>,
+        #[clap(flatten)]
+        decomposition: DecompositionArgs,
+        #[clap(flatten)]
+        output_opts: OutputArgs,
+        #[clap(flatten)]
+        search: SearchArgs,
+        /// Score and align the queries this many at a time, in two passes over them, to bound memory use on very large query sets
         #[clap(long)]
-        progress: bool,
+        batch_size: Option<usize>,
         /// Set level of parallelism; defaults to number of logical cores
         #[clap(long)]
         threads: Option<usize>,
     },
 }
 
+fn init_workers(threads: Option<usize>) -> anyhow::Result<usize> {
+    let nworkers = if let Some(t) = threads {
+        t
+    } else {
+        num_cpus::get()
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(nworkers)
+        .build_global()?;
+    info!("using {:?} workers", nworkers);
+    Ok(nworkers)
+}
+
+/// the options of building the eHMM; other fields are left to their defaults
+fn build_context(
+    hmm_size_lb: Option<usize>,
+    builder: BuildBackend,
+    alphabet: Option<Alphabet>,
+    decomposition: &DecompositionArgs,
+) -> ExternalContext {
+    if hmm_size_lb.is_some() && decomposition.decomposition != DecompositionKind::Balanced {
+        warn!("--hmm-size-lb only applies to the balanced decomposition and is ignored");
+    }
+    ExternalContext {
+        hmm_size_lb: hmm_size_lb.unwrap_or(10),
+        decomposition: decomposition.strategy(),
+        attach_missing_taxa: decomposition.attach_missing_taxa,
+        guide_tree: decomposition.guide_tree(),
+        builder,
+        alphabet,
+        ..Default::default()
+    }
+}
+
+/// the options of the scoring stage on top of `base`
+fn search_context(
+    search: &SearchArgs,
+    base: ExternalContext,
+    checkpoint_path: &PathBuf,
+    nworkers: usize,
+) -> ExternalContext {
+    let external_context = ExternalContext {
+        show_progress: search.progress,
+        io_bound: search.io_bound,
+        db: search.checkpoint.then(|| {
+            warn!("reloading a checkpoint is only guaranteed to work on the same input files and architecture, with same hmm-size-lb");
+            sled::Config::default()
+                .path(checkpoint_path)
+                .flush_every_ms(Some(3000))
+                .use_compression(true)
+                .compression_factor(3)
+                .open()
+                .unwrap_or_else(|_| panic!("failed to open checkpoint file at {:?}", checkpoint_path))
+        }),
+        num_workers: nworkers,
+        num_threads_per_worker: if search.io_bound { 2 } else { 1 },
+        scorer: search.scorer,
+        ..base
+    };
+    if let Some(db) = &external_context.db {
+        if db.was_recovered() {
+            info!("recovered from checkpoint file at {:?}", checkpoint_path);
+            debug!("checkpoint file takes {:?} bytes", db.size_on_disk().ok());
+        }
+    }
+    external_context
+}
+
 fn main() -> anyhow::Result<()> {
     let now = Instant::now();
     let args = Args::parse();
     tracing_subscriber::fmt::init();
     debug!("command: {:?}", &args.cmd);
+    let hmmer = args.hmmer.tools();
+    let retry = args.hmmer.retry();
+    let limits = args.hmmer.limits()?;
     match args.cmd {
         SubCommand::Add {
             input,
+            ehmm,
+            output,
+            output_opts,
+            search,
+            batch_size,
+            threads,
+        } => {
+            let checkpoint_path = output.with_extension("checkpoint");
+            let nworkers = init_workers(threads)?;
+            let external_context = ExternalContext {
+                trim: output_opts.trim,
+                only_queries: output_opts.only_queries,
+                output_format: output_opts.output_format,
+                quality_weights: output_opts.quality_weights,
+                aligner: output_opts.aligner,
+                fix_inputs: args.fix_inputs,
+                batch_size,
+                hmmer,
+                retry,
+                limits,
+                ..search_context(
+                    &search,
+                    build_context(
+                        ehmm.hmm_size_lb,
+                        ehmm.builder,
+                        ehmm.alphabet,
+                        &ehmm.decomposition,
+                    ),
+                    &checkpoint_path,
+                    nworkers,
+                )
+            };
+            external_context.check_hmmer(!ehmm.backbone.is_dir(), true, true)?;
+            combined_analysis(
+                input,
+                ehmm.backbone,
+                output,
+                ehmm.ehmm_path,
+                ehmm.tree,
+                &external_context,
+            )?;
+        }
+        SubCommand::Build {
             backbone,
-            ehmm_path,
             tree,
             output,
-            trim,
-            only_queries,
-            threads,
             hmm_size_lb,
-            io_bound,
-            checkpoint,
-            progress,
+            builder,
+            alphabet,
+            decomposition,
+            threads,
+        } => {
+            let external_context = ExternalContext {
+                num_workers: init_workers(threads)?,
+                hmmer,
+                retry,
+                limits,
+                ..build_context(hmm_size_lb, builder, alphabet, &decomposition)
+            };
+            validate(None, Some(&backbone))?.check(false)?;
+            external_context.check_hmmer(true, false, false)?;
+            let ehmm_path = output.unwrap_or_else(|| backbone.with_extension("ehmm"));
+            let ctxt = oneshot_melt(&backbone, tree.as_deref(), &ehmm_path, &external_context)?;
+            summarize_decomposition(&ctxt);
+            info!("eHMM written to {:?}", ehmm_path);
+        }
+        SubCommand::Score {
+            input,
+            ehmm,
+            output,
+            record_hits,
+            search,
+            threads,
         } => {
             let checkpoint_path = output.with_extension("checkpoint");
-
-            let nworkers = if let Some(t) = threads {
-                t
-            } else { 
-                num_cpus::get()
+            let nworkers = init_workers(threads)?;
+            let external_context = ExternalContext {
+                fix_inputs: args.fix_inputs,
+                record_hits,
+                hmmer,
+                retry,
+                limits,
+                ..search_context(
+                    &search,
+                    build_context(
+                        ehmm.hmm_size_lb,
+                        ehmm.builder,
+                        ehmm.alphabet,
+                        &ehmm.decomposition,
+                    ),
+                    &checkpoint_path,
+                    nworkers,
+                )
             };
-
-            let nthreads_per_worker = if io_bound { 2 } else { 1 };
+            external_context.check_hmmer(!ehmm.backbone.is_dir(), true, false)?;
+            score_analysis(
+                input,
+                ehmm.backbone,
+                output,
+                ehmm.ehmm_path,
+                ehmm.tree,
+                &external_context,
+            )?;
+        }
+        SubCommand::Align {
+            input,
+            backbone,
+            ehmm_path,
+            scores,
+            output,
+            output_opts,
+            alphabet,
+            threads,
+        } => {
             let external_context = ExternalContext {
-                hmm_size_lb: hmm_size_lb.unwrap_or(10),
-                show_progress: progress,
-                io_bound,
-                trim,
-                only_queries,
-                db: checkpoint.then(|| {
-                    warn!("reloading a checkpoint is only guaranteed to work on the same input files and architecture, with same hmm-size-lb");
-                    sled::Config::default()
-                        .path(&checkpoint_path)
-                        .flush_every_ms(Some(3000))
-                        .use_compression(true)
-                        .compression_factor(3)
-                        .open().unwrap_or_else(|_| panic!("failed to open checkpoint file at {:?}",
-                            checkpoint_path.clone()))
-                }),
-                num_workers: nworkers,
-                num_threads_per_worker: nthreads_per_worker,
+                trim: output_opts.trim,
+                only_queries: output_opts.only_queries,
+                output_format: output_opts.output_format,
+                quality_weights: output_opts.quality_weights,
+                aligner: output_opts.aligner,
+                fix_inputs: args.fix_inputs,
+                alphabet,
+                num_workers: init_workers(threads)?,
+                hmmer,
+                retry,
+                limits,
+                ..Default::default()
             };
-
-            rayon::ThreadPoolBuilder::new()
-                .num_threads(nworkers)
-                .build_global()?;
-            info!("using {:?} workers", nworkers);
-            if checkpoint {
-                let num_entries = &external_context.db.as_ref().unwrap().len();
-                if external_context.db.as_ref().unwrap().was_recovered() {
-                    info!("recovered from checkpoint file at {:?}", &checkpoint_path);
-                    debug!("checkpoint file contains {:?} entries", num_entries);
-                }
-            }
-            combined::combined_analysis(
+            external_context.check_hmmer(false, false, true)?;
+            align_analysis(
                 input,
                 backbone,
+                scores,
                 output,
                 ehmm_path,
-                tree,
+                &external_context,
+            )?;
+        }
+        SubCommand::Validate {
+            input,
+            backbone,
+            output,
+        } => {
+            if input.is_none() && backbone.is_none() {
+                bail!(
+                    "nothing to validate; give queries (--input), a backbone (--backbone) or both"
+                );
+            }
+            let report = validate(input.as_deref(), backbone.as_deref())?;
+            for problem in &report.problems {
+                println!("{}", problem);
+            }
+            for omitted in report.omitted() {
+                println!("{}", omitted);
+            }
+            if !report.is_ok(args.fix_inputs || output.is_some()) {
+                bail!("found {} problem(s) in the inputs", report.num_problems());
+            }
+            if let (Some(input), Some(output)) = (input, output) {
+                let mut writer = OutputFile::create(&output)?;
+                let queries = read_sequences(&input)?;
+                match queries.qualities {
+                    Some(qualities) => {
+                        for (mut r, mut q) in queries.records.into_iter().zip(qualities) {
+                            fix_query_with_qualities(&mut r, &mut q);
+                            write_fastq(&mut writer, &r, &q)?;
+                        }
+                    }
+                    None => {
+                        for mut r in queries.records {
+                            fix_query(&mut r);
+                            r.write_wrap(&mut writer, 60)?;
+                        }
+                    }
+                }
+                writer.finish()?;
+                info!("fixed queries written to {:?}", output);
+            }
+            info!(num_problems = report.num_problems(), "inputs validated");
+        }
+        SubCommand::Denovo {
+            input,
+            output,
+            workdir,
+            backbone_size,
+            length_tolerance,
+            seed,
+            backbone_aligner,
+            backbone_aligner_args,
+            hmm_size_lb,
+            builder,
+            alphabet,
+            decomposition,
+            output_opts,
+            search,
+            batch_size,
+            threads,
+        } => {
+            let checkpoint_path = output.with_extension("checkpoint");
+            let nworkers = init_workers(threads)?;
+            let external_context = ExternalContext {
+                trim: output_opts.trim,
+                only_queries: output_opts.only_queries,
+                output_format: output_opts.output_format,
+                quality_weights: output_opts.quality_weights,
+                aligner: output_opts.aligner,
+                fix_inputs: args.fix_inputs,
+                batch_size,
+                hmmer,
+                retry,
+                limits,
+                ..search_context(
+                    &search,
+                    build_context(hmm_size_lb, builder, alphabet, &decomposition),
+                    &checkpoint_path,
+                    nworkers,
+                )
+            };
+            external_context.check_hmmer(true, true, true)?;
+            let selection = BackboneSelection {
+                max_size: backbone_size,
+                length_tolerance: length_tolerance.into_inner(),
+                seed,
+            };
+            let aligner = match backbone_aligner {
+                Some(program) => BackboneAligner::External(HmmerTool {
+                    name: "backbone aligner",
+                    program,
+                    extra_args: backbone_aligner_args
+                        .iter()
+                        .flat_map(|a| a.split_whitespace().map(String::from))
+                        .collect(),
+                }),
+                None => BackboneAligner::Builtin,
+            };
+            let workdir = workdir.unwrap_or_else(|| output.with_extension("denovo"));
+            denovo_analysis(
+                input,
+                output,
+                workdir,
+                &selection,
+                &aligner,
                 &external_context,
             )?;
         }
@@ -1,24 +1,116 @@
-use crate::{config::ExternalContext, external::hmmbuild, structures::*};
-use ahash::AHashSet;
+use crate::{
+    compression,
+    config::{BuildBackend, DecompositionStrategy, ExternalContext},
+    construct::build_hmm,
+    error::{ErrorKind, Stage},
+    external::hmmbuild,
+    guide_tree::guide_tree,
+    hmm::Alphabet,
+    structures::*,
+    tree::{has_branch_lengths, read_newick, reconcile},
+};
+use ahash::{AHashMap, AHashSet};
+use anyhow::bail;
 use fixedbitset::FixedBitSet;
+use itertools::Itertools;
 
 use ndarray::{Array, ShapeBuilder};
 use ogcat::ogtree::*;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use seq_io::fasta::{Reader, Record};
+use seq_io::fasta::Record;
 use thread_local::ThreadLocal;
 
 use std::{
     cell::RefCell,
     collections::BinaryHeap,
-    fs::{create_dir_all, File},
+    fs::{self, create_dir_all, File},
     io::BufWriter,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
 };
-use tracing::info;
+use tracing::{info, warn};
+
+/// the longest path between two nodes of the subtree of `root` without the subtrees of `cuts`
+/// (its length being the diameter of the subset), as the branches along it: the node below each
+/// branch, with the distances from the start of the path to both ends of the branch. Missing
+/// branch lengths count as zero
+fn longest_path(tree: &Tree, root: usize, cuts: &AHashSet<usize>) -> (f64, Vec<(usize, f64, f64)>) {
+    let length = |i: usize| tree.lengths[i].max(0.0);
+    // node -> distance to its farthest node below, and the child on the way there
+    let mut height: AHashMap<usize, (f64, Option<usize>)> = AHashMap::new();
+    let (mut diameter, mut arms) = (0f64, (None, None));
+    for i in PostorderIterator::from_node_excluding(tree, root, cuts) {
+        let (mut first, mut second) = ((0f64, None), (0f64, None));
+        for c in tree.children(i).filter(|c| !cuts.contains(c)) {
+            let h = height[&c].0 + length(c);
+            if h > first.0 {
+                second = first;
+                first = (h, Some(c));
+            } else if h > second.0 {
+                second = (h, Some(c));
+            }
+        }
+        if first.0 + second.0 > diameter {
+            diameter = first.0 + second.0;
+            arms = (first.1, second.1);
+        }
+        height.insert(i, first);
+    }
+    // the nodes on each arm of the path, from the top of the path downwards
+    let arm = |mut next: Option<usize>| {
+        let mut nodes = vec![];
+        while let Some(n) = next {
+            nodes.push(n);
+            next = height[&n].1;
+        }
+        nodes
+    };
+    let (first_arm, second_arm) = (arm(arms.0), arm(arms.1));
+    let mut branches = vec![];
+    let mut d = 0f64;
+    for n in first_arm.into_iter().rev().chain(second_arm) {
+        branches.push((n, d, d + length(n)));
+        d += length(n);
+    }
+    (diameter, branches)
+}
+
+/// the branch of `path` (see `longest_path`) closest to its midpoint among those `allowed`
+fn midpoint_branch(
+    diameter: f64,
+    path: &[(usize, f64, f64)],
+    allowed: impl Fn(usize) -> bool,
+) -> Option<usize> {
+    let midpoint = diameter / 2.0;
+    path.iter()
+        .filter(|&&(n, _, _)| allowed(n))
+        .map(|&(n, from, to)| {
+            let off = if midpoint < from {
+                from - midpoint
+            } else {
+                (midpoint - to).max(0.0)
+            };
+            (off, n)
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, n)| n)
+}
 
-pub fn hierarchical_decomp(tree: &Tree, max_size: usize) -> TaxaHierarchy {
+/// splits `tree` top-down by `strategy`, keeping every intermediate subset, the first one being all taxa
+pub fn hierarchical_decomp(
+    tree: &Tree,
+    hmm_size_lb: usize,
+    mut strategy: DecompositionStrategy,
+) -> TaxaHierarchy {
+    if let DecompositionStrategy::Diameter {
+        max_size, min_size, ..
+    } = strategy
+    {
+        if !has_branch_lengths(tree) {
+            warn!("the tree has no branch lengths, decomposing by subset sizes instead");
+            strategy = DecompositionStrategy::CentroidEdge { max_size, min_size };
+        }
+    }
     let n = tree.ntaxa;
     let mut reordered_taxa = (0..n).collect::<Vec<_>>();
     let mut taxa_label = FixedBitSet::with_capacity(n); // Taxa ID -> is on the left
@@ -40,32 +132,55 @@ pub fn hierarchical_decomp(tree: &Tree, max_size: usize) -> TaxaHierarchy {
     decomposition_ranges.push((0usize, tree.ntaxa));
     while let Some((size, (lb, ub), root)) = pq.pop() {
         assert_eq!(size, ub - lb);
-        if size <= max_size {
-            break;
-        }
-        let it = PostorderIterator::from_node_excluding(tree, root, &cuts);
-        let mut best_inbalance = u64::MAX;
-        let mut best_cut = 0usize;
-        let mut non_leaf = false;
-        for i in it {
-            if i == root {
-                continue;
-            }
-            if tree.is_leaf(i) {
-            } else {
-                non_leaf = true;
-                let inbalance = (size as u64 - tree_sizes[i]).abs_diff(tree_sizes[i]);
-                if inbalance < best_inbalance {
-                    best_inbalance = inbalance;
-                    best_cut = i;
+        // the longest path of a subset too wide for `Diameter`, whose middle is where to split it
+        let mut wide = None;
+        let needs_split = match strategy {
+            DecompositionStrategy::Balanced => size > hmm_size_lb,
+            DecompositionStrategy::CentroidEdge { max_size, .. } => size > max_size,
+            DecompositionStrategy::Diameter {
+                max_diameter,
+                max_size,
+                ..
+            } => {
+                if size > 1 {
+                    let (diameter, path) = longest_path(tree, root, &cuts);
+                    if diameter > max_diameter.into_inner() {
+                        wide = Some((diameter, path));
+                    }
                 }
+                wide.is_some() || size > max_size
             }
-        } // finding the best cut
-        if non_leaf {
-            assert_ne!(best_inbalance, u64::MAX, "No cut found");
-        } else {
+        };
+        if !needs_split {
             continue;
         }
+        let allowed = |i: usize| {
+            let outside = size as u64 - tree_sizes[i];
+            match strategy {
+                DecompositionStrategy::Balanced => !tree.is_leaf(i),
+                DecompositionStrategy::CentroidEdge { min_size, .. }
+                | DecompositionStrategy::Diameter { min_size, .. } => {
+                    tree_sizes[i].min(outside) >= min_size as u64
+                }
+            }
+        };
+        let midpoint_cut = wide
+            .as_ref()
+            .and_then(|(diameter, path)| midpoint_branch(*diameter, path, allowed));
+        let best_cut = match midpoint_cut {
+            Some(cut) => cut,
+            None => {
+                // the centroid edge, the one best balancing the sizes of both sides
+                let best = PostorderIterator::from_node_excluding(tree, root, &cuts)
+                    .filter(|&i| i != root && allowed(i))
+                    .min_by_key(|&i| (size as u64 - tree_sizes[i]).abs_diff(tree_sizes[i]));
+                match best {
+                    Some(cut) => cut,
+                    // no cut allowed, the subset stays whole
+                    None => continue,
+                }
+            }
+        };
         for a in tree.ancestors(best_cut) {
             if a == root {
                 break;
@@ -82,10 +197,12 @@ pub fn hierarchical_decomp(tree: &Tree, max_size: usize) -> TaxaHierarchy {
         let view = &mut reordered_taxa[lb..ub];
         view.sort_unstable_by_key(|e| !taxa_label[*e]);
         taxa_label.clear();
-        if tree_sizes[best_cut] >= 2 {
+        // the balanced strategy leaves out tiny subsets, the other ones are bounded by `min_size`
+        let balanced = matches!(strategy, DecompositionStrategy::Balanced);
+        if !balanced || tree_sizes[best_cut] >= 2 {
             decomposition_ranges.push((lb, lb + tree_sizes[best_cut] as usize));
         }
-        if size - tree_sizes[best_cut] as usize > 2 {
+        if !balanced || size - tree_sizes[best_cut] as usize > 2 {
             decomposition_ranges.push((lb + tree_sizes[best_cut] as usize, ub));
         }
         pq.push((
@@ -108,33 +225,87 @@ pub fn hierarchical_decomp(tree: &Tree, max_size: usize) -> TaxaHierarchy {
         reordered_taxa,
         taxa_positions,
         decomposition_ranges,
+        strategy,
     }
 }
 
 pub fn oneshot_melt(
-    input: &PathBuf,
-    tree: &PathBuf,
+    input: &Path,
+    tree: Option<&Path>,
     outdir: &PathBuf,
     config: &ExternalContext,
 ) -> anyhow::Result<CrucibleCtxt> {
-    let collection = TreeCollection::from_newick(tree).expect("Failed to read tree");
-    let decomp = hierarchical_decomp(&collection.trees[0], config.hmm_size_lb);
+    let mut reader = compression::fasta_reader(input)?;
+    let records = reader.records().collect::<Result<Vec<_>, _>>()?;
+    let names = records
+        .iter()
+        .map(|r| String::from_utf8(r.head.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let alphabet = match config.alphabet {
+        Some(alphabet) => alphabet,
+        None => {
+            let alphabet = Alphabet::guess(records.iter().map(|r| &r.seq[..]));
+            info!(?alphabet, "detected alphabet of the backbone");
+            alphabet
+        }
+    };
+    let tree_path = match tree {
+        Some(tree) => tree.to_path_buf(),
+        None => {
+            info!(options = ?config.guide_tree, "no backbone tree given, inferring a guide tree");
+            let newick = guide_tree(&records, alphabet, config.guide_tree)?;
+            create_dir_all(outdir)?;
+            let tree_path = outdir.join("guide.tre");
+            fs::write(&tree_path, newick)?;
+            info!("guide tree written to {:?}", tree_path);
+            tree_path
+        }
+    };
+    let reconciled = reconcile(&read_newick(&tree_path)?, &names)?;
+    if !reconciled.pruned.is_empty() {
+        warn!(
+            num_pruned = reconciled.pruned.len(),
+            "pruned tree leaves not in the backbone, e.g., {:?}",
+            &reconciled.pruned[..reconciled.pruned.len().min(5)]
+        );
+    }
+    if !reconciled.missing.is_empty() {
+        let examples = &reconciled.missing[..reconciled.missing.len().min(5)];
+        if !config.attach_missing_taxa {
+            bail!(
+                "{} backbone sequences are not in the tree, e.g., {:?}; fix the tree, or pass --attach-missing-taxa to only use them in the root HMM",
+                reconciled.missing.len(),
+                examples
+            );
+        }
+        warn!(
+            num_missing = reconciled.missing.len(),
+            "backbone sequences not in the tree are only used in the root HMM, e.g., {:?}",
+            examples
+        );
+    }
+    let mut decomp =
+        hierarchical_decomp(&reconciled.tree, config.hmm_size_lb, config.decomposition);
     info!(
         num_subsets = decomp.decomposition_ranges.len(),
+        strategy = ?decomp.strategy,
         "decomposed input tree"
     );
-    let mut reader = Reader::from_path(input)?;
-    let mut records_failable: Result<Vec<_>, _> = reader.records().collect();
-    let records = records_failable.as_mut().unwrap();
-    let ts = &collection.taxon_set;
-    records.sort_unstable_by_key(|r| {
-        let taxon_name = String::from_utf8(r.head.clone()).unwrap();
-        let id = ts.to_id[&taxon_name];
-        decomp.taxa_positions[id]
-    });
-    for (i, &t) in decomp.reordered_taxa.iter().enumerate() {
-        assert_eq!(&String::from_utf8(records[i].head.clone())?, &ts.names[t]);
-    }
+    let ts = &reconciled.taxon_set;
+    // sequences missing from the tree go last, in their original order; as the names are unique,
+    // the others are in the order of `reordered_taxa`
+    let mut keyed = names
+        .iter()
+        .map(|name| {
+            ts.to_id
+                .get(name)
+                .map_or(usize::MAX, |&id| decomp.taxa_positions[id])
+        })
+        .zip(records)
+        .collect::<Vec<_>>();
+    keyed.sort_by_key(|&(position, _)| position);
+    let records = keyed.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+    decomp.decomposition_ranges[0] = (0, records.len());
     let n = records.len(); // # of seqs
     let k = records[0].seq.len(); // # of columns
     let mut nchars_prefix = Array::<u32, _>::zeros((n + 1, k).f());
@@ -153,26 +324,33 @@ pub fn oneshot_melt(
 
     create_dir_all(&subsets_root)?;
     {
-        let to_write = &records[0..collection.ntaxa()];
+        let to_write = &records[..];
         let mut writer = BufWriter::new(File::create(subsets_root.join(format!("{}.afa", 0)))?);
         for r in to_write {
             r.write_wrap(&mut writer, 60)?;
         }
     }
 
+    info!(builder = ?config.builder, ?alphabet, "building HMMs");
     decomp
         .decomposition_ranges
         .par_iter()
         .enumerate()
-        .for_each(|(i, &(lb, ub))| {
+        .try_for_each(|(i, &(lb, ub))| {
             let to_write = &records[lb..ub];
-            hmmbuild(
-                to_write.iter(),
-                format!("{}", i).as_str(),
-                &subsets_root.join(format!("{}.hmm", i)),
-            )
-            .expect("Failed to build HMM");
-        });
+            let name = format!("{}", i);
+            let hmm_path = subsets_root.join(format!("{}.hmm", i));
+            config
+                .retry
+                .run(Stage::Build, i as u32, None, || match config.builder {
+                    BuildBackend::Hmmbuild => {
+                        hmmbuild(to_write.iter(), &name, &hmm_path, alphabet, config)
+                    }
+                    BuildBackend::Native => build_hmm(to_write.iter(), &name, alphabet)
+                        .and_then(|hmm| hmm.to_path(&hmm_path))
+                        .map_err(ErrorKind::Other),
+                })
+        })?;
 
     let mut writer = BufWriter::new(File::create(metadata_path)?);
     // TODO: very probably not the best way to reuse buffer
@@ -197,7 +375,93 @@ pub fn oneshot_melt(
             HmmMeta::new(decomp_range, nonzero_counts, column_positions)
         })
         .collect();
-    let ctxt = CrucibleCtxt::new(metadata);
+    let ctxt = CrucibleCtxt {
+        decomposition: Some(decomp.strategy),
+        hmm_size_lb: matches!(decomp.strategy, DecompositionStrategy::Balanced)
+            .then_some(config.hmm_size_lb),
+        ..CrucibleCtxt::new(metadata, alphabet)
+    };
     serde_json::to_writer(&mut writer, &ctxt)?;
     Ok(ctxt)
 }
+
+/// log the sizes of the subsets in a built eHMM
+pub fn summarize_decomposition(ctxt: &CrucibleCtxt) {
+    let sizes = ctxt
+        .metadata
+        .iter()
+        .map(|m| m.num_seqs())
+        .sorted_unstable()
+        .collect_vec();
+    info!(
+        num_subsets = ctxt.num_hmms(),
+        num_sequences = ctxt.metadata[0].num_seqs(),
+        num_columns = ctxt.num_consensus_columns(),
+        decomposition = ?ctxt.decomposition,
+        "eHMM summary"
+    );
+    info!(
+        smallest = sizes[0],
+        median = sizes[sizes.len() / 2],
+        largest = sizes[sizes.len() - 1],
+        "HMM sizes (number of sequences)"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ordered_float::NotNan;
+
+    /// the taxa of each subset of the decomposition of `newick`, sorted
+    fn subsets(newick: &str, strategy: DecompositionStrategy) -> Vec<Vec<String>> {
+        let mut taxon_set = TaxonSet::new();
+        let tree = parse_newick(&mut taxon_set, newick);
+        let decomp = hierarchical_decomp(&tree, 10, strategy);
+        decomp
+            .decomposition_ranges
+            .iter()
+            .map(|&(lb, ub)| {
+                let mut names = decomp.reordered_taxa[lb..ub]
+                    .iter()
+                    .map(|&t| taxon_set.names[t].clone())
+                    .collect::<Vec<_>>();
+                names.sort();
+                names
+            })
+            .collect()
+    }
+
+    fn diameter(max_diameter: f64, max_size: usize) -> DecompositionStrategy {
+        DecompositionStrategy::Diameter {
+            max_diameter: NotNan::new(max_diameter).unwrap(),
+            max_size,
+            min_size: 1,
+        }
+    }
+
+    #[test]
+    fn diameter_splits_the_longest_path_in_the_middle() {
+        // the centroid edge would split {A, B} from {C, D}, but the middle of the longest path,
+        // from A (or B) to D, is on the branch of D
+        let newick = "(((A:1,B:1):1,C:1):1,D:20);";
+        assert_eq!(
+            subsets(newick, diameter(5.0, 100)),
+            [vec!["A", "B", "C", "D"], vec!["D"], vec!["A", "B", "C"]]
+        );
+    }
+
+    #[test]
+    fn diameter_honors_max_size() {
+        let newick = "(((A:1,B:1):1,C:1):1,D:1);";
+        // the tree is narrow enough, but every subset of more than two taxa is still split
+        let found = subsets(newick, diameter(100.0, 2));
+        for s in &found {
+            let is_split = found
+                .iter()
+                .any(|t| t.len() < s.len() && t.iter().all(|x| s.contains(x)));
+            assert!(is_split || s.len() <= 2, "{:?} was not split", s);
+        }
+        assert_eq!(subsets(newick, diameter(100.0, 4)), [["A", "B", "C", "D"]]);
+    }
+}
@@ -0,0 +1,184 @@
+//! Dirichlet priors for estimating profile HMM parameters from weighted counts, following HMMER's defaults
+use crate::hmm::Alphabet;
+
+/// a mixture of Dirichlet distributions over the probability vectors of one distribution
+#[derive(Debug, Clone)]
+pub struct DirichletMixture {
+    /// mixture coefficients
+    pub q: Vec<f64>,
+    /// Dirichlet parameters of each component
+    pub alpha: Vec<Vec<f64>>,
+}
+
+/// ln(Gamma(x)) for x > 0, Lanczos approximation (g = 7)
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // reflection formula
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let mut a = COEFFICIENTS[0];
+    let t = x + 7.5;
+    for (i, &c) in COEFFICIENTS.iter().enumerate().skip(1) {
+        a += c / (x + i as f64);
+    }
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + a.ln()
+}
+
+impl DirichletMixture {
+    pub fn single(alpha: Vec<f64>) -> Self {
+        Self {
+            q: vec![1.0],
+            alpha: vec![alpha],
+        }
+    }
+
+    /// log probability of the counts under one component, up to a term shared by all components
+    fn ln_likelihood(alpha: &[f64], counts: &[f64]) -> f64 {
+        let alpha_sum: f64 = alpha.iter().sum();
+        let count_sum: f64 = counts.iter().sum();
+        let mut res = ln_gamma(alpha_sum) - ln_gamma(alpha_sum + count_sum);
+        for (&a, &c) in alpha.iter().zip(counts) {
+            res += ln_gamma(a + c) - ln_gamma(a);
+        }
+        res
+    }
+
+    /// posterior mean probabilities given the observed (weighted) counts
+    pub fn posterior_mean(&self, counts: &[f64]) -> Vec<f64> {
+        let count_sum: f64 = counts.iter().sum();
+        let mut component_weights = if self.q.len() == 1 {
+            vec![1.0]
+        } else {
+            let ln_posteriors = self
+                .q
+                .iter()
+                .zip(&self.alpha)
+                .map(|(q, alpha)| q.ln() + Self::ln_likelihood(alpha, counts))
+                .collect::<Vec<_>>();
+            let max = ln_posteriors
+                .iter()
+                .fold(f64::NEG_INFINITY, |acc, &p| acc.max(p));
+            ln_posteriors.iter().map(|p| (p - max).exp()).collect()
+        };
+        let z: f64 = component_weights.iter().sum();
+        component_weights.iter_mut().for_each(|w| *w /= z);
+        let mut res = vec![0.0; counts.len()];
+        for (w, alpha) in component_weights.iter().zip(&self.alpha) {
+            let denom = count_sum + alpha.iter().sum::<f64>();
+            for (r, (&c, &a)) in res.iter_mut().zip(counts.iter().zip(alpha)) {
+                *r += w * (c + a) / denom;
+            }
+        }
+        res
+    }
+}
+
+/// priors of all distributions of a profile HMM
+#[derive(Debug, Clone)]
+pub struct Priors {
+    pub match_emissions: DirichletMixture,
+    pub insert_emissions: DirichletMixture,
+    /// match transitions (m->m, m->i, m->d)
+    pub match_transitions: DirichletMixture,
+    /// insert transitions (i->m, i->i)
+    pub insert_transitions: DirichletMixture,
+    /// delete transitions (d->m, d->d)
+    pub delete_transitions: DirichletMixture,
+}
+
+/// Sjölander et al.'s nine-component mixture for amino acid match emissions
+const AMINO_MIXTURE_Q: [f64; 9] = [
+    0.178091, 0.056591, 0.0960191, 0.0781233, 0.0834977, 0.0904123, 0.114468, 0.0682132, 0.234585,
+];
+const AMINO_MIXTURE_ALPHA: [[f64; 20]; 9] = [
+    [
+        0.270671, 0.039848, 0.017576, 0.016415, 0.014268, 0.131916, 0.012391, 0.022599, 0.020358,
+        0.030727, 0.015315, 0.048298, 0.053803, 0.020662, 0.023612, 0.216147, 0.147226, 0.065438,
+        0.003758, 0.009621,
+    ],
+    [
+        0.021465, 0.010300, 0.011741, 0.010883, 0.385651, 0.016416, 0.076196, 0.035329, 0.013921,
+        0.093517, 0.022034, 0.028593, 0.013086, 0.023011, 0.018866, 0.029156, 0.018153, 0.036100,
+        0.071770, 0.419641,
+    ],
+    [
+        0.561459, 0.045448, 0.438366, 0.764167, 0.087364, 0.259114, 0.214940, 0.145928, 0.762204,
+        0.247320, 0.118662, 0.441564, 0.174822, 0.530840, 0.465529, 0.583402, 0.445586, 0.227050,
+        0.029510, 0.121090,
+    ],
+    [
+        0.070143, 0.011140, 0.019479, 0.094657, 0.013162, 0.048038, 0.077000, 0.032939, 0.576639,
+        0.072293, 0.028240, 0.080372, 0.037661, 0.185037, 0.506783, 0.073732, 0.071587, 0.042532,
+        0.011254, 0.028723,
+    ],
+    [
+        0.041103, 0.014794, 0.005610, 0.010216, 0.153602, 0.007797, 0.007175, 0.299635, 0.010849,
+        0.999446, 0.210189, 0.006127, 0.013021, 0.019798, 0.014509, 0.012049, 0.035799, 0.180085,
+        0.012744, 0.026466,
+    ],
+    [
+        0.115607, 0.037381, 0.012414, 0.018179, 0.051778, 0.017255, 0.004911, 0.796882, 0.017074,
+        0.285858, 0.075811, 0.014548, 0.015092, 0.011382, 0.012696, 0.027535, 0.088333, 0.944340,
+        0.004373, 0.016741,
+    ],
+    [
+        0.093461, 0.004737, 0.387252, 0.347841, 0.010822, 0.105877, 0.049776, 0.014963, 0.094276,
+        0.027761, 0.010040, 0.187869, 0.050018, 0.110039, 0.038668, 0.119471, 0.065802, 0.025430,
+        0.003215, 0.018742,
+    ],
+    [
+        0.452171, 0.114613, 0.062460, 0.115702, 0.284246, 0.140204, 0.100358, 0.550230, 0.143995,
+        0.700649, 0.276580, 0.118569, 0.097470, 0.126673, 0.143634, 0.278983, 0.358482, 0.661750,
+        0.061533, 0.199373,
+    ],
+    [
+        0.005193, 0.004039, 0.006722, 0.006121, 0.003468, 0.016931, 0.003647, 0.002184, 0.005019,
+        0.005990, 0.001473, 0.004158, 0.009055, 0.003630, 0.006583, 0.003172, 0.003690, 0.002967,
+        0.002772, 0.002686,
+    ],
+];
+
+/// total pseudocount of the insert emission prior, which pins insert emissions to the background
+const INSERT_EMISSION_CONCENTRATION: f64 = 1000.0;
+
+impl Priors {
+    /// HMMER's default priors: the nine-component mixture for amino acid match emissions, and the
+    /// same transition priors for both alphabets. Nucleotide match emissions use a plus-one (Laplace)
+    /// prior instead of HMMER's nucleotide mixture
+    pub fn default_for(alphabet: Alphabet) -> Self {
+        let match_emissions = match alphabet {
+            Alphabet::Amino => DirichletMixture {
+                q: AMINO_MIXTURE_Q.to_vec(),
+                alpha: AMINO_MIXTURE_ALPHA.iter().map(|a| a.to_vec()).collect(),
+            },
+            Alphabet::Dna | Alphabet::Rna => DirichletMixture::single(vec![1.0; alphabet.size()]),
+        };
+        let insert_emissions = DirichletMixture::single(
+            alphabet
+                .background()
+                .iter()
+                .map(|f| f * INSERT_EMISSION_CONCENTRATION)
+                .collect(),
+        );
+        Self {
+            match_emissions,
+            insert_emissions,
+            match_transitions: DirichletMixture::single(vec![0.7939, 0.0278, 0.0135]),
+            insert_transitions: DirichletMixture::single(vec![0.1551, 0.1331]),
+            delete_transitions: DirichletMixture::single(vec![0.9002, 0.5630]),
+        }
+    }
+}
@@ -0,0 +1,473 @@
+//! In-process scoring and alignment of sequences against profile HMMs, as an alternative to running
+//! `hmmsearch` (local multihit configuration) and `hmmalign` (glocal unihit configuration)
+use ndarray::{Array, Ix2};
+
+use crate::hmm::{Alphabet, ProfileHmm, T_DD, T_DM, T_II, T_IM, T_MD, T_MI, T_MM};
+
+/// which dynamic programming algorithm computes the score of a sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DpAlgorithm {
+    /// sum over all alignments, same as the sequence scores reported by `hmmsearch`
+    Forward,
+    /// best alignment only; faster, and slightly lower scores than `Forward`
+    Viterbi,
+    /// best ungapped local segments only, the score of HMMER's MSV filter
+    Msv,
+}
+
+/// a profile HMM with log-odds emission scores, for search and alignment
+pub struct SearchProfile {
+    pub alphabet: Alphabet,
+    /// number of match states (M)
+    pub length: usize,
+    /// (M + 1) x (residue codes) match emission log-odds scores in nats
+    match_scores: Array<f64, Ix2>,
+    /// (M + 1) x 7 transition log probabilities
+    transitions: Array<f64, Ix2>,
+    /// log probabilities of entering at each match state (index 0 unused)
+    entry: Vec<f64>,
+    /// `match_scores`, `transitions` and `entry` in probability space, for the Forward algorithm
+    match_odds: Array<f64, Ix2>,
+    transition_probs: Array<f64, Ix2>,
+    entry_probs: Vec<f64>,
+}
+
+// traceback pointers of the alignment
+const FROM_M: u8 = 0;
+const FROM_I: u8 = 1;
+const FROM_D: u8 = 2;
+const FROM_B: u8 = 3;
+
+/// null model score (nats) of a sequence of length `l`
+fn null_score(l: usize) -> f64 {
+    let p1 = l as f64 / (l as f64 + 1.0);
+    l as f64 * p1.ln() + (1.0 - p1).ln()
+}
+
+impl SearchProfile {
+    pub fn new(hmm: &ProfileHmm) -> Self {
+        let m = hmm.length;
+        let alphabet = hmm.alphabet;
+        let bg = alphabet.background();
+        let num_codes = alphabet.num_codes();
+        let mut match_scores = Array::<f64, _>::from_elem((m + 1, num_codes), f64::NEG_INFINITY);
+        let code_residues = (0..num_codes as u8)
+            .map(|c| alphabet.code_residues(c))
+            .collect::<Vec<_>>();
+        for k in 1..=m {
+            for (code, residues) in code_residues.iter().enumerate() {
+                // degenerate residues score the background-weighted expected score, as in HMMER
+                let (mut sc, mut denom) = (0.0, 0.0);
+                for &x in residues {
+                    sc += bg[x] * (hmm.match_emissions[[k, x]] - bg[x].ln());
+                    denom += bg[x];
+                }
+                match_scores[[k, code]] = sc / denom;
+            }
+        }
+        // local entry is weighted by the occupancy of each match state
+        let t = |k: usize, j: usize| hmm.transitions[[k, j]].exp();
+        let mut occupancy = vec![0.0; m + 1];
+        occupancy[1] = t(0, T_MI) + t(0, T_MM);
+        for k in 2..=m {
+            occupancy[k] = occupancy[k - 1] * (t(k - 1, T_MM) + t(k - 1, T_MI))
+                + (1.0 - occupancy[k - 1]) * t(k - 1, T_DM);
+        }
+        let z: f64 = (1..=m).map(|k| occupancy[k] * (m - k + 1) as f64).sum();
+        let mut entry = vec![f64::NEG_INFINITY; m + 1];
+        for k in 1..=m {
+            entry[k] = (occupancy[k] / z).ln();
+        }
+        Self {
+            alphabet,
+            length: m,
+            match_odds: match_scores.mapv(f64::exp),
+            transition_probs: hmm.transitions.mapv(f64::exp),
+            entry_probs: entry.iter().map(|e| e.exp()).collect(),
+            match_scores,
+            transitions: hmm.transitions.clone(),
+            entry,
+        }
+    }
+
+    /// bit score of a sequence, comparable to the sequence scores of `hmmsearch` before its null2 bias
+    /// correction (the reported score plus the reported bias) for `Forward`
+    pub fn score(&self, seq: &[u8], algorithm: DpAlgorithm) -> f64 {
+        let dsq = self.alphabet.digitize(seq);
+        if dsq.is_empty() || self.length == 0 {
+            return f64::NEG_INFINITY;
+        }
+        let nats = match algorithm {
+            DpAlgorithm::Forward => self.forward(&dsq),
+            DpAlgorithm::Viterbi => self.viterbi(&dsq),
+            DpAlgorithm::Msv => self.msv(&dsq),
+        };
+        (nats - null_score(dsq.len())) / std::f64::consts::LN_2
+    }
+
+    /// N/C/J loop and move log probabilities of the multihit length model for a target of length `l`
+    fn length_model(l: usize) -> (f64, f64) {
+        let l = l as f64;
+        ((l / (l + 3.0)).ln(), (3.0 / (l + 3.0)).ln())
+    }
+
+    /// Forward score in nats, computed in probability space with per-row scaling
+    fn forward(&self, dsq: &[u8]) -> f64 {
+        let m = self.length;
+        let (lp_loop, lp_move) = Self::length_model(dsq.len());
+        let (p_loop, p_move, p_ej) = (lp_loop.exp(), lp_move.exp(), 0.5);
+        let (t, entry, odds) = (&self.transition_probs, &self.entry_probs, &self.match_odds);
+        let (mut mp, mut ip, mut dp) = (vec![0.0; m + 1], vec![0.0; m + 1], vec![0.0; m + 1]);
+        let (mut mc, mut ic, mut dc) = (vec![0.0; m + 1], vec![0.0; m + 1], vec![0.0; m + 1]);
+        let (mut n, mut b, mut j, mut c) = (1.0, p_move, 0.0, 0.0);
+        let mut log_scale = 0.0;
+        for &x in dsq {
+            let x = x as usize;
+            let mut e = 0.0;
+            mc[0] = 0.0;
+            ic[0] = 0.0;
+            dc[0] = 0.0;
+            for k in 1..=m {
+                mc[k] = odds[[k, x]]
+                    * (mp[k - 1] * t[[k - 1, T_MM]]
+                        + ip[k - 1] * t[[k - 1, T_IM]]
+                        + dp[k - 1] * t[[k - 1, T_DM]]
+                        + b * entry[k]);
+                // insert emissions score zero (odds of one)
+                ic[k] = if k < m {
+                    mp[k] * t[[k, T_MI]] + ip[k] * t[[k, T_II]]
+                } else {
+                    0.0
+                };
+                dc[k] = mc[k - 1] * t[[k - 1, T_MD]] + dc[k - 1] * t[[k - 1, T_DD]];
+                e += mc[k] + dc[k];
+            }
+            j = j * p_loop + e * p_ej;
+            c = c * p_loop + e * p_ej;
+            n *= p_loop;
+            b = n * p_move + j * p_move;
+            // rescale the row to avoid underflow
+            let scale = mc
+                .iter()
+                .chain(ic.iter())
+                .chain(dc.iter())
+                .fold(n.max(b).max(j).max(c), |acc, &v| acc.max(v));
+            if scale > 0.0 && !(1e-100..=1e100).contains(&scale) {
+                for v in mc.iter_mut().chain(ic.iter_mut()).chain(dc.iter_mut()) {
+                    *v /= scale;
+                }
+                n /= scale;
+                b /= scale;
+                j /= scale;
+                c /= scale;
+                log_scale += scale.ln();
+            }
+            std::mem::swap(&mut mp, &mut mc);
+            std::mem::swap(&mut ip, &mut ic);
+            std::mem::swap(&mut dp, &mut dc);
+        }
+        (c * p_move).ln() + log_scale
+    }
+
+    /// Viterbi score in nats
+    fn viterbi(&self, dsq: &[u8]) -> f64 {
+        let m = self.length;
+        let (lp_loop, lp_move) = Self::length_model(dsq.len());
+        let lp_ej = 0.5f64.ln();
+        let t = &self.transitions;
+        let ninf = f64::NEG_INFINITY;
+        let (mut mp, mut ip, mut dp) = (vec![ninf; m + 1], vec![ninf; m + 1], vec![ninf; m + 1]);
+        let (mut mc, mut ic, mut dc) = (vec![ninf; m + 1], vec![ninf; m + 1], vec![ninf; m + 1]);
+        let (mut n, mut b, mut j, mut c) = (0.0, lp_move, ninf, ninf);
+        for &x in dsq {
+            let x = x as usize;
+            let mut e = ninf;
+            for k in 1..=m {
+                mc[k] = self.match_scores[[k, x]]
+                    + (mp[k - 1] + t[[k - 1, T_MM]])
+                        .max(ip[k - 1] + t[[k - 1, T_IM]])
+                        .max(dp[k - 1] + t[[k - 1, T_DM]])
+                        .max(b + self.entry[k]);
+                ic[k] = if k < m {
+                    (mp[k] + t[[k, T_MI]]).max(ip[k] + t[[k, T_II]])
+                } else {
+                    ninf
+                };
+                dc[k] = (mc[k - 1] + t[[k - 1, T_MD]]).max(dc[k - 1] + t[[k - 1, T_DD]]);
+                e = e.max(mc[k]).max(dc[k]);
+            }
+            j = (j + lp_loop).max(e + lp_ej);
+            c = (c + lp_loop).max(e + lp_ej);
+            n += lp_loop;
+            b = (n + lp_move).max(j + lp_move);
+            std::mem::swap(&mut mp, &mut mc);
+            std::mem::swap(&mut ip, &mut ic);
+            std::mem::swap(&mut dp, &mut dc);
+        }
+        c + lp_move
+    }
+
+    /// MSV score in nats: ungapped segments entered uniformly, ignoring the transitions of the model
+    fn msv(&self, dsq: &[u8]) -> f64 {
+        let m = self.length;
+        let (lp_loop, lp_move) = Self::length_model(dsq.len());
+        let lp_ej = 0.5f64.ln();
+        let lp_entry = (2.0 / (m as f64 * (m as f64 + 1.0))).ln();
+        let ninf = f64::NEG_INFINITY;
+        let mut mv = vec![ninf; m + 1];
+        let (mut n, mut b, mut j, mut c) = (0.0, lp_move, ninf, ninf);
+        for &x in dsq {
+            let x = x as usize;
+            let mut e = ninf;
+            // backwards, so that mv[k - 1] still holds the previous row
+            for k in (1..=m).rev() {
+                mv[k] = self.match_scores[[k, x]] + mv[k - 1].max(b + lp_entry);
+                e = e.max(mv[k]);
+            }
+            j = (j + lp_loop).max(e + lp_ej);
+            c = (c + lp_loop).max(e + lp_ej);
+            n += lp_loop;
+            b = (n + lp_move).max(j + lp_move);
+        }
+        c + lp_move
+    }
+
+    /// Viterbi alignment of a sequence to the whole model (glocal unihit, the configuration of
+    /// `hmmalign`, which decodes by optimal accuracy instead), returning the (residue index, match
+    /// state index) pairs of residues emitted by match states, both 0-based. Other residues are
+    /// insertions.
+    pub fn align(&self, seq: &[u8]) -> Vec<(u32, u32)> {
+        let dsq = self.alphabet.digitize(seq);
+        let (l, m) = (dsq.len(), self.length);
+        if l == 0 || m == 0 {
+            return vec![];
+        }
+        let lf = l as f64;
+        let (lp_loop, lp_move) = ((lf / (lf + 2.0)).ln(), (2.0 / (lf + 2.0)).ln());
+        let t = &self.transitions;
+        // glocal entry into M1 or D1, renormalized without the begin insert state
+        let begin_norm = (t[[0, T_MM]].exp() + t[[0, T_MD]].exp()).ln();
+        let (t_bm, t_bd) = (t[[0, T_MM]] - begin_norm, t[[0, T_MD]] - begin_norm);
+        let ninf = f64::NEG_INFINITY;
+        let mut m_ptr = Array::<u8, _>::zeros((l + 1, m + 1));
+        let mut i_ptr = Array::<u8, _>::zeros((l + 1, m + 1));
+        let mut d_ptr = Array::<u8, _>::zeros((l + 1, m + 1));
+        let mut e_ptr = vec![FROM_M; l + 1];
+        let mut c_from_e = vec![false; l + 1];
+        let (mut mp, mut ip, mut dp) = (vec![ninf; m + 1], vec![ninf; m + 1], vec![ninf; m + 1]);
+        let (mut mc, mut ic, mut dc) = (vec![ninf; m + 1], vec![ninf; m + 1], vec![ninf; m + 1]);
+        let mut n = 0.0;
+        let mut b = lp_move;
+        // row 0: only the all-deletion path is possible
+        dp[1] = b + t_bd;
+        d_ptr[[0, 1]] = FROM_B;
+        for k in 2..=m {
+            dp[k] = dp[k - 1] + t[[k - 1, T_DD]];
+            d_ptr[[0, k]] = FROM_D;
+        }
+        e_ptr[0] = FROM_D;
+        let mut c = dp[m];
+        c_from_e[0] = true;
+        for i in 1..=l {
+            let x = dsq[i - 1] as usize;
+            let b_prev = b;
+            n += lp_loop;
+            b = n + lp_move;
+            for k in 1..=m {
+                let (best, ptr) = if k == 1 {
+                    (b_prev + t_bm, FROM_B)
+                } else {
+                    [
+                        (mp[k - 1] + t[[k - 1, T_MM]], FROM_M),
+                        (ip[k - 1] + t[[k - 1, T_IM]], FROM_I),
+                        (dp[k - 1] + t[[k - 1, T_DM]], FROM_D),
+                    ]
+                    .into_iter()
+                    .fold((ninf, FROM_M), |acc, v| if v.0 > acc.0 { v } else { acc })
+                };
+                mc[k] = best + self.match_scores[[k, x]];
+                m_ptr[[i, k]] = ptr;
+                if k < m {
+                    let (from_m, from_i) = (mp[k] + t[[k, T_MI]], ip[k] + t[[k, T_II]]);
+                    (ic[k], i_ptr[[i, k]]) = if from_m >= from_i {
+                        (from_m, FROM_M)
+                    } else {
+                        (from_i, FROM_I)
+                    };
+                } else {
+                    ic[k] = ninf;
+                }
+                (dc[k], d_ptr[[i, k]]) = if k == 1 {
+                    (b + t_bd, FROM_B)
+                } else {
+                    let (from_m, from_d) =
+                        (mc[k - 1] + t[[k - 1, T_MD]], dc[k - 1] + t[[k - 1, T_DD]]);
+                    if from_m >= from_d {
+                        (from_m, FROM_M)
+                    } else {
+                        (from_d, FROM_D)
+                    }
+                };
+            }
+            let e = if mc[m] >= dc[m] {
+                e_ptr[i] = FROM_M;
+                mc[m]
+            } else {
+                e_ptr[i] = FROM_D;
+                dc[m]
+            };
+            if e >= c + lp_loop {
+                c = e;
+                c_from_e[i] = true;
+            } else {
+                c += lp_loop;
+            }
+            std::mem::swap(&mut mp, &mut mc);
+            std::mem::swap(&mut ip, &mut ic);
+            std::mem::swap(&mut dp, &mut dc);
+        }
+        if c == ninf {
+            return vec![];
+        }
+        let mut pairs = vec![];
+        let mut i = l;
+        while !c_from_e[i] {
+            i -= 1;
+        }
+        let (mut state, mut k) = (e_ptr[i], m);
+        loop {
+            match state {
+                FROM_M => {
+                    pairs.push(((i - 1) as u32, (k - 1) as u32));
+                    state = m_ptr[[i, k]];
+                    i -= 1;
+                    k -= 1;
+                }
+                FROM_I => {
+                    state = i_ptr[[i, k]];
+                    i -= 1;
+                }
+                FROM_D => {
+                    state = d_ptr[[i, k]];
+                    k -= 1;
+                }
+                _ => break,
+            }
+        }
+        pairs.reverse();
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ExternalContext, HmmerTool},
+        construct::build_hmm,
+        external,
+    };
+    use seq_io::fasta::OwnedRecord;
+
+    fn records(seqs: &[&str]) -> Vec<OwnedRecord> {
+        seqs.iter()
+            .enumerate()
+            .map(|(i, s)| OwnedRecord {
+                head: format!("s{}", i).into_bytes(),
+                seq: s.as_bytes().to_vec(),
+            })
+            .collect()
+    }
+
+    const ALIGNMENT: [&str; 6] = [
+        "ATGGCTAAGCTTGACCGTAAGTCA",
+        "ATGGCTAAGC-TGACCGTAAGTCA",
+        "ATGGCAAAGCTTGACCGAAAGTCA",
+        "ATGCCTAAGCTTGA--GTAAGTCA",
+        "ATGGCTCAGCTTGACCGTAAGACA",
+        "ATGGCTAAGCTAGACCGTTAGTCA",
+    ];
+
+    /// a full-length homolog, a fragment, a homolog with an insertion, two copies of the model and
+    /// an unrelated low-complexity sequence
+    const QUERIES: [&str; 5] = [
+        "ATGGCTAAGCTTGACCGTAAGTCA",
+        "CTTGACCGTAAG",
+        "ATGGCTAAGCTTGGGGACCGTAAGTCA",
+        "ATGGCTAAGCTTGACCGTAAGTCAGGATGGCTAAGCTTGACCGTAAGTCA",
+        "ACACACACACACACACACACACAC",
+    ];
+
+    /// a single residue has one path through the model (N, B, M1, E, C, T), whose score follows
+    /// from the multihit length model and the null model of HMMER
+    #[test]
+    fn single_residue_score() {
+        let hmm = build_hmm(records(&["A", "A", "C"]).iter(), "one", Alphabet::Dna).unwrap();
+        let profile = SearchProfile::new(&hmm);
+        for (residue, x) in [(b"A", 0), (b"G", 2)] {
+            let emission = (hmm.match_emissions[[1, x]] - 0.25f64.ln()) / std::f64::consts::LN_2;
+            // N to B and C to T each move with 3 / (L + 3), E to C with 1/2, against a null model
+            // emitting the residue with 1/2 and ending with 1/2
+            let expected = emission + 2.0 * 0.75f64.log2() + 0.5f64.log2() - 0.25f64.log2();
+            for algorithm in [DpAlgorithm::Forward, DpAlgorithm::Viterbi] {
+                let score = profile.score(residue, algorithm);
+                assert!(
+                    (score - expected).abs() < 1e-9,
+                    "{:?}: {} instead of {}",
+                    algorithm,
+                    score,
+                    expected
+                );
+            }
+        }
+    }
+
+    /// scores of an HMM built by hmmbuild against the scores of hmmsearch before its null2 correction
+    /// (score plus bias), which it reports to one decimal: Forward scores match them, Viterbi scores
+    /// only count the best path and are lower. Skipped without HMMER.
+    #[test]
+    fn scores_match_hmmsearch() {
+        let config = ExternalContext::default();
+        if external::check_version(&HmmerTool::new("hmmsearch")).is_err()
+            || external::check_version(&HmmerTool::new("hmmbuild")).is_err()
+        {
+            eprintln!("HMMER not found, skipping");
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("witch-ng-profile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hmm_path = dir.join("test.hmm");
+        let alignment = records(&ALIGNMENT);
+        external::hmmbuild(alignment.iter(), "test", &hmm_path, Alphabet::Dna, &config).unwrap();
+        let queries = records(&QUERIES);
+        let hits = external::hmmsearch(
+            &hmm_path,
+            queries.iter().enumerate().map(|(i, r)| (i as u32, r)),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(hits.len(), QUERIES.len());
+        let profile = SearchProfile::new(&ProfileHmm::from_path(&hmm_path).unwrap());
+        for hit in hits {
+            let seq = QUERIES[hit.seq_id as usize].as_bytes();
+            let forward = profile.score(seq, DpAlgorithm::Forward);
+            let viterbi = profile.score(seq, DpAlgorithm::Viterbi);
+            let expected = hit.score + hit.bias;
+            assert!(
+                (forward - expected).abs() < 0.15,
+                "query {}: Forward score {} instead of {}",
+                hit.seq_id,
+                forward,
+                expected
+            );
+            assert!(
+                viterbi <= forward + 1e-9 && viterbi < expected + 0.15,
+                "query {}: Viterbi score {} above the Forward score {}",
+                hit.seq_id,
+                viterbi,
+                expected
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
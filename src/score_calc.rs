@@ -1,7 +1,6 @@
 use std::{
     cmp::Reverse,
-    fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::AtomicUsize,
         mpsc::{Receiver, Sender},
@@ -10,7 +9,7 @@ use std::{
     time::Duration,
 };
 
-use ahash::AHashMap;
+use anyhow::{anyhow, bail};
 use itertools::Itertools;
 use ordered_float::NotNan;
 use rayon::{
@@ -18,33 +17,117 @@ use rayon::{
     prelude::IndexedParallelIterator,
     slice::ParallelSlice,
 };
+use rkyv::AlignedVec;
 use seq_io::fasta::OwnedRecord;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use crate::{
-    config::ExternalContext,
+    compression,
+    config::{AlignBackend, ExternalContext, ScoringBackend},
+    error::{ErrorKind, Stage, StageError},
     external::hmmsearch,
+    hmm::ProfileHmm,
+    profile::{DpAlgorithm, SearchProfile},
     progress_reporter,
-    structures::{AdderPayload, CrucibleCtxt},
+    structures::{AdderPayload, CrucibleCtxt, SequenceHit},
 };
 
 const DEFAULT_CHUNK_SIZE: usize = 1000;
 
+/// the key of the hits of a chunk of queries against one HMM in the checkpoint. The scorer is part
+/// of the key, so that resuming with another scorer does not mix their scores
+fn checkpoint_key(scorer: ScoringBackend, chunk_id: usize, hmm_id: usize) -> Vec<u8> {
+    let mut key = format!("{:?}/", scorer).into_bytes();
+    key.extend_from_slice(&(chunk_id as u64).to_be_bytes());
+    key.extend_from_slice(&(hmm_id as u64).to_be_bytes());
+    key
+}
+
+/// the sled tree holding the checkpoint entries, versioned by the layout of the entries
+const CHECKPOINT_TREE: &str = "sequence-hits-v1";
+
+/// the checkpoint entries of `db`, dropping the entries of older versions (in the default tree),
+/// whose layout differs
+fn open_checkpoint(db: &sled::Db) -> anyhow::Result<sled::Tree> {
+    if !db.is_empty() {
+        warn!(
+            num_entries = db.len(),
+            "dropping the checkpoint entries written by an older version"
+        );
+        db.clear()?;
+    }
+    Ok(db.open_tree(CHECKPOINT_TREE)?)
+}
+
+/// the hits cached under `key` in the checkpoint, if any, checking that they are well-formed
+fn cached_hits(checkpoint: &sled::Tree, key: &[u8]) -> Result<Option<Vec<SequenceHit>>, ErrorKind> {
+    let value = match checkpoint.get(key) {
+        Ok(Some(value)) => value,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(ErrorKind::Other(e.into())),
+    };
+    // the archived hits must be aligned, which the values of sled are not
+    let mut bytes = AlignedVec::with_capacity(value.len());
+    bytes.extend_from_slice(&value);
+    rkyv::from_bytes::<Vec<SequenceHit>>(&bytes)
+        .map(Some)
+        .map_err(|e| ErrorKind::InvalidOutput(anyhow!("corrupt checkpoint entry: {}", e)))
+}
+
+/// the profile of HMM `hmm_id` of the eHMM at `base_dir`, checked against its subset
+pub(crate) fn load_profile(
+    base_dir: &Path,
+    hmm_ctxt: &CrucibleCtxt,
+    hmm_id: u32,
+) -> anyhow::Result<SearchProfile> {
+    let hmm = ProfileHmm::from_path(base_dir.join("subsets").join(format!("{}.hmm", hmm_id)))?;
+    hmm.check_against(&hmm_ctxt.metadata[hmm_id as usize])?;
+    Ok(SearchProfile::new(&hmm))
+}
+
+/// the profiles of all HMMs of the eHMM at `base_dir` for the in-process scorer and aligner, none
+/// if HMMER does both. Loaded once, they are shared by scoring and aligning, and by every batch
+pub(crate) fn load_profiles(
+    base_dir: &Path,
+    hmm_ctxt: &CrucibleCtxt,
+    config: &ExternalContext,
+) -> anyhow::Result<Option<Arc<Vec<SearchProfile>>>> {
+    if config.scorer == ScoringBackend::Hmmsearch && config.aligner == AlignBackend::Hmmalign {
+        return Ok(None);
+    }
+    let profiles = config.create_full_pool().install(|| {
+        (0..hmm_ctxt.num_hmms() as u32)
+            .into_par_iter()
+            .map(|hmm_id| load_profile(base_dir, hmm_ctxt, hmm_id))
+            .collect::<anyhow::Result<Vec<_>>>()
+    })?;
+    Ok(Some(Arc::new(profiles)))
+}
+
 pub struct ScoringCtxt {
     pub base_dir: PathBuf,
     pub hmm_ctxt: CrucibleCtxt,
+    /// the queries, whose indices are the internal ids given to HMMER in place of their headers
     pub queries: Vec<OwnedRecord>,
-    pub seq_ids: AHashMap<String, u32>,
+    /// the Phred quality of each residue of the queries, if read from FASTQ
+    pub qualities: Option<Vec<Vec<u8>>>,
+    /// the profiles of the HMMs, if loaded beforehand by `load_profiles`
+    pub(crate) profiles: Option<Arc<Vec<SearchProfile>>>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct BitscoreTracker {
     pub hmm_ids: Vec<u32>,
     pub bitscores: Vec<f64>,
+    pub hits: Vec<SequenceHit>,
 }
 
 impl BitscoreTracker {
-    pub fn calc_adjusted_scores(&self, ctxt: &ScoringCtxt) -> impl Iterator<Item = (u32, f64)> {
+    /// the top hits by adjusted bitscore, along with the index of each hit in the tracker
+    pub fn calc_adjusted_scores(
+        &self,
+        ctxt: &ScoringCtxt,
+    ) -> impl Iterator<Item = (u32, f64, usize)> {
         let hmm_sizes = self
             .hmm_ids
             .iter()
@@ -54,7 +137,8 @@ impl BitscoreTracker {
             .hmm_ids
             .iter()
             .zip(self.bitscores.iter())
-            .map(|(hmm_id, score_i)| {
+            .enumerate()
+            .map(|(ix, (hmm_id, score_i))| {
                 // let hmm_size
                 let size_i = ctxt.hmm_ctxt.metadata[*hmm_id as usize].num_seqs();
                 let exponents = self
@@ -63,14 +147,20 @@ impl BitscoreTracker {
                     .zip(hmm_sizes.iter())
                     .map(|(b, s)| b - score_i + (*s as f64 / size_i as f64).log2());
                 let denominator = exponents.map(|e| 2.0f64.powf(e)).sum::<f64>();
-                (Reverse(NotNan::new(1.0 / denominator).unwrap()), *hmm_id)
+                (
+                    Reverse(NotNan::new(1.0 / denominator).unwrap()),
+                    *hmm_id,
+                    ix,
+                )
             })
             .collect_vec();
         if converted.len() > 10 {
             converted.select_nth_unstable(9);
         }
         converted.truncate(10);
-        converted.into_iter().map(|(s, c)| (c, s.0.into_inner()))
+        converted
+            .into_iter()
+            .map(|(s, c, ix)| (c, s.0.into_inner(), ix))
     }
 }
 
@@ -78,32 +168,102 @@ impl ScoringCtxt {
     pub fn from_ehmms_ctxt(
         base_dir: PathBuf,
         hmm_ctxt: CrucibleCtxt,
-        queries_path: &PathBuf,
+        queries_path: &Path,
     ) -> anyhow::Result<Self> {
-        let queries_failiable: Result<Vec<_>, _> =
-            seq_io::fasta::Reader::new(File::open(queries_path)?)
-                .records()
-                .collect();
-        let queries = queries_failiable?;
-        info!("read {} query sequences", queries.len());
-        let mut seq_ids: AHashMap<String, u32> = AHashMap::new();
-        for (i, q) in queries.iter().enumerate() {
-            seq_ids.insert(String::from_utf8(q.head.clone())?, i as u32);
+        // the qualities are only used with `quality_weights`, see `with_qualities`
+        let queries = compression::read_sequences(queries_path)?;
+        info!("read {} query sequences", queries.records.len());
+        Self::from_records(base_dir, hmm_ctxt, queries.records)
+    }
+
+    pub fn from_records(
+        base_dir: PathBuf,
+        hmm_ctxt: CrucibleCtxt,
+        queries: Vec<OwnedRecord>,
+    ) -> anyhow::Result<Self> {
+        if let Some(alphabet) = hmm_ctxt.alphabet {
+            let invalid = queries
+                .iter()
+                .filter_map(|q| alphabet.find_invalid(&q.seq).map(|found| (q, found)))
+                .collect_vec();
+            if let Some((q, (pos, c))) = invalid.first() {
+                bail!(
+                    "{} queries have letters outside the {:?} alphabet of the eHMM, e.g., {:?} at position {} of {:?} (the alphabet can be set with --alphabet)",
+                    invalid.len(),
+                    alphabet,
+                    *c as char,
+                    pos + 1,
+                    String::from_utf8_lossy(&q.head)
+                );
+            }
         }
         Ok(Self {
             base_dir,
             hmm_ctxt,
             queries,
-            seq_ids,
+            qualities: None,
+            profiles: None,
         })
     }
 
+    /// sets the Phred qualities of the residues of the queries, from FASTQ; only to be set with
+    /// `quality_weights`, as they are then used to weigh the residues
+    pub fn with_qualities(self, qualities: Option<Vec<Vec<u8>>>) -> Self {
+        Self { qualities, ..self }
+    }
+
+    /// sets the profiles of the HMMs loaded by `load_profiles`, so that neither scoring nor
+    /// aligning loads them again
+    pub(crate) fn with_profiles(self, profiles: Option<Arc<Vec<SearchProfile>>>) -> Self {
+        Self { profiles, ..self }
+    }
+
     pub fn hmm_path(&self, hmm_id: u32) -> PathBuf {
         self.base_dir
             .join("subsets")
             .join(format!("{}.hmm", hmm_id))
     }
 
+    /// the search profile of each HMM, for the in-process scorers; none for `hmmsearch`
+    fn search_profiles(&self, config: &ExternalContext) -> anyhow::Result<Arc<Vec<SearchProfile>>> {
+        if config.scorer == ScoringBackend::Hmmsearch {
+            return Ok(Arc::default());
+        }
+        match &self.profiles {
+            Some(profiles) => Ok(profiles.clone()),
+            None => Ok(load_profiles(&self.base_dir, &self.hmm_ctxt, config)?.unwrap_or_default()),
+        }
+    }
+
+    /// scores a chunk of queries (the first being `first_seq_id`) against one HMM, whose search
+    /// profile is `profiles[hmm_id]` for the in-process scorers
+    fn search_chunk(
+        &self,
+        hmm_id: u32,
+        profiles: &[SearchProfile],
+        first_seq_id: usize,
+        chunk: &[OwnedRecord],
+        config: &ExternalContext,
+    ) -> Result<Vec<SequenceHit>, ErrorKind> {
+        let algorithm = match config.scorer {
+            ScoringBackend::Hmmsearch => {
+                let ids = (first_seq_id as u32..).zip(chunk);
+                return hmmsearch(&self.hmm_path(hmm_id), ids, config);
+            }
+            ScoringBackend::Forward => DpAlgorithm::Forward,
+            ScoringBackend::Viterbi => DpAlgorithm::Viterbi,
+        };
+        let profile = &profiles[hmm_id as usize];
+        Ok(chunk
+            .iter()
+            .enumerate()
+            .map(|(j, q)| {
+                SequenceHit::from_score((first_seq_id + j) as u32, profile.score(&q.seq, algorithm))
+            })
+            .filter(|hit| hit.score.is_finite())
+            .collect())
+    }
+
     pub fn produce_payload(&self, config: &ExternalContext) -> anyhow::Result<AdderPayload> {
         let h = self.hmm_ctxt.num_hmms();
         let q = self.queries.len();
@@ -111,6 +271,9 @@ impl ScoringCtxt {
         let mut chunk_size = DEFAULT_CHUNK_SIZE;
         chunk_size = chunk_size.min(q / config.num_workers).max(400);
         info!(chunk_size, "prepared to run hmmsearch");
+        let profiles = self.search_profiles(config)?;
+        let profiles = &profiles[..];
+        let checkpoint = config.db.as_ref().map(open_checkpoint).transpose()?;
         let mut score_trackers = vec![BitscoreTracker::default(); q];
         let total_work = (self.queries.len() as f64 / chunk_size as f64).ceil() as usize * h;
         let num_finished = Arc::new(AtomicUsize::new(0)); // FIXME: use an eventually consistent counter. Arc might have too high an overhead
@@ -131,71 +294,118 @@ impl ScoringCtxt {
             })
         });
 
-        let hmmsearch_results: Vec<(u32, u32, f64)> = self
+        let hmmsearch_results: anyhow::Result<Vec<(u32, SequenceHit)>> = self
             .queries
             .par_chunks(chunk_size)
             .enumerate()
-            .flat_map(|(chunk_id, chunk)| {
-                let for_progress = num_finished.clone();
-                (0..h).into_par_iter().flat_map_iter(move |i| {
+            .flat_map(|(chunk_id, chunk)| (0..h).into_par_iter().map(move |i| (chunk_id, chunk, i)))
+            .map(
+                |(chunk_id, chunk, i)| -> anyhow::Result<Vec<(u32, SequenceHit)>> {
                     debug!("scoring hmm {}", i);
-                    let hmm_path = self.hmm_path(i as u32);
-                    let search_res = match &config.db {
+                    let search = || {
+                        config
+                            .retry
+                            .run(Stage::Search, i as u32, Some(chunk_id), || {
+                                self.search_chunk(
+                                    i as u32,
+                                    profiles,
+                                    chunk_id * chunk_size,
+                                    chunk,
+                                    config,
+                                )
+                            })
+                    };
+                    let search_res = match &checkpoint {
                         Some(db) => {
-                            let k = [chunk_id, i];
-                            let k_bytes: [u8; (usize::BITS / 8 * 2) as usize] = k[0]
-                                .to_be_bytes()
-                                .into_iter()
-                                .chain(k[1].to_be_bytes().into_iter())
-                                .collect::<Vec<u8>>()
-                                .try_into()
-                                .unwrap();
-                            match db.get(k_bytes).expect("failed to get from db") {
-                                Some(v) => {
+                            let k_bytes = checkpoint_key(config.scorer, chunk_id, i);
+                            let cached = cached_hits(db, &k_bytes).map_err(|kind| StageError {
+                                stage: Stage::Search,
+                                hmm_id: i as u32,
+                                chunk_id: Some(chunk_id),
+                                attempts: 1,
+                                kind,
+                            })?;
+                            match cached {
+                                Some(search_res) => {
                                     info!(i, chunk_id, "found cached hmmsearch result");
-                                    let search_res: Vec<(u32, f64)> =
-                                        unsafe { rkyv::from_bytes_unchecked(v.as_ref()) }.unwrap();
                                     search_res
                                 }
                                 None => {
-                                    let search_res =
-                                        hmmsearch(&hmm_path, chunk.iter(), &self.seq_ids, config)
-                                            .expect("hmmsearch failed");
+                                    let search_res = search()?;
                                     let serialized = rkyv::to_bytes::<_, 1024>(&search_res)
-                                        .expect("failed to serialize");
-                                    db.insert(k_bytes, sled::IVec::from(serialized.into_vec()))
-                                        .expect("failed to insert into db");
+                                        .map_err(|e| StageError {
+                                            stage: Stage::Search,
+                                            hmm_id: i as u32,
+                                            chunk_id: Some(chunk_id),
+                                            attempts: 1,
+                                            kind: ErrorKind::Other(anyhow!(
+                                                "failed to serialize the hits for the checkpoint: {}",
+                                                e
+                                            )),
+                                        })?;
+                                    db.insert(k_bytes, sled::IVec::from(serialized.into_vec()))?;
                                     debug!(i, chunk_id, "cached hmmsearch result");
                                     search_res
                                 }
                             }
                         }
-                        None => hmmsearch(&hmm_path, chunk.iter(), &self.seq_ids, config)
-                            .expect("hmmsearch failed"),
+                        None => search()?,
                     };
                     if report_progress {
-                        for_progress.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        num_finished.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                     }
-                    search_res.into_iter().map(move |(b, c)| (i as u32, b, c))
-                })
+                    Ok(search_res.into_iter().map(|hit| (i as u32, hit)).collect())
+                },
+            )
+            .try_fold(Vec::new, |mut acc, res| {
+                acc.extend(res?);
+                Ok(acc)
             })
-            .collect();
+            .try_reduce(Vec::new, |mut a, b| {
+                a.extend(b);
+                Ok(a)
+            });
         let _ = tx.send(true);
         if let Some(handle) = progress_handle {
             handle.join().unwrap();
         }
-        for (hmm_id, seq_id, score) in hmmsearch_results {
-            score_trackers[seq_id as usize].hmm_ids.push(hmm_id);
-            score_trackers[seq_id as usize].bitscores.push(score);
+        let hmmsearch_results = match hmmsearch_results {
+            Ok(res) => res,
+            Err(e) => {
+                // keep the finished work, so that rerunning with the same checkpoint resumes
+                if let Some(db) = &config.db {
+                    db.flush()?;
+                    info!("finished scoring work is kept in the checkpoint; rerun to resume");
+                }
+                return Err(e);
+            }
+        };
+        for (hmm_id, hit) in hmmsearch_results {
+            let tracker = &mut score_trackers[hit.seq_id as usize];
+            tracker.hmm_ids.push(hmm_id);
+            tracker.bitscores.push(hit.score);
+            if config.record_hits {
+                tracker.hits.push(hit);
+            }
         }
-        let new_scores: Vec<Vec<(u32, f64)>> = config.create_full_pool().install(|| {
+        let (new_scores, top_hits): (Vec<_>, Vec<_>) = config.create_full_pool().install(|| {
             score_trackers
                 .par_iter()
-                .map(|st| st.calc_adjusted_scores(self).collect_vec())
-                .collect()
+                .map(|st| {
+                    let top = st.calc_adjusted_scores(self).collect_vec();
+                    let hits = if config.record_hits {
+                        top.iter().map(|&(_, _, ix)| st.hits[ix].clone()).collect()
+                    } else {
+                        vec![]
+                    };
+                    let scores = top.into_iter().map(|(hmm_id, score, _)| (hmm_id, score));
+                    (scores.collect::<Vec<_>>(), hits)
+                })
+                .unzip()
         });
         Ok(AdderPayload {
             sequence_tophits: new_scores,
+            sequence_hits: if config.record_hits { top_hits } else { vec![] },
         })
     }
 }
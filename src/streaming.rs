@@ -0,0 +1,302 @@
+//! Adding very large query sets in batches, keeping a bounded number of queries in memory
+//!
+//! The queries are read twice. The first pass scores and aligns each batch, spilling the
+//! homologies of its letters to a scratch file; these settle the columns of the output alignment.
+//! The second pass reads the queries again along with their homologies and writes their rows.
+use crate::{
+    adder::{solve_homologies, AdderContext},
+    combined::{prepare_queries, score_queries},
+    compact_printer::LettersWithColors,
+    compression::{self, OutputFile, SequenceBatches},
+    config::{ExternalContext, OutputFormat},
+    formats::AlignmentWriter,
+    score_calc::{load_profiles, ScoringCtxt},
+    structures::CrucibleCtxt,
+};
+use anyhow::{bail, Context};
+use fixedbitset::FixedBitSet;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+/// the homologies of the letters of each query, in input order, kept on disk between the passes;
+/// removed when dropped
+struct HitsSpill(PathBuf);
+
+impl HitsSpill {
+    /// a scratch file next to the output, which is expected to have room for the alignment
+    fn new(output_path: &Path) -> Self {
+        let mut path = output_path.as_os_str().to_owned();
+        path.push(".hits");
+        Self(PathBuf::from(path))
+    }
+
+    fn create(&self) -> anyhow::Result<BufWriter<File>> {
+        let file =
+            File::create(&self.0).with_context(|| format!("failed to create {:?}", self.0))?;
+        Ok(BufWriter::new(file))
+    }
+
+    fn open(&self) -> anyhow::Result<BufReader<File>> {
+        let file = File::open(&self.0).with_context(|| format!("failed to open {:?}", self.0))?;
+        Ok(BufReader::new(file))
+    }
+}
+
+impl Drop for HitsSpill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// writes the homologies of each query as its length followed by the homologies
+fn write_hits<W: Write>(w: &mut W, hits: &[Vec<i32>]) -> anyhow::Result<()> {
+    for h in hits {
+        w.write_all(&(h.len() as u32).to_le_bytes())?;
+        for c in h {
+            w.write_all(&c.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// reads the homologies of the next `n` queries written by `write_hits`
+fn read_hits<R: Read>(r: &mut R, n: usize) -> anyhow::Result<Vec<Vec<i32>>> {
+    let mut buf = [0u8; 4];
+    (0..n)
+        .map(|_| {
+            r.read_exact(&mut buf)?;
+            let len = u32::from_le_bytes(buf) as usize;
+            let mut bytes = vec![0u8; len * 4];
+            r.read_exact(&mut bytes)?;
+            Ok(bytes
+                .chunks_exact(4)
+                .map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect())
+        })
+        .collect()
+}
+
+/// the homology classes of `hits`, dropping those not in `used` when only the queries are written
+fn letters_with_colors(
+    num_colors: usize,
+    hits: Vec<Vec<i32>>,
+    used: Option<&FixedBitSet>,
+) -> LettersWithColors {
+    let mut letters = LettersWithColors::new(num_colors, hits);
+    if let Some(used) = used {
+        letters.retain_colors(used);
+    }
+    letters
+}
+
+/// the same as `align_queries_using_scores` on the queries at `input_path`, but scoring and
+/// aligning them `batch_size` at a time: only one batch of queries and their homologies are in
+/// memory at once
+pub fn align_in_batches(
+    input_path: &Path,
+    base_alignment_path: &Path,
+    output_path: &Path,
+    ehmm_path: &Path,
+    ehmm_ctxt: CrucibleCtxt,
+    batch_size: usize,
+    config: &ExternalContext,
+) -> anyhow::Result<()> {
+    if batch_size == 0 {
+        bail!("the batch size must be at least 1");
+    }
+    if config.output_format == OutputFormat::Clustal {
+        bail!("the Clustal format interleaves the rows and cannot be written in batches");
+    }
+    if config.db.is_some() {
+        bail!("checkpoints are kept by query and cannot be used with batches");
+    }
+    if config.quality_weights && !compression::is_fastq(input_path)? {
+        warn!("the queries are not FASTQ, so they have no qualities to weigh residues by");
+    }
+    let ehmm_dir = ehmm_path.to_path_buf();
+    let num_colors = ehmm_ctxt.num_consensus_columns();
+    let spill = HitsSpill::new(output_path);
+    // the HMMs are only read once for all batches
+    let profiles = load_profiles(ehmm_path, &ehmm_ctxt, config)?;
+
+    // first pass: the homologies of each batch, and the homology classes used by the queries
+    let mut spill_writer = spill.create()?;
+    let mut used = FixedBitSet::with_capacity(num_colors);
+    let mut num_queries = 0usize;
+    for (i, batch) in SequenceBatches::new(input_path, batch_size)?.enumerate() {
+        let mut batch = batch?;
+        prepare_queries(&mut batch, config);
+        let batch_len = batch.records.len();
+        info!(
+            batch = i,
+            num_queries = batch_len,
+            "aligning a batch of queries"
+        );
+        let scorer = ScoringCtxt::from_records(ehmm_dir.clone(), ehmm_ctxt.clone(), batch.records)?
+            .with_qualities(batch.qualities)
+            .with_profiles(profiles.clone());
+        let scored = score_queries(&scorer, config)?;
+        let adder = AdderContext::from_scoring_ctxt(&ehmm_dir, scorer, scored)?;
+        let hits = solve_homologies(&adder, config)?;
+        write_hits(&mut spill_writer, &hits)?;
+        if config.only_queries {
+            used.union_with(&LettersWithColors::new(num_colors, hits).used_colors());
+        }
+        num_queries += batch_len;
+    }
+    spill_writer.flush()?;
+    drop(spill_writer);
+    info!("aligned {} query sequences", num_queries);
+
+    // the columns needed before each homology class by the letters of all batches
+    let used = config.only_queries.then_some(&used);
+    let num_kept_colors = used.map_or(num_colors, |u| u.count_ones(..));
+    let mut front_paddings = vec![0u32; num_kept_colors + 1];
+    let mut spill_reader = spill.open()?;
+    let mut num_read = 0usize;
+    while num_read < num_queries {
+        let n = batch_size.min(num_queries - num_read);
+        let hits = read_hits(&mut spill_reader, n)?;
+        letters_with_colors(num_colors, hits, used).update_front_paddings(&mut front_paddings);
+        num_read += n;
+    }
+    let mut layout = LettersWithColors::new(num_kept_colors, vec![]);
+    if !config.only_queries {
+        layout.append_backbone_column_colors();
+    }
+    let mut layout = layout.transl_with(front_paddings.clone());
+    if config.trim {
+        layout.mask_singleton_columns();
+    }
+    info!(
+        "output homologies formatted, output alignment will have {} columns",
+        layout.num_visual_columns
+    );
+
+    // second pass: the rows of the backbone, then of each batch of queries
+    let mut num_rows = num_queries;
+    if config.output_format == OutputFormat::Phylip {
+        num_rows += layout.num_backbone_rows(base_alignment_path)?;
+    }
+    let mut output_writer = OutputFile::create(output_path)?;
+    let mut writer = AlignmentWriter::new(
+        &mut output_writer,
+        config.output_format,
+        layout.match_columns(),
+        num_rows,
+    )?;
+    layout.write_backbone(base_alignment_path, &mut writer)?;
+    let mut spill_reader = spill.open()?;
+    let mut num_written = 0usize;
+    for batch in SequenceBatches::new(input_path, batch_size)? {
+        let mut batch = batch?;
+        prepare_queries(&mut batch, config);
+        num_written += batch.records.len();
+        if num_written > num_queries {
+            bail!("{:?} changed while being aligned", input_path);
+        }
+        let hits = read_hits(&mut spill_reader, batch.records.len())?;
+        if hits
+            .iter()
+            .zip(&batch.records)
+            .any(|(h, r)| h.len() != r.seq.len())
+        {
+            bail!("{:?} changed while being aligned", input_path);
+        }
+        let mut formatted =
+            letters_with_colors(num_colors, hits, used).transl_with(front_paddings.clone());
+        if config.trim {
+            formatted.mask_singleton_columns();
+        }
+        formatted.write_queries(&batch.records, &mut writer)?;
+    }
+    if num_written != num_queries {
+        bail!("{:?} changed while being aligned", input_path);
+    }
+    writer.finish()?;
+    output_writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        adder::align_queries_using_scores,
+        config::{AlignBackend, BuildBackend, ScoringBackend},
+        melt::oneshot_melt,
+    };
+    use std::fs;
+
+    const BACKBONE: &str = "\
+>b1\nATGGCTAAGCTTGACCGTAAGTCA\n>b2\nATGGCTAAGC-TGACCGTAAGTCA\n>b3\nATGGCAAAGCTTGACCGAAAGTCA
+>b4\nATGCCTAAGCTTGA--GTAAGTCA\n>b5\nATGGCTCAGCTTGACCGTAAGACA\n>b6\nATGGCTAAGCTAGACCGTTAGTCA\n";
+
+    /// fragments missing the first columns of the backbone, some with insertions, so that trimming
+    /// and dropping the columns not used by the queries both change the output
+    const QUERIES: &str = "\
+>q1\nGCTAAGCTTGACCGTAAGTCA\n>q2\nGCTAAGCTTGAC\n>q3\nCTTGACCGTAAGTCA\n>q4\nGCTAAGCTTGGGGACCGTAAG
+>q5\nCTTGACCGTAAGTCATTT\n>q6\nTGACCGTAAG\n";
+    const NUM_QUERIES: usize = 6;
+
+    #[test]
+    fn batches_match_whole_input() {
+        let dir = std::env::temp_dir().join(format!("witch-ng-streaming-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let backbone_path = dir.join("backbone.afa");
+        let queries_path = dir.join("queries.fa");
+        fs::write(&backbone_path, BACKBONE).unwrap();
+        fs::write(&queries_path, QUERIES).unwrap();
+        let ehmm_path = dir.join("backbone.ehmm");
+        let base = ExternalContext {
+            hmm_size_lb: 3,
+            num_workers: 1,
+            builder: BuildBackend::Native,
+            scorer: ScoringBackend::Forward,
+            aligner: AlignBackend::Viterbi,
+            ..ExternalContext::default()
+        };
+        let ehmm_ctxt = oneshot_melt(&backbone_path, None, &ehmm_path, &base).unwrap();
+        for (trim, only_queries) in [(false, false), (true, false), (false, true), (true, true)] {
+            let config = ExternalContext {
+                trim,
+                only_queries,
+                ..base.clone()
+            };
+            let whole_path = dir.join("whole.afa");
+            let queries = compression::read_sequences(&queries_path).unwrap();
+            let scorer =
+                ScoringCtxt::from_records(ehmm_path.clone(), ehmm_ctxt.clone(), queries.records)
+                    .unwrap();
+            let scored = score_queries(&scorer, &config).unwrap();
+            let adder = AdderContext::from_scoring_ctxt(&ehmm_path, scorer, scored).unwrap();
+            align_queries_using_scores(adder, &whole_path, &backbone_path, &config).unwrap();
+            let whole = fs::read_to_string(&whole_path).unwrap();
+            for batch_size in [1, 3, NUM_QUERIES] {
+                let batches_path = dir.join("batches.afa");
+                align_in_batches(
+                    &queries_path,
+                    &backbone_path,
+                    &batches_path,
+                    &ehmm_path,
+                    ehmm_ctxt.clone(),
+                    batch_size,
+                    &config,
+                )
+                .unwrap();
+                assert_eq!(
+                    fs::read_to_string(&batches_path).unwrap(),
+                    whole,
+                    "batch size {}, trim {}, only queries {}",
+                    batch_size,
+                    trim,
+                    only_queries
+                );
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,15 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
 
 use ndarray::{Array, Ix2};
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
 use serde::{Deserialize, Serialize};
 
+use crate::{config::DecompositionStrategy, hmm::Alphabet};
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct HmmMeta {
     pub sequence_range: (usize, usize),
@@ -33,19 +40,33 @@ pub struct TaxaHierarchy {
     pub reordered_taxa: Vec<usize>,
     pub taxa_positions: Vec<usize>,
     pub decomposition_ranges: Vec<(usize, usize)>,
+    /// the strategy actually used, after falling back from `Diameter` for trees without lengths
+    pub strategy: DecompositionStrategy,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct CrucibleCtxt {
     pub version: u32,
     pub metadata: Vec<HmmMeta>,
+    /// alphabet of the backbone, missing in eHMMs built by older versions until loaded
+    #[serde(default)]
+    pub alphabet: Option<Alphabet>,
+    /// how the backbone tree was decomposed, missing in eHMMs built by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decomposition: Option<DecompositionStrategy>,
+    /// the size bound of the balanced decomposition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmm_size_lb: Option<usize>,
 }
 
 impl CrucibleCtxt {
-    pub fn new(metadata: Vec<HmmMeta>) -> Self {
+    pub fn new(metadata: Vec<HmmMeta>, alphabet: Alphabet) -> Self {
         Self {
             version: 0,
             metadata,
+            alphabet: Some(alphabet),
+            decomposition: None,
+            hmm_size_lb: None,
         }
     }
 
@@ -71,9 +92,68 @@ impl CrucibleCtxt {
     }
 }
 
+/// one domain of a hit, a line of `hmmsearch --domtblout`. Coordinates are 1-based and inclusive
+#[derive(
+    Debug, Clone, PartialEq, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[archive(check_bytes)]
+pub struct DomainHit {
+    /// E-value of the domain as if it were the only one in the sequence (i-Evalue)
+    pub evalue: f64,
+    pub score: f64,
+    /// composition bias correction of the domain score
+    pub bias: f64,
+    pub hmm_range: (u32, u32),
+    pub alignment_range: (u32, u32),
+    /// envelope of the domain on the sequence
+    pub envelope_range: (u32, u32),
+}
+
+/// a query scored against one HMM, a line of `hmmsearch --tblout` with its domains
+#[derive(
+    Debug, Clone, PartialEq, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[archive(check_bytes)]
+pub struct SequenceHit {
+    pub seq_id: u32,
+    /// bitscore of the whole sequence
+    pub score: f64,
+    /// E-value of the whole sequence, unknown when scored in-process
+    pub evalue: Option<f64>,
+    /// composition bias correction of the sequence score
+    pub bias: f64,
+    pub domains: Vec<DomainHit>,
+}
+
+impl SequenceHit {
+    /// a hit known only by its score
+    pub fn from_score(seq_id: u32, score: f64) -> Self {
+        Self {
+            seq_id,
+            score,
+            evalue: None,
+            bias: 0.0,
+            domains: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct AdderPayload {
     /// a list of top hits tuple of HMM id and adjusted bitscore for each sequence
     pub sequence_tophits: Vec<Vec<(u32, f64)>>,
+    /// the raw hits behind `sequence_tophits`, in the same order; empty unless
+    /// `ExternalContext::record_hits`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sequence_hits: Vec<Vec<SequenceHit>>,
+}
+
+/// the scores files written so far: older versions wrote only the bare `sequence_tophits`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredPayload {
+    Payload(AdderPayload),
+    TopHits(Vec<Vec<(u32, f64)>>),
 }
 
 impl AdderPayload {
@@ -81,12 +161,24 @@ impl AdderPayload {
     where
         P: AsRef<Path>,
     {
-        let tophits = serde_json::from_reader(BufReader::new(File::open(path)?))?;
-        Ok(Self {
-            sequence_tophits: tophits,
+        let stored = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(match stored {
+            StoredPayload::Payload(payload) => payload,
+            StoredPayload::TopHits(sequence_tophits) => Self {
+                sequence_tophits,
+                sequence_hits: vec![],
+            },
         })
     }
 
+    pub fn to_path<P>(&self, path: P) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        serde_json::to_writer(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
     /// consumes self and returns a mapping from HMM id to sequence and adjusted bitscores for hmmalign
     pub fn transpose(self, ctxt: &CrucibleCtxt) -> Vec<Vec<(u32, f64)>> {
         let n = ctxt.num_hmms();
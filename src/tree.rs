@@ -0,0 +1,488 @@
+//! Preparing backbone trees for decomposition: reconciling their leaves with the backbone and rooting them
+use ahash::{AHashMap, AHashSet};
+use anyhow::{bail, Context};
+use ogcat::ogtree::{TaxonSet, Tree, TreeCollection};
+
+/// characters that cannot appear in the (unquoted) taxon names of a Newick tree
+const NEWICK_RESERVED: &[u8] = b"(),:;[]'";
+
+/// if any branch of `tree` has a positive length; trees without branch lengths may store them as
+/// zeros
+pub fn has_branch_lengths(tree: &Tree) -> bool {
+    (0..tree.num_nodes()).any(|i| !tree.is_root(i) && tree.lengths[i] > 0.0)
+}
+
+/// if `label` can be written in a Newick tree without quotes
+pub(crate) fn is_plain_newick_label(label: &str) -> bool {
+    !label.is_empty()
+        && !label
+            .bytes()
+            .any(|c| c.is_ascii_whitespace() || NEWICK_RESERVED.contains(&c))
+}
+
+/// `label` as a Newick label, between single quotes (doubled inside) if needed
+#[cfg(test)]
+fn newick_label(label: &str) -> String {
+    if is_plain_newick_label(label) {
+        label.to_string()
+    } else {
+        format!("'{}'", label.replace('\'', "''"))
+    }
+}
+
+/// a rooted tree with named leaves, as a list of nodes that is easy to edit
+#[derive(Debug, Clone)]
+pub struct NamedTree {
+    parent: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    /// length of the branch above each node, `None` if unknown
+    length: Vec<Option<f64>>,
+    /// names of the leaves
+    name: Vec<Option<String>>,
+    root: usize,
+    /// if the input tree had any positive branch length; unknown lengths are then taken as 0
+    /// rather than 1
+    has_lengths: bool,
+}
+
+impl NamedTree {
+    /// a copy of `tree`, whose taxa are named by `taxon_set`
+    pub fn from_ogcat(tree: &Tree, taxon_set: &TaxonSet) -> Self {
+        let n = tree.num_nodes();
+        let mut res = Self {
+            parent: vec![None; n],
+            children: vec![vec![]; n],
+            length: vec![None; n],
+            name: vec![None; n],
+            root: 0,
+            has_lengths: has_branch_lengths(tree),
+        };
+        for i in 0..n {
+            res.children[i] = tree.children(i).collect();
+            for &c in &res.children[i] {
+                res.parent[c] = Some(i);
+            }
+            if res.has_lengths && !tree.is_root(i) && tree.lengths[i] >= 0.0 {
+                res.length[i] = Some(tree.lengths[i]);
+            }
+            if tree.is_leaf(i) {
+                res.name[i] = Some(taxon_set.names[tree.taxa[i] as usize].clone());
+            }
+        }
+        res
+    }
+
+    fn is_leaf(&self, node: usize) -> bool {
+        self.children[node].is_empty()
+    }
+
+    /// names of the leaves, in preorder
+    pub fn leaf_names(&self) -> Vec<&str> {
+        self.preorder()
+            .into_iter()
+            .filter_map(|i| self.name[i].as_deref())
+            .collect()
+    }
+
+    fn preorder(&self) -> Vec<usize> {
+        let mut order = vec![];
+        let mut stack = vec![self.root];
+        while let Some(i) = stack.pop() {
+            order.push(i);
+            stack.extend(self.children[i].iter().rev());
+        }
+        order
+    }
+
+    /// removes the leaves not satisfying `keep`, then the nodes left without leaves below and the
+    /// nodes left with a single child (merging their branches)
+    pub fn retain_leaves<F>(&mut self, keep: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        for i in self.preorder().into_iter().rev() {
+            let removed = match &self.name[i] {
+                Some(name) => !keep(name),
+                None => self.is_leaf(i),
+            };
+            if removed {
+                if let Some(p) = self.parent[i] {
+                    self.children[p].retain(|&c| c != i);
+                }
+                self.parent[i] = None;
+            }
+        }
+        for i in self.preorder().into_iter().rev() {
+            if self.children[i].len() != 1 {
+                continue;
+            }
+            let c = self.children[i][0];
+            match self.parent[i] {
+                Some(p) => {
+                    let pos = self.children[p].iter().position(|&x| x == i).unwrap();
+                    self.children[p][pos] = c;
+                    self.parent[c] = Some(p);
+                    self.length[c] = match (self.length[c], self.length[i]) {
+                        (Some(a), Some(b)) => Some(a + b),
+                        (a, b) => a.or(b),
+                    };
+                }
+                None => {
+                    self.parent[c] = None;
+                    self.length[c] = None;
+                    self.root = c;
+                }
+            }
+            self.children[i].clear();
+        }
+    }
+
+    /// neighbors of each node with the lengths of the branches to them, as an unrooted tree.
+    /// Unknown lengths count as 1 if the tree has no lengths at all, and as 0 otherwise
+    fn adjacency(&self) -> AHashMap<usize, Vec<(usize, f64)>> {
+        let default = if self.has_lengths { 0.0 } else { 1.0 };
+        let mut adj: AHashMap<usize, Vec<(usize, f64)>> = AHashMap::new();
+        for i in self.preorder() {
+            adj.entry(i).or_default();
+            if let Some(p) = self.parent[i] {
+                let l = self.length[i].unwrap_or(default).max(0.0);
+                adj.entry(p).or_default().push((i, l));
+                adj.entry(i).or_default().push((p, l));
+            }
+        }
+        adj
+    }
+
+    /// re-roots the tree at the midpoint of its longest leaf-to-leaf path, so that the root does not
+    /// depend on how the (unrooted) input was written
+    pub fn midpoint_root(&mut self) {
+        let adj = self.adjacency();
+        let farthest = |from: usize| {
+            // distances and predecessors from `from`, ties broken towards smaller node ids
+            let mut dist: AHashMap<usize, (f64, Option<usize>)> = AHashMap::new();
+            let mut stack = vec![(from, 0.0, None)];
+            while let Some((u, d, pred)) = stack.pop() {
+                dist.insert(u, (d, pred));
+                for &(v, l) in &adj[&u] {
+                    if Some(v) != pred {
+                        stack.push((v, d + l, Some(u)));
+                    }
+                }
+            }
+            let best = dist
+                .iter()
+                .filter(|(&u, _)| self.is_leaf(u))
+                .max_by(|(u, (a, _)), (v, (b, _))| a.total_cmp(b).then(v.cmp(u)))
+                .map(|(&u, _)| u)
+                .unwrap();
+            (best, dist)
+        };
+        let start = match self.preorder().into_iter().find(|&i| self.is_leaf(i)) {
+            Some(start) => start,
+            None => return,
+        };
+        let (a, _) = farthest(start);
+        let (b, dist) = farthest(a);
+        let half = dist[&b].0 / 2.0;
+        // walk back from b until crossing the midpoint
+        let mut v = b;
+        while let Some(u) = dist[&v].1 {
+            if dist[&u].0 <= half {
+                let (du, dv) = (dist[&u].0, dist[&v].0);
+                self.reroot_on_branch(u, v, half - du, dv - half);
+                return;
+            }
+            v = u;
+        }
+    }
+
+    /// makes a new root on the branch between `u` and `v`, at the given distances to both
+    fn reroot_on_branch(&mut self, u: usize, v: usize, to_u: f64, to_v: f64) {
+        let adj = self.adjacency();
+        let new_root = self.parent.len();
+        let n = new_root + 1;
+        let mut parent = vec![None; n];
+        let mut children = vec![vec![]; n];
+        let mut length = vec![None; n];
+        let mut name = self.name.clone();
+        name.push(None);
+        let mut stack = vec![(u, new_root, to_u), (v, new_root, to_v)];
+        while let Some((x, p, l)) = stack.pop() {
+            parent[x] = Some(p);
+            children[p].push(x);
+            length[x] = self.has_lengths.then_some(l);
+            for &(y, ly) in &adj[&x] {
+                // the branch between u and v is replaced by the two branches to the new root
+                let upwards = if p == new_root {
+                    y == u || y == v
+                } else {
+                    y == p
+                };
+                if !upwards {
+                    stack.push((y, x, ly));
+                }
+            }
+        }
+        for cs in children.iter_mut() {
+            cs.sort_unstable();
+        }
+        *self = Self {
+            parent,
+            children,
+            length,
+            name,
+            root: new_root,
+            has_lengths: self.has_lengths,
+        };
+        // the old root may now have a single child
+        self.retain_leaves(|_| true);
+    }
+
+    /// the tree in Newick format, with labels quoted where needed; only the tests write trees,
+    /// the eHMM being built from the ogcat tree
+    #[cfg(test)]
+    pub fn to_newick(&self) -> String {
+        use std::fmt::Write;
+        fn write_node(t: &NamedTree, i: usize, out: &mut String) {
+            if !t.is_leaf(i) {
+                out.push('(');
+                for (k, &c) in t.children[i].iter().enumerate() {
+                    if k > 0 {
+                        out.push(',');
+                    }
+                    write_node(t, c, out);
+                }
+                out.push(')');
+            }
+            if let Some(name) = &t.name[i] {
+                out.push_str(&newick_label(name));
+            }
+            if let Some(l) = t.length[i] {
+                write!(out, ":{}", l).unwrap();
+            }
+        }
+        let mut out = String::new();
+        write_node(self, self.root, &mut out);
+        out.push(';');
+        out
+    }
+
+    /// converts to the representation used for decomposition, with nodes and taxa numbered in
+    /// preorder; unknown lengths are stored as -1
+    pub fn to_ogcat(&self) -> (TaxonSet, Tree) {
+        let order = self.preorder();
+        let mut index = vec![usize::MAX; self.parent.len()];
+        for (k, &i) in order.iter().enumerate() {
+            index[i] = k;
+        }
+        let n = order.len();
+        let mut taxon_set = TaxonSet::new();
+        let mut tree = Tree {
+            taxa: vec![-1; n],
+            parents: vec![-1; n],
+            support: vec![-1.0; n],
+            lengths: vec![-1.0; n],
+            firstchild: vec![-1; n],
+            nextsib: vec![-1; n],
+            childcount: vec![0; n],
+            fake_root: false,
+            ntaxa: 0,
+        };
+        for (k, &i) in order.iter().enumerate() {
+            if let Some(p) = self.parent[i] {
+                tree.parents[k] = index[p] as i32;
+            }
+            let children = &self.children[i];
+            tree.childcount[k] = children.len() as u32;
+            if let Some(&c) = children.first() {
+                tree.firstchild[k] = index[c] as i32;
+            }
+            for pair in children.windows(2) {
+                tree.nextsib[index[pair[0]]] = index[pair[1]] as i32;
+            }
+            if let Some(l) = self.length[i] {
+                tree.lengths[k] = l;
+            }
+            if self.is_leaf(i) {
+                let name = self.name[i].clone().unwrap_or_default();
+                tree.taxa[k] = taxon_set.request(name) as i32;
+                tree.ntaxa += 1;
+            }
+        }
+        (taxon_set, tree)
+    }
+}
+
+/// a backbone tree reconciled with the sequences of the backbone
+pub struct ReconciledTree {
+    pub taxon_set: TaxonSet,
+    pub tree: Tree,
+    /// leaves of the input tree not in the backbone, which were pruned
+    pub pruned: Vec<String>,
+    /// backbone sequences not in the input tree, in backbone order
+    pub missing: Vec<String>,
+}
+
+/// prunes the leaves of the first tree of `collection` that are not among `backbone` (the names of
+/// the backbone sequences, which must be unique), and midpoint-roots the tree if it is unrooted (its root has more than
+/// two children)
+pub fn reconcile(
+    collection: &TreeCollection,
+    backbone: &[String],
+) -> anyhow::Result<ReconciledTree> {
+    let input = match collection.trees.first() {
+        Some(input) => input,
+        None => bail!("the tree file contains no tree"),
+    };
+    let mut tree = NamedTree::from_ogcat(input, &collection.taxon_set);
+    let leaves = tree.leaf_names();
+    let mut seen = AHashSet::new();
+    if let Some(dup) = leaves.iter().find(|&&l| !seen.insert(l)) {
+        bail!("taxon {:?} appears more than once in the tree", dup);
+    }
+    let mut in_backbone = AHashSet::new();
+    if let Some(dup) = backbone.iter().find(|&b| !in_backbone.insert(b.as_str())) {
+        bail!("sequence {:?} appears more than once in the backbone", dup);
+    }
+    let pruned = leaves
+        .iter()
+        .filter(|l| !in_backbone.contains(*l))
+        .map(|l| l.to_string())
+        .collect::<Vec<_>>();
+    let missing = backbone
+        .iter()
+        .filter(|b| !seen.contains(b.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !pruned.is_empty() {
+        tree.retain_leaves(|l| in_backbone.contains(l));
+    }
+    if tree.leaf_names().is_empty() {
+        bail!("none of the taxa of the tree are in the backbone");
+    }
+    if tree.children[tree.root].len() > 2 {
+        tree.midpoint_root();
+    }
+    let (taxon_set, tree) = tree.to_ogcat();
+    Ok(ReconciledTree {
+        taxon_set,
+        tree,
+        pruned,
+        missing,
+    })
+}
+
+/// reads the trees of a Newick file
+pub fn read_newick(path: &std::path::Path) -> anyhow::Result<TreeCollection> {
+    std::fs::metadata(path).with_context(|| format!("failed to read tree at {:?}", path))?;
+    TreeCollection::from_newick(path)
+        .map_err(|e| anyhow::anyhow!("failed to read tree at {:?}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ogcat::ogtree::parse_newick;
+
+    fn named_tree(newick: &str) -> NamedTree {
+        let mut taxon_set = TaxonSet::new();
+        let tree = parse_newick(&mut taxon_set, newick);
+        NamedTree::from_ogcat(&tree, &taxon_set)
+    }
+
+    /// the distance from the root to each leaf, by name
+    fn depths(t: &NamedTree) -> Vec<(String, f64)> {
+        let mut depth = vec![0.0; t.parent.len()];
+        let mut res = vec![];
+        for i in t.preorder() {
+            if let Some(p) = t.parent[i] {
+                depth[i] = depth[p] + t.length[i].unwrap_or(1.0);
+            }
+            if let Some(name) = &t.name[i] {
+                res.push((name.clone(), depth[i]));
+            }
+        }
+        res.sort_by(|a, b| a.0.cmp(&b.0));
+        res
+    }
+
+    #[test]
+    fn retain_leaves_merges_branches() {
+        let mut t = named_tree("((A:1,B:2):1,(C:1,D:1):2);");
+        t.retain_leaves(|l| l != "B");
+        assert_eq!(t.to_newick(), "(A:2,(C:1,D:1):2);");
+        t.retain_leaves(|l| l == "C" || l == "D");
+        assert_eq!(t.to_newick(), "(C:1,D:1);");
+        assert_eq!(t.leaf_names(), ["C", "D"]);
+    }
+
+    #[test]
+    fn midpoint_root_of_trifurcating_root() {
+        let mut t = named_tree("(A:1,B:1,C:4);");
+        t.midpoint_root();
+        assert_eq!(t.children[t.root].len(), 2);
+        assert_eq!(
+            depths(&t),
+            [("A".into(), 2.5), ("B".into(), 2.5), ("C".into(), 2.5)]
+        );
+    }
+
+    #[test]
+    fn midpoint_root_without_lengths() {
+        let mut t = named_tree("(A,B,(C,D));");
+        assert!(!t.has_lengths);
+        t.midpoint_root();
+        assert_eq!(t.children[t.root].len(), 2);
+        assert!(t.length.iter().all(|l| l.is_none()));
+        assert_eq!(
+            depths(&t),
+            [
+                ("A".into(), 2.0),
+                ("B".into(), 2.0),
+                ("C".into(), 2.0),
+                ("D".into(), 2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_are_kept_verbatim() {
+        let mut t = named_tree("(A:1,B:2,C:3);");
+        t.name = t
+            .name
+            .iter()
+            .map(|n| {
+                n.as_deref().map(|n| match n {
+                    "A" => "a b".to_string(),
+                    "B" => "it's".to_string(),
+                    _ => "x:(y)".to_string(),
+                })
+            })
+            .collect();
+        assert_eq!(t.to_newick(), "('a b':1,'it''s':2,'x:(y)':3);");
+        let (taxon_set, tree) = t.to_ogcat();
+        assert_eq!(taxon_set.names, ["a b", "it's", "x:(y)"]);
+        assert_eq!(tree.ntaxa, 3);
+        assert!(has_branch_lengths(&tree));
+        let back = NamedTree::from_ogcat(&tree, &taxon_set);
+        assert_eq!(back.to_newick(), t.to_newick());
+    }
+
+    #[test]
+    fn reconcile_rejects_duplicate_backbone_names() {
+        let mut taxon_set = TaxonSet::new();
+        let tree = parse_newick(&mut taxon_set, "(A:1,B:1,C:1);");
+        let collection = TreeCollection {
+            taxon_set,
+            trees: vec![tree],
+        };
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        match reconcile(&collection, &names(&["A", "B", "A"])) {
+            Err(e) => assert!(e.to_string().contains("\"A\" appears more than once")),
+            Ok(_) => panic!("duplicate backbone names were accepted"),
+        }
+        let reconciled = reconcile(&collection, &names(&["A", "C"])).unwrap();
+        assert_eq!(reconciled.pruned, ["B"]);
+        assert_eq!(reconciled.tree.ntaxa, 2);
+    }
+}
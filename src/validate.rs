@@ -0,0 +1,521 @@
+//! Checking the input files before any work is done, reporting every problem with its location
+//! (only a few examples of the fixable ones, which may be in every query)
+use crate::compression;
+use ahash::AHashMap;
+use seq_io::fasta::OwnedRecord;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+/// a problem with one of the sequences of an input file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// text before the first header of a FASTA file
+    NotFasta,
+    /// a FASTQ record not made of a '@' header, a sequence, a '+' line and qualities
+    NotFastq,
+    /// a FASTQ record with a different number of qualities than residues
+    QualityLength {
+        name: String,
+        length: usize,
+        expected: usize,
+    },
+    /// a sequence with the same name as an earlier one of the same file
+    DuplicateName { name: String, first_line: usize },
+    /// a query with the same name as a backbone sequence
+    SharedName {
+        name: String,
+        backbone: PathBuf,
+        backbone_line: usize,
+    },
+    /// a sequence without residues
+    EmptySequence { name: String },
+    /// a backbone row of a different length than the first row
+    RowLength {
+        name: String,
+        length: usize,
+        expected: usize,
+    },
+    /// a query containing gaps ('-' or '.')
+    GapInQuery { name: String },
+    /// a query containing lower-case letters, which would be taken as insertions
+    LowercaseInQuery { name: String },
+}
+
+impl ProblemKind {
+    /// if `fix_query` fixes this problem
+    pub fn is_fixable(&self) -> bool {
+        self.fixable_summary().is_some()
+    }
+
+    /// what the queries with this problem have in common, if it is fixable
+    pub fn fixable_summary(&self) -> Option<&'static str> {
+        match self {
+            ProblemKind::GapInQuery { .. } => Some("contain gaps"),
+            ProblemKind::LowercaseInQuery { .. } => Some("contain lower-case letters"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProblemKind::NotFasta => write!(f, "expected a FASTA header ('>')"),
+            ProblemKind::NotFastq => write!(
+                f,
+                "expected a FASTQ record ('@' header, sequence, '+' line and qualities)"
+            ),
+            ProblemKind::QualityLength {
+                name,
+                length,
+                expected,
+            } => write!(
+                f,
+                "{:?} has {} qualities for {} residues",
+                name, length, expected
+            ),
+            ProblemKind::DuplicateName { name, first_line } => {
+                write!(
+                    f,
+                    "{:?} is also the name of the sequence at line {}",
+                    name, first_line
+                )
+            }
+            ProblemKind::SharedName {
+                name,
+                backbone,
+                backbone_line,
+            } => write!(
+                f,
+                "{:?} is also the name of a backbone sequence ({}:{})",
+                name,
+                backbone.display(),
+                backbone_line
+            ),
+            ProblemKind::EmptySequence { name } => write!(f, "{:?} has no residues", name),
+            ProblemKind::RowLength {
+                name,
+                length,
+                expected,
+            } => write!(
+                f,
+                "{:?} has {} columns, but the first row of the backbone has {}",
+                name, length, expected
+            ),
+            ProblemKind::GapInQuery { name } => {
+                write!(f, "query {:?} contains gaps", name)
+            }
+            ProblemKind::LowercaseInQuery { name } => {
+                write!(f, "query {:?} contains lower-case letters", name)
+            }
+        }
+    }
+}
+
+/// a problem located in an input file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub path: PathBuf,
+    /// 1-based line number
+    pub line: usize,
+    pub kind: ProblemKind,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.path.display(), self.line, self.kind)?;
+        if self.kind.is_fixable() {
+            write!(f, " (fixable with --fix-inputs)")?;
+        }
+        Ok(())
+    }
+}
+
+/// the number of fixable problems of each kind kept in a `Report`
+const FIXABLE_EXAMPLES: usize = 3;
+
+/// the problems found in the inputs. Fixable problems may be found in every query, so only the
+/// first few of each kind are kept, the others being only counted
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// the number of fixable problems by `ProblemKind::fixable_summary`, kept or not
+    pub fixable_counts: BTreeMap<&'static str, usize>,
+}
+
+impl Report {
+    fn push(&mut self, problem: Problem) {
+        if let Some(summary) = problem.kind.fixable_summary() {
+            let count = self.fixable_counts.entry(summary).or_default();
+            *count += 1;
+            if *count > FIXABLE_EXAMPLES {
+                return;
+            }
+        }
+        self.problems.push(problem);
+    }
+
+    /// the number of problems found, kept or not
+    pub fn num_problems(&self) -> usize {
+        let num_kept_fixable = self.problems.iter().filter(|p| p.kind.is_fixable()).count();
+        self.problems.len() - num_kept_fixable + self.fixable_counts.values().sum::<usize>()
+    }
+
+    /// a line for each kind of fixable problems found more often than kept
+    pub fn omitted(&self) -> impl Iterator<Item = String> + '_ {
+        self.fixable_counts
+            .iter()
+            .filter(|(_, &count)| count > FIXABLE_EXAMPLES)
+            .map(|(summary, count)| {
+                format!(
+                    "... and {} more queries that {} (fixable with --fix-inputs)",
+                    count - FIXABLE_EXAMPLES,
+                    summary
+                )
+            })
+    }
+
+    /// if no problem was found, or only problems fixed by `fix_query` when `fix` is set
+    pub fn is_ok(&self, fix: bool) -> bool {
+        self.problems.iter().all(|p| fix && p.kind.is_fixable())
+    }
+
+    /// an error listing all problems, unless `is_ok(fix)`
+    pub fn check(&self, fix: bool) -> anyhow::Result<()> {
+        if self.is_ok(fix) {
+            return Ok(());
+        }
+        let listed = self
+            .problems
+            .iter()
+            .map(|p| p.to_string())
+            .chain(self.omitted())
+            .collect::<Vec<_>>()
+            .join("\n");
+        anyhow::bail!(
+            "found {} problem(s) in the inputs:\n{}",
+            self.num_problems(),
+            listed
+        )
+    }
+}
+
+fn is_gap(c: u8) -> bool {
+    c == b'-' || c == b'.'
+}
+
+/// a sequence of a FASTA file with the lines it was read from
+struct Entry {
+    name: String,
+    header_line: usize,
+    /// each line of the sequence with its line number
+    lines: Vec<(usize, Vec<u8>)>,
+}
+
+impl Entry {
+    fn residues(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        self.lines
+            .iter()
+            .flat_map(|(l, s)| s.iter().map(move |&c| (*l, c)))
+    }
+
+    /// line of the first letter satisfying `pred`
+    fn find(&self, pred: impl Fn(u8) -> bool) -> Option<usize> {
+        self.residues().find(|&(_, c)| pred(c)).map(|(l, _)| l)
+    }
+}
+
+/// reads a FASTA file (or a FASTQ file, if `allow_fastq`) keeping track of line numbers, with the
+/// problems of its format, calling `f` on each entry; only one entry is kept in memory at a time
+fn read_entries<F>(
+    path: &Path,
+    allow_fastq: bool,
+    report: &mut Report,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(Entry, &mut Report),
+{
+    let reader = BufReader::new(compression::open(path)?);
+    let lines = reader.split(b'\n').enumerate().map(|(i, line)| {
+        let mut line = line?;
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok((i + 1, line))
+    });
+    if allow_fastq && compression::is_fastq(path)? {
+        return read_fastq_entries(path, lines, report, f);
+    }
+    let mut current: Option<Entry> = None;
+    for line in lines {
+        let (lineno, line) = line?;
+        if let Some(header) = line.strip_prefix(b">") {
+            if let Some(e) = current.take() {
+                f(e, report);
+            }
+            current = Some(Entry {
+                name: String::from_utf8_lossy(header).into_owned(),
+                header_line: lineno,
+                lines: vec![],
+            });
+        } else if let Some(e) = current.as_mut() {
+            e.lines.push((lineno, line));
+        } else if !line.iter().all(|c| c.is_ascii_whitespace()) {
+            report.push(Problem {
+                path: path.to_path_buf(),
+                line: lineno,
+                kind: ProblemKind::NotFasta,
+            });
+        }
+    }
+    if let Some(e) = current {
+        f(e, report);
+    }
+    Ok(())
+}
+
+/// same as `read_entries`, for the four-line records of a FASTQ file
+fn read_fastq_entries<I, F>(
+    path: &Path,
+    lines: I,
+    report: &mut Report,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    I: Iterator<Item = anyhow::Result<(usize, Vec<u8>)>>,
+    F: FnMut(Entry, &mut Report),
+{
+    let problem = |report: &mut Report, line, kind| {
+        report.push(Problem {
+            path: path.to_path_buf(),
+            line,
+            kind,
+        })
+    };
+    let mut lines = lines.filter(|l| !matches!(l, Ok((_, line)) if line.is_empty()));
+    while let Some(header) = lines.next() {
+        let (lineno, header) = header?;
+        let header = match header.strip_prefix(b"@") {
+            Some(header) => String::from_utf8_lossy(header).into_owned(),
+            None => {
+                problem(report, lineno, ProblemKind::NotFastq);
+                continue;
+            }
+        };
+        let seq = lines.next().transpose()?;
+        let plus = lines.next().transpose()?;
+        let qual = lines.next().transpose()?;
+        match (seq, plus, qual) {
+            (Some(seq), Some((_, plus)), Some((_, qual))) if plus.starts_with(b"+") => {
+                if qual.len() != seq.1.len() {
+                    problem(
+                        report,
+                        lineno,
+                        ProblemKind::QualityLength {
+                            name: header.clone(),
+                            length: qual.len(),
+                            expected: seq.1.len(),
+                        },
+                    );
+                }
+                f(
+                    Entry {
+                        name: header,
+                        header_line: lineno,
+                        lines: vec![seq],
+                    },
+                    report,
+                );
+            }
+            _ => problem(report, lineno, ProblemKind::NotFastq),
+        }
+    }
+    Ok(())
+}
+
+/// the checks shared by the backbone and the queries, remembering the line of each name
+#[derive(Default)]
+struct CommonChecks {
+    first_lines: AHashMap<String, usize>,
+}
+
+impl CommonChecks {
+    fn check(&mut self, path: &Path, e: &Entry, report: &mut Report) {
+        let mut problem = |line, kind| {
+            report.push(Problem {
+                path: path.to_path_buf(),
+                line,
+                kind,
+            })
+        };
+        if let Some(&first_line) = self.first_lines.get(&e.name) {
+            problem(
+                e.header_line,
+                ProblemKind::DuplicateName {
+                    name: e.name.clone(),
+                    first_line,
+                },
+            );
+        } else {
+            self.first_lines.insert(e.name.clone(), e.header_line);
+        }
+        if e.residues()
+            .all(|(_, c)| is_gap(c) || c.is_ascii_whitespace())
+        {
+            problem(
+                e.header_line,
+                ProblemKind::EmptySequence {
+                    name: e.name.clone(),
+                },
+            );
+        }
+    }
+}
+
+/// the checks of the queries, with the names of the backbone sequences (and the path they were
+/// read from) if the backbone is checked too
+struct QueryChecks {
+    common: CommonChecks,
+    backbone: Option<(PathBuf, AHashMap<String, usize>)>,
+}
+
+impl QueryChecks {
+    fn check(&mut self, path: &Path, e: &Entry, report: &mut Report) {
+        self.common.check(path, e, report);
+        let mut problem = |line, kind| {
+            report.push(Problem {
+                path: path.to_path_buf(),
+                line,
+                kind,
+            })
+        };
+        if let Some((backbone, names)) = &self.backbone {
+            if let Some(&backbone_line) = names.get(&e.name) {
+                problem(
+                    e.header_line,
+                    ProblemKind::SharedName {
+                        name: e.name.clone(),
+                        backbone: backbone.clone(),
+                        backbone_line,
+                    },
+                );
+            }
+        }
+        if let Some(line) = e.find(is_gap) {
+            problem(
+                line,
+                ProblemKind::GapInQuery {
+                    name: e.name.clone(),
+                },
+            );
+        }
+        if let Some(line) = e.find(|c| c.is_ascii_lowercase()) {
+            problem(
+                line,
+                ProblemKind::LowercaseInQuery {
+                    name: e.name.clone(),
+                },
+            );
+        }
+    }
+}
+
+/// checks the backbone (an alignment, or an eHMM directory, whose alignment is then checked),
+/// returning the path of the alignment with the line of each of its names
+fn check_backbone(
+    backbone: &Path,
+    report: &mut Report,
+) -> anyhow::Result<(PathBuf, AHashMap<String, usize>)> {
+    let path = if backbone.is_dir() {
+        backbone.join("subsets").join("0.afa")
+    } else {
+        backbone.to_path_buf()
+    };
+    let start = report.problems.len();
+    let mut common = CommonChecks::default();
+    let mut expected = None;
+    read_entries(&path, false, report, |e, report| {
+        common.check(&path, &e, report);
+        let width = e.lines.iter().map(|(_, s)| s.len()).sum::<usize>();
+        let expected = *expected.get_or_insert(width);
+        if width != expected {
+            report.push(Problem {
+                path: path.clone(),
+                line: e.header_line,
+                kind: ProblemKind::RowLength {
+                    name: e.name,
+                    length: width,
+                    expected,
+                },
+            });
+        }
+    })?;
+    report.problems[start..].sort_by_key(|p| p.line);
+    Ok((path, common.first_lines))
+}
+
+/// checks the queries and the backbone (an alignment, or an eHMM directory, whose alignment is
+/// then checked) in one pass, collecting every problem. The sequences are read one at a time,
+/// only their names are kept
+pub fn validate(queries: Option<&Path>, backbone: Option<&Path>) -> anyhow::Result<Report> {
+    let mut report = Report::default();
+    let backbone = backbone
+        .map(|backbone| check_backbone(backbone, &mut report))
+        .transpose()?;
+    if let Some(path) = queries {
+        let start = report.problems.len();
+        let mut checks = QueryChecks {
+            common: CommonChecks::default(),
+            backbone,
+        };
+        read_entries(path, true, &mut report, |e, report| {
+            checks.check(path, &e, report)
+        })?;
+        report.problems[start..].sort_by_key(|p| p.line);
+    }
+    Ok(report)
+}
+
+/// the same checks as `validate`, on queries already in memory. As these were not read from a
+/// file, their problems are located in `<queries>`, at the (1-based) index of the query
+pub fn validate_records(
+    queries: &[OwnedRecord],
+    backbone: Option<&Path>,
+) -> anyhow::Result<Report> {
+    let mut report = Report::default();
+    let backbone = backbone
+        .map(|backbone| check_backbone(backbone, &mut report))
+        .transpose()?;
+    let mut checks = QueryChecks {
+        common: CommonChecks::default(),
+        backbone,
+    };
+    let path = Path::new("<queries>");
+    for (i, r) in queries.iter().enumerate() {
+        let entry = Entry {
+            name: String::from_utf8_lossy(&r.head).into_owned(),
+            header_line: i + 1,
+            lines: vec![(i + 1, r.seq.clone())],
+        };
+        checks.check(path, &entry, &mut report);
+    }
+    Ok(report)
+}
+
+/// fixes the fixable problems of a query: removes gaps and upper-cases letters
+pub fn fix_query(record: &mut OwnedRecord) {
+    record.seq.retain(|&c| !is_gap(c));
+    record.seq.make_ascii_uppercase();
+}
+
+/// same as `fix_query`, also removing the qualities of the gaps
+pub fn fix_query_with_qualities(record: &mut OwnedRecord, qualities: &mut Vec<u8>) {
+    let mut seq = record.seq.iter();
+    qualities.retain(|_| !seq.next().is_some_and(|&c| is_gap(c)));
+    fix_query(record);
+}